@@ -7,32 +7,90 @@ use clap::{ArgGroup, Parser, Subcommand};
 use cmd_lib::run_fun;
 use env_logger::Env;
 use serde_json::json;
-use time::Instant;
 
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 mod utils;
 use utils::af_utils::*;
+use utils::dl_utils::*;
+use utils::estimate_utils::*;
+use utils::fs_utils::*;
 use utils::prog_utils::*;
-
+use utils::prov_utils::*;
+use utils::quant_utils::*;
+use utils::ref_utils::*;
+use utils::run_utils::*;
+use utils::sample_utils::*;
+
+// parsed once per run, so the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// build the splici index
     #[clap(arg_required_else_help = true)]
+    #[clap(group(
+            ArgGroup::new("t2g_source")
+            .args(["t2g_map", "derive_t2g"])
+            ))]
     Index {
         /// reference genome
-        #[clap(short, long, value_parser)]
-        fasta: PathBuf,
+        #[clap(short, long, required_unless_present_any = ["transcripts", "feature_csv"], value_parser)]
+        fasta: Option<PathBuf>,
 
         /// reference GTF file
-        #[clap(short, long, value_parser)]
-        gtf: PathBuf,
+        #[clap(short, long, required_unless_present_any = ["transcripts", "feature_csv"], value_parser)]
+        gtf: Option<PathBuf>,
 
         /// the target read length the index will be built for
-        #[clap(short, long, value_parser)]
-        rlen: u32,
+        #[clap(short, long, required_unless_present_any = ["transcripts", "feature_csv"], value_parser)]
+        rlen: Option<u32>,
+
+        /// the number of bases trimmed from the intron flanks (the flank length is rlen minus this)
+        #[clap(
+            long,
+            default_value_t = 5,
+            conflicts_with = "transcripts",
+            value_parser
+        )]
+        flank_trim_length: u32,
+
+        /// the GTF attribute holding gene ids, for annotations that don't use `gene_id`
+        #[clap(long, conflicts_with = "transcripts", value_parser)]
+        gtf_gene_id_attr: Option<String>,
+
+        /// the GTF attribute holding transcript ids, for annotations that don't use `transcript_id`
+        #[clap(long, conflicts_with = "transcripts", value_parser)]
+        gtf_transcript_id_attr: Option<String>,
+
+        /// the GTF feature type of exons, for annotations that don't use `exon`
+        #[clap(long, conflicts_with = "transcripts", value_parser)]
+        gtf_exon_feature: Option<String>,
+
+        /// build a transcript-only index directly from this transcriptome FASTA,
+        /// skipping make-splici (requires --t2g-map or --derive-t2g)
+        #[clap(long, conflicts_with_all = ["fasta", "gtf", "rlen", "spliced", "unspliced", "dedup"], requires = "t2g_source", value_parser)]
+        transcripts: Option<PathBuf>,
+
+        /// transcript to gene map to use with --transcripts
+        #[clap(short = 'm', long, requires = "transcripts", value_parser)]
+        t2g_map: Option<PathBuf>,
+
+        /// derive a one-to-one transcript to gene map from the --transcripts headers
+        #[clap(long, requires = "transcripts", action)]
+        derive_t2g: bool,
+
+        /// build a feature-barcode (e.g. antibody capture or cell hashing) index from this
+        /// 10x-style feature reference CSV, with the columns id and sequence, and optionally
+        /// read and pattern locating the barcode in R2
+        #[clap(long, conflicts_with_all = ["fasta", "gtf", "rlen", "gtf_gene_id_attr", "gtf_transcript_id_attr", "gtf_exon_feature", "transcripts", "spliced", "unspliced", "extra_sequences", "decoy", "dedup", "sparse"], value_parser)]
+        feature_csv: Option<PathBuf>,
 
         /// path to output directory (will be created if it doesn't exist)
         #[clap(short, long, value_parser)]
@@ -46,6 +104,22 @@ enum Commands {
         #[clap(short, long, value_parser)]
         unspliced: Option<PathBuf>,
 
+        /// TSV listing extra sequence FASTA files, one `fasta<TAB>spliced|unspliced[<TAB>gene_id]`
+        /// line each, to add to the splici reference in place of --spliced and --unspliced
+        #[clap(long, conflicts_with_all = ["spliced", "unspliced", "transcripts"], value_parser)]
+        extra_sequences: Option<PathBuf>,
+
+        /// FASTA of decoy sequences salmon should map against but not quantify; on its own
+        /// every record is a decoy (a full genome decoy), while with --decoy-names only
+        /// the named records are
+        #[clap(long, value_parser)]
+        decoy: Option<PathBuf>,
+
+        /// a salmon `decoys.txt` naming, one per line, the records of --decoy to use as
+        /// (partial) decoys
+        #[clap(long, requires = "decoy", value_parser)]
+        decoy_names: Option<PathBuf>,
+
         /// deduplicate identical sequences inside the R script when building the splici reference
         #[clap(short = 'd', long = "dedup", action)]
         dedup: bool,
@@ -54,22 +128,72 @@ enum Commands {
         #[clap(short = 'p', long = "sparse", action)]
         sparse: bool,
 
-        /// number of threads to use when running [default: min(16, num cores)]"
-        #[clap(short, long, default_value_t = 16, value_parser)]
+        /// number of threads to use when running, or `auto` (or 0) for as many as the
+        /// machine and any scheduler allocation provide [default: min(16, num cores)]
+        #[clap(short, long, default_value_t = 16, value_parser = parse_threads)]
         threads: u32,
+
+        /// kill a subprocess stage that runs longer than this many seconds
+        #[clap(long, value_parser)]
+        stage_timeout: Option<u64>,
+
+        /// number of times to retry a stage that exceeded --stage-timeout
+        #[clap(long, default_value_t = 0, requires = "stage_timeout", value_parser)]
+        max_retries: u32,
+
+        /// write the resolved commands to this bash script instead of running them
+        #[clap(long, value_parser)]
+        emit_script: Option<PathBuf>,
+
+        /// a label for the reference (e.g. `GRCh38-2024A`) recorded with the index
+        /// and in the provenance of quant runs that use it
+        #[clap(long, value_parser)]
+        reference_name: Option<String>,
+
+        /// free-text note stored verbatim in the provenance JSON
+        #[clap(long, value_parser)]
+        note: Option<String>,
+
+        /// only run the preflight checks and report whether the index can be
+        /// built, without running make-splici or salmon
+        #[clap(long, conflicts_with = "emit_script", action)]
+        validate_only: bool,
+
+        /// print a rough estimate of the runtime, peak memory and disk use of building
+        /// the index from the input sizes and stop (or, with --validate-only, go on to
+        /// its checks)
+        #[clap(long, conflicts_with = "emit_script", action)]
+        estimate: bool,
+
+        /// continue to salmon index when make-splici fails but reports only non-fatal
+        /// problems (e.g. skipped transcripts), recording them as warnings
+        #[clap(long, action)]
+        keep_going: bool,
+
+        /// treat problems found by the reference preflight checks as errors
+        #[clap(long, action)]
+        strict: bool,
+
+        /// replace a salmon index already in `output/index`; only the index is guarded,
+        /// as the reference in `output/ref` is cheap to rebuild
+        #[clap(long, action)]
+        overwrite: bool,
     },
     /// quantify a sample
     #[clap(arg_required_else_help = true)]
     #[clap(group(
             ArgGroup::new("filter")
-            .required(true)
-            .args(&["knee", "unfiltered-pl", "forced-cells", "expect-cells"])
+            .args(["knee", "unfiltered_pl", "explicit_pl", "forced_cells", "expect_cells"])
             ))]
     Quant {
-        /// path to index
+        /// path to index, or an http(s) URL to a .tar.gz of the index
         #[clap(short, long, value_parser)]
         index: PathBuf,
 
+        /// the SHA-256 checksum the .tar.gz at an --index URL must have
+        #[clap(long, value_parser)]
+        index_sha256: Option<String>,
+
         /// path to read 1 files
         #[clap(short = '1', long = "reads1", value_parser)]
         reads1: Vec<PathBuf>,
@@ -78,10 +202,85 @@ enum Commands {
         #[clap(short = '2', long = "reads2", value_parser)]
         reads2: Vec<PathBuf>,
 
-        /// number of threads to use when running [default: min(16, num cores)]"
-        #[clap(short, long, default_value_t = 16, value_parser)]
+        /// start from an existing salmon alevin RAD output directory (containing `map.rad`),
+        /// e.g. one mapped on another machine, instead of mapping reads
+        #[clap(long, conflicts_with_all = ["reads1", "reads2", "manifest", "downsample", "fail_on_empty_reads", "dump_features"], value_parser)]
+        from_rad: Option<PathBuf>,
+
+        /// quantify only a random subset of about this fraction of the read pairs,
+        /// for a quick approximate QC run
+        #[clap(long, conflicts_with = "emit_script", value_parser = parse_fraction)]
+        downsample: Option<f64>,
+
+        /// check that every read file is non-empty and looks like FASTQ (or gzip)
+        /// before mapping, failing if any does not
+        #[clap(long, action)]
+        fail_on_empty_reads: bool,
+
+        /// check that the first reads of each R1 file are long enough to hold the
+        /// chemistry's barcode and UMI before mapping, failing if most are not
+        #[clap(long, action)]
+        read_geometry_check: bool,
+
+        /// TSV manifest of samples to quantify, one `name<TAB>reads1<TAB>reads2` line per
+        /// sample with comma-separated read files, or a `.csv` samplesheet with a
+        /// `sample,fastq_1,fastq_2` header (rows of the same sample are concatenated);
+        /// each sample is written to `output/<name>`. An optional chemistry column
+        /// (fourth in the TSV) must match --chemistry, as one run uses one chemistry
+        #[clap(long, conflicts_with_all = ["reads1", "reads2"], value_parser)]
+        manifest: Option<PathBuf>,
+
+        /// number of manifest samples to quantify at the same time; the threads are
+        /// divided evenly among them
+        #[clap(long, default_value_t = 1, requires = "manifest", value_parser = clap::value_parser!(u32).range(1..))]
+        parallel_samples: u32,
+
+        /// keep quantifying the remaining manifest samples after one fails, reporting
+        /// every failure at the end
+        #[clap(long, requires = "manifest", action)]
+        continue_on_error: bool,
+
+        /// number of threads to use when running, or `auto` (or 0) for as many as the
+        /// machine and any scheduler allocation provide [default: min(16, num cores)]
+        #[clap(short, long, default_value_t = 16, value_parser = parse_threads)]
         threads: u32,
 
+        /// kill a subprocess stage that runs longer than this many seconds
+        #[clap(long, value_parser)]
+        stage_timeout: Option<u64>,
+
+        /// number of times to retry a stage that exceeded --stage-timeout
+        #[clap(long, default_value_t = 0, requires = "stage_timeout", value_parser)]
+        max_retries: u32,
+
+        /// write the resolved commands to this bash script instead of running them
+        #[clap(long, value_parser)]
+        emit_script: Option<PathBuf>,
+
+        /// print a rough estimate of the runtime, peak memory and disk use of the run
+        /// from the sizes of the reads and the index, and stop
+        #[clap(long, conflicts_with = "emit_script", action)]
+        estimate: bool,
+
+        /// append a row summarizing each quantified sample to this TSV file
+        /// (created with a header if it doesn't exist)
+        #[clap(long, value_parser)]
+        summary_tsv: Option<PathBuf>,
+
+        /// also record the SHA-256 of every file in the `output/manifest.json` listing
+        /// the files of the run, to verify copies of the results against
+        #[clap(long, action)]
+        manifest_checksums: bool,
+
+        /// write a methods-section paragraph citing the exact tool versions
+        /// and key parameters of this run to this file
+        #[clap(long, value_parser)]
+        methods_out: Option<PathBuf>,
+
+        /// free-text note stored verbatim in the provenance JSON
+        #[clap(long, value_parser)]
+        note: Option<String>,
+
         /// use knee filtering mode
         #[clap(short, long, action)]
         knee: bool,
@@ -90,8 +289,21 @@ enum Commands {
         #[clap(short, long, action)]
         unfiltered_pl: bool,
 
+        /// minimum number of reads for a barcode of the unfiltered permit list to be kept
+        #[clap(long, default_value_t = 10, requires = "unfiltered_pl", value_parser)]
+        unfiltered_min_reads: usize,
+
+        /// stop after generate-permit-list if it permits more than this many barcodes,
+        /// which usually means the cell filtering went wrong (0 disables the check)
+        #[clap(long, default_value_t = 500_000, value_parser)]
+        max_barcodes: u64,
+
+        /// only warn, rather than stop, when --max-barcodes is exceeded
+        #[clap(long, action)]
+        warn_max_barcodes: bool,
+
         /// use a filtered, explicit permit list
-        #[clap(short, long, value_parser)]
+        #[clap(short = 'x', long, value_parser)]
         explicit_pl: Option<PathBuf>,
 
         /// use forced number of cells
@@ -102,21 +314,245 @@ enum Commands {
         #[clap(short, long, value_parser)]
         expect_cells: Option<usize>,
 
-        /// resolution mode
-        #[clap(short, long, value_parser = clap::builder::PossibleValuesParser::new(["cr-like", "cr-like-em", "parsimony", "parsimony-em", "parsimony-gene", "parsimony-gene-em"]))]
-        resolution: String,
-
-        /// chemistry
-        #[clap(short, long, value_parser)]
-        chemistry: String,
+        /// resolution mode [default: chosen by the chemistry, cr-like for 10x]; given several
+        /// (comma-separated) modes, the mapping is quantified once per mode into `af_quant_<mode>`;
+        /// see `simpleaf list-resolutions` for what each mode does
+        #[clap(short, long, value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(RESOLUTIONS))]
+        resolution: Vec<String>,
+
+        /// for the parsimony resolutions, the number of nodes above which a UMI graph
+        /// is resolved with a faster, approximate method (requires alevin-fry >= 0.4.1)
+        #[clap(long, value_parser)]
+        large_graph_thresh: Option<usize>,
+
+        /// for the parsimony-gene resolutions, only collapse identical UMIs rather than
+        /// those within one edit (requires alevin-fry >= 0.5.0)
+        #[clap(long, action)]
+        pug_exact_umi: bool,
+
+        /// format of the count matrix written by alevin-fry quant; requested explicitly,
+        /// as alevin-fry >= 0.5.0 writes mtx unless told otherwise
+        #[clap(long, default_value = "eds", value_parser = clap::builder::PossibleValuesParser::new(["eds", "mtx"]))]
+        output_format: String,
+
+        /// split the USA-mode count matrix into spliced, unspliced and ambiguous
+        /// matrices under `output/velocity` for RNA velocity tools (needs --output-format mtx)
+        #[clap(long, action)]
+        velocity: bool,
+
+        /// write the barcodes of the cells that passed filtering to
+        /// `output/permitted_barcodes.txt`, one per line
+        #[clap(long, action)]
+        output_barcodes: bool,
+
+        /// file of gene ids, one per line; the count matrix restricted to these genes
+        /// is also written to `output/allowlisted` (needs --output-format mtx)
+        #[clap(long, value_parser)]
+        gene_allowlist: Option<PathBuf>,
+
+        /// have alevin-fry quant write its extra per-cell summary statistics, copied to
+        /// `output/qc` with their medians (requires alevin-fry >= 0.4.1)
+        #[clap(long, action)]
+        summary_stat: bool,
+
+        /// plot the reads of every barcode generate-permit-list saw by rank to
+        /// `output/qc/knee.png`, to judge the filtering threshold (needs simpleaf built
+        /// with the knee-plot feature)
+        #[clap(long, action)]
+        knee_plot: bool,
+
+        /// expected orientation of the mapped reads (use rc for reverse-complemented
+        /// libraries); when given, used consistently for the salmon library type and
+        /// generate-permit-list, or `auto` to map unstranded and choose fw, rc or both
+        /// from the mapped orientations [default: chosen by the chemistry, fw for 10x,
+        /// with salmon's library type left at A]
+        #[clap(short = 'd', long, value_parser = clap::builder::PossibleValuesParser::new(EXPECTED_ORIS))]
+        expected_ori: Option<String>,
+
+        /// chemistry; either a known name (10xv2, 10xv3), a salmon technology flag,
+        /// or a path to a JSON file defining a custom barcode/UMI/read geometry
+        #[clap(short, long, required_unless_present_any = ["sample_sheet", "profile"], value_parser)]
+        chemistry: Option<String>,
+
+        /// a named preset of the chemistry, cell filtering method, resolution and
+        /// orientation (built in: 10x-3prime-v2-standard, 10x-3prime-v3-standard, or
+        /// defined in $ALEVIN_FRY_HOME/profiles.json); explicit flags override it
+        #[clap(long, value_parser)]
+        profile: Option<String>,
+
+        /// sample sheet or run metadata CSV (e.g. a cellranger multi config) from which
+        /// to infer the chemistry when --chemistry is not given
+        #[clap(long, value_parser)]
+        sample_sheet: Option<PathBuf>,
+
+        /// the format salmon writes the mapping in, which alevin-fry must be able to read
+        #[clap(long, default_value = "rad", value_parser = clap::builder::PossibleValuesParser::new(["rad", "bam"]))]
+        mapping_output_format: String,
+
+        /// hit filtering policy for salmon's sketch mode mapping
+        #[clap(long, value_parser = clap::builder::PossibleValuesParser::new(["BEFORE", "AFTER", "BOTH", "NONE"]))]
+        hit_filter_policy: Option<String>,
+
+        /// ignore reads with more than this many mappings in salmon's sketch mode
+        #[clap(long, value_parser)]
+        max_read_occ: Option<u32>,
+
+        /// have salmon alevin write its per-barcode feature statistics (--dumpFeatures),
+        /// copied to `output/qc`
+        #[clap(long, action)]
+        dump_features: bool,
+
+        /// the maximum number of read records collate keeps in memory at once;
+        /// lower it if collate runs out of memory
+        #[clap(long, value_parser)]
+        collate_max_records: Option<u32>,
+
+        /// have collate compress its output to save disk space
+        #[clap(long, action)]
+        collate_compress: bool,
 
         /// transcript to gene map
         #[clap(short = 'm', long, value_parser)]
         t2g_map: PathBuf,
 
-        /// output directory
+        /// check that the t2g map covers every transcript in the index
+        /// (requires an index built by `simpleaf index`)
+        #[clap(long, action)]
+        check_t2g: bool,
+
+        /// fail, rather than warn, if --check-t2g finds unmapped transcripts
+        #[clap(long, requires = "check_t2g", action)]
+        strict: bool,
+
+        /// fail, rather than warn, if read 1 and read 2 of a sample look swapped
+        #[clap(long, action)]
+        strict_read_order: bool,
+
+        /// check that the index is complete before mapping, with salmon's own validation
+        /// where the salmon version has one, or else by checking the index files
+        #[clap(long, action)]
+        check_index: bool,
+
+        /// output directory; may be a template using {chemistry}, {date}, {time},
+        /// {reference_name} and {resolution}, e.g. `runs/{chemistry}/{date}`
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+    },
+    /// compare the provenance of two runs field by field
+    #[clap(arg_required_else_help = true)]
+    Diff {
+        /// the first provenance JSON, or a simpleaf output directory containing one
+        #[clap(value_parser)]
+        a: PathBuf,
+
+        /// the second provenance JSON, or a simpleaf output directory containing one
+        #[clap(value_parser)]
+        b: PathBuf,
+
+        /// also compare the fields that vary between runs anyway, such as timings
+        #[clap(long, action)]
+        include_timing: bool,
+    },
+    /// sum the count matrices of several quant outputs (e.g. per-lane runs) into one
+    #[clap(arg_required_else_help = true)]
+    Merge {
+        /// the quant output (or `af_quant`) directories to merge
+        #[clap(short, long, required = true, num_args = 2.., value_parser)]
+        inputs: Vec<PathBuf>,
+
+        /// path to output directory (will be created if it doesn't exist)
+        #[clap(short, long, value_parser)]
+        output: PathBuf,
+    },
+    /// download (if not already cached) the permit list of a single chemistry
+    #[clap(arg_required_else_help = true)]
+    FetchPermitList {
+        /// chemistry whose permit list to fetch (10xv2, 10xv3, or a custom chemistry JSON file)
+        #[clap(short, long, value_parser)]
+        chemistry: String,
+
+        /// kill the download if it runs longer than this many seconds
+        #[clap(long, value_parser)]
+        stage_timeout: Option<u64>,
+
+        /// number of times to retry a download that exceeded --stage-timeout
+        #[clap(long, default_value_t = 0, requires = "stage_timeout", value_parser)]
+        max_retries: u32,
+    },
+    /// describe each UMI resolution mode quant supports and when to use it
+    ListResolutions,
+    /// print the path of a chemistry's permit list (downloading it if needed), and nothing else
+    #[clap(arg_required_else_help = true)]
+    PermitListPath {
+        /// chemistry whose permit list to locate (10xv2, 10xv3, or a custom chemistry JSON file)
+        #[clap(short, long, value_parser)]
+        chemistry: String,
+    },
+    /// exit successfully only if the index or quant run in a directory completed,
+    /// printing why not otherwise (for pipelines and schedulers)
+    #[clap(arg_required_else_help = true)]
+    Check {
+        /// the output directory of the run
+        #[clap(value_parser)]
+        dir: PathBuf,
+    },
+    /// check that a t2g file has 2 or 3 columns, no duplicate transcripts and (with 3
+    /// columns) only S, U or A statuses, printing each problem with its line number
+    #[clap(arg_required_else_help = true)]
+    ValidateT2g {
+        /// the t2g file to check
+        #[clap(value_parser)]
+        t2g: PathBuf,
+    },
+    /// remove intermediate or all non-provenance files from simpleaf output directories
+    #[clap(arg_required_else_help = true)]
+    Clean {
+        /// the output directories to clean
+        #[clap(required = true, value_parser)]
+        dirs: Vec<PathBuf>,
+
+        /// what to remove; `intermediates` removes the mapping output and splici
+        /// reference, `all` removes everything but the provenance files
+        #[clap(short, long, default_value = "intermediates", value_parser = clap::builder::PossibleValuesParser::new(["intermediates", "all"]))]
+        level: String,
+
+        /// clean directories even if they contain no simpleaf provenance file
+        #[clap(short, long, action)]
+        force: bool,
+    },
+    /// download the programs simpleaf uses into $ALEVIN_FRY_HOME/bin and record their paths
+    #[clap(arg_required_else_help = true)]
+    Setup {
+        /// JSON manifest giving, per platform (e.g. `linux-x86_64`) and tool, the `url`
+        /// to download, its `sha256` checksum and, for a `.tar.gz` archive, the path of
+        /// the `executable` inside it; tools it doesn't list are looked up in the PATH
+        #[clap(short, long, value_parser)]
+        manifest: PathBuf,
+
+        /// kill a download if it runs longer than this many seconds
+        #[clap(long, value_parser)]
+        stage_timeout: Option<u64>,
+
+        /// number of times to retry a download that exceeded --stage-timeout
+        #[clap(long, default_value_t = 0, requires = "stage_timeout", value_parser)]
+        max_retries: u32,
+    },
+    /// write a conda environment (or list of version pins) that reproduces
+    /// the tool versions simpleaf is set up with, or those a run recorded
+    #[clap(arg_required_else_help = true)]
+    EmitEnv {
+        /// the file to write, e.g. `environment.yml`
         #[clap(short, long, value_parser)]
         output: PathBuf,
+
+        /// a provenance JSON, or a simpleaf output directory containing one, whose
+        /// recorded versions to use instead of those set up with set-paths
+        #[clap(long, value_parser)]
+        from: Option<PathBuf>,
+
+        /// write a conda environment.yml, or plain `package=version` pins
+        #[clap(long, default_value = "conda", value_parser = clap::builder::PossibleValuesParser::new(["conda", "pins"]))]
+        format: String,
     },
     /// set paths to the programs that simpleaf will use
     SetPaths {
@@ -134,15 +570,585 @@ enum Commands {
 
 /// simplifying alevin-fry workflows
 #[derive(Debug, Parser)]
+#[clap(
+    after_help = "Defaults for any option can be kept in a JSON `.simpleafrc` in the home \
+    directory and in the current (project) directory, as in \
+    {\"global\": {\"alevin-fry-home\": \"/data/af\"}, \"quant\": {\"threads\": 8}}. \
+    An option given on the command line wins over the project .simpleafrc, \
+    which wins over the home .simpleafrc, which wins over the built-in default."
+)]
 struct Cli {
+    /// write newline-delimited JSON progress events to stdout
+    /// (human-readable logging always goes to stderr)
+    #[clap(long, global = true, action)]
+    json_logs: bool,
+
+    /// print a plain-language description of each stage before it runs
+    #[clap(long, global = true, action)]
+    explain: bool,
+
+    /// require exact tool versions and fail if they drift, e.g. `salmon=1.9.0,alevin-fry=0.8.2`
+    #[clap(long, global = true, value_parser)]
+    require_versions: Option<String>,
+
+    /// accept tool versions newer than those simpleaf was tested with,
+    /// warning about them instead of failing
+    #[clap(long, global = true, action)]
+    allow_newer: bool,
+
+    /// the directory simpleaf keeps its configuration and caches in
+    /// [default: $ALEVIN_FRY_HOME, or ~/.afhome if that is unset]
+    #[clap(long, global = true, value_parser)]
+    alevin_fry_home: Option<PathBuf>,
+
+    /// make the files and directories written by `index` and `quant` writable by
+    /// their group, so collaborators in shared directories can manage them
+    #[clap(long, global = true, action)]
+    group_writable: bool,
+
+    /// keep the intermediate files (the mapping, the splici reference) of a failed `index`
+    /// or `quant` run for debugging, naming them in the error; `false` removes them
+    #[clap(long, global = true, default_value_t = true, action = clap::ArgAction::Set)]
+    keep_tmp_on_failure: bool,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
-enum Chemistry {
-    TenxV2,
-    TenxV3,
-    Other(String),
+// The file of persistent option defaults, read from the home
+// directory and then from the current (project) directory. It holds
+// a JSON object with a "global" section and one per subcommand, each
+// mapping long option names to values. The project file overrides
+// the home file key by key, and the command line overrides both.
+const RC_FILE: &str = ".simpleafrc";
+
+// The option defaults of a `.simpleafrc` section, as (option,
+// value, the file it comes from).
+type RcSection = Vec<(String, serde_json::Value, PathBuf)>;
+
+// The option defaults of the `.simpleafrc` files, per section.
+fn read_rc_defaults() -> Result<HashMap<String, RcSection>> {
+    let mut rcs = Vec::new();
+    if let Some(h) = env::var_os("HOME") {
+        rcs.push(PathBuf::from(h).join(RC_FILE));
+    }
+    rcs.push(PathBuf::from(RC_FILE));
+    // the project directory may be the home directory
+    if rcs.len() == 2 && rcs[0].canonicalize().ok() == rcs[1].canonicalize().ok() {
+        rcs.pop();
+    }
+
+    let mut sections: HashMap<String, RcSection> = HashMap::new();
+    for rc in rcs.into_iter().filter(|p| p.is_file()) {
+        let text = std::fs::read_to_string(&rc)
+            .with_context(|| format!("could not read {}", rc.display()))?;
+        let v: serde_json::Value = serde_json::from_str(&text)
+            .with_context(|| format!("{} is not valid JSON", rc.display()))?;
+        let Some(obj) = v.as_object() else {
+            bail!("{} must hold a JSON object of sections", rc.display());
+        };
+        for (section, opts) in obj {
+            let Some(opts) = opts.as_object() else {
+                bail!(
+                    "section {:?} of {} must be an object of option values",
+                    section,
+                    rc.display()
+                );
+            };
+            let entries = sections.entry(section.clone()).or_default();
+            for (k, v) in opts {
+                // a later (project) file overrides an earlier one
+                entries.retain(|(ek, _, _)| ek != k);
+                entries.push((k.clone(), v.clone(), rc.clone()));
+            }
+        }
+    }
+    Ok(sections)
+}
+
+// Adds the `.simpleafrc` defaults of the global options and of the
+// subcommand in `args` that the command line leaves unset to `args`.
+fn args_with_rc_defaults(args: Vec<std::ffi::OsString>) -> Result<Vec<std::ffi::OsString>> {
+    let sections = read_rc_defaults()?;
+    if sections.is_empty() {
+        return Ok(args);
+    }
+    apply_rc_defaults(args, &sections)
+}
+
+// The argument given on the command line (one of `given`) that `arg`
+// conflicts with or shares an argument group with, if any.
+fn conflicting_given_arg<'a>(
+    cmd: &'a clap::Command,
+    arg: &clap::Arg,
+    given: &[&'a clap::Arg],
+) -> Option<&'a clap::Arg> {
+    let same = |a: &clap::Arg, b: &clap::Arg| a.get_id() == b.get_id();
+    let conflicts = cmd.get_arg_conflicts_with(arg);
+    given.iter().copied().find(|g| {
+        conflicts.iter().any(|c| same(c, g))
+            || cmd
+                .get_arg_conflicts_with(g)
+                .iter()
+                .any(|c| same(c, arg))
+            // only a group that doesn't allow several of its
+            // arguments excludes the others (clap's derive also adds
+            // one allowing all of a subcommand's arguments)
+            || cmd.get_groups().any(|grp| {
+                !grp.clone().is_multiple()
+                    && grp.get_args().any(|id| id == arg.get_id())
+                    && grp.get_args().any(|id| id == g.get_id())
+            })
+    })
+}
+
+// Adds the defaults in `sections` to `args` (see `args_with_rc_defaults`).
+fn apply_rc_defaults(
+    args: Vec<std::ffi::OsString>,
+    sections: &HashMap<String, RcSection>,
+) -> Result<Vec<std::ffi::OsString>> {
+    use clap::parser::ValueSource;
+    use clap::CommandFactory;
+
+    let cmd = Cli::command();
+    // leave reporting malformed arguments to the real parse
+    let Ok(matches) = cmd.clone().ignore_errors(true).try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    let Some((sub_name, sub_matches)) = matches.subcommand() else {
+        return Ok(args);
+    };
+    let sub_cmd = cmd
+        .find_subcommand(sub_name)
+        .context("unknown subcommand")?;
+
+    let mut extra = Vec::new();
+    // global options may be given before or after the subcommand
+    let sections_given = [
+        ("global", &cmd, vec![&matches, sub_matches]),
+        (sub_name, sub_cmd, vec![sub_matches]),
+    ];
+    for (section, section_cmd, given_in) in sections_given {
+        let is_given = |a: &clap::Arg| {
+            given_in
+                .iter()
+                .any(|m| m.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine))
+        };
+        let given = section_cmd
+            .get_arguments()
+            .filter(|a| is_given(a))
+            .collect::<Vec<&clap::Arg>>();
+        for (key, val, rc) in sections.get(section).into_iter().flatten() {
+            let Some(arg) = section_cmd
+                .get_arguments()
+                .find(|a| a.get_long() == Some(key.as_str()))
+            else {
+                bail!(
+                    "{} sets --{}, which is not an option of {}",
+                    rc.display(),
+                    key,
+                    if section == "global" {
+                        "simpleaf"
+                    } else {
+                        section
+                    }
+                );
+            };
+            if is_given(arg) {
+                continue;
+            }
+            // the command line wins over a default it rules out
+            if let Some(g) = conflicting_given_arg(section_cmd, arg, &given) {
+                info!(
+                    "ignoring --{} from {}, as it can't be combined with the given --{}",
+                    key,
+                    rc.display(),
+                    g.get_long().unwrap_or(g.get_id().as_str())
+                );
+                continue;
+            }
+            let flag = format!("--{}", key);
+            match (arg.get_action(), val) {
+                (clap::ArgAction::SetTrue, serde_json::Value::Bool(b)) => {
+                    if *b {
+                        extra.push(flag);
+                    }
+                }
+                (clap::ArgAction::SetTrue, _) => bail!(
+                    "{} sets the flag --{} to {}; it must be true or false",
+                    rc.display(),
+                    key,
+                    val
+                ),
+                (_, serde_json::Value::Array(vs)) => {
+                    for v in vs {
+                        extra.push(flag.clone());
+                        extra.push(rc_value_string(v));
+                    }
+                }
+                (_, v) => {
+                    extra.push(flag);
+                    extra.push(rc_value_string(v));
+                }
+            }
+            info!("using --{} {} from {}", key, val, rc.display());
+        }
+    }
+    if extra.is_empty() {
+        return Ok(args);
+    }
+    // the defaults go right after the subcommand, ahead of any positional arguments
+    let at = args
+        .iter()
+        .position(|a| a.as_os_str() == sub_name)
+        .map_or(args.len(), |i| i + 1);
+    let mut args = args;
+    args.splice(at..at, extra.into_iter().map(std::ffi::OsString::from));
+    Ok(args)
+}
+
+fn rc_value_string(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+// A methods-section paragraph describing how `qs` was quantified,
+// with the exact tool versions, followed by the references to cite.
+fn methods_text(qs: &QuantSettings) -> String {
+    let index_info = read_index_info(&qs.index).unwrap_or_default();
+    let pyroe_version = index_info["version_info"]["pyroe"]["version"].as_str();
+    let index_salmon = index_info["version_info"]["salmon"]["version"].as_str();
+    // an index built over given transcripts has no splici reference
+    let rlen = match index_info["args"]["transcripts"] {
+        serde_json::Value::Null => index_info["args"]["rlen"].as_u64(),
+        _ => None,
+    };
+
+    let chemistry = match &qs.chem {
+        Chemistry::TenxV2 => String::from("10x Chromium v2"),
+        Chemistry::TenxV3 => String::from("10x Chromium v3"),
+        Chemistry::Custom(cc) => format!(
+            "a custom chemistry (barcode geometry {}, UMI geometry {}, read geometry {})",
+            cc.barcode_geometry, cc.umi_geometry, cc.read_geometry
+        ),
+        Chemistry::Other(s) => format!("the {} chemistry", s),
+    };
+
+    let mut text = format!(
+        "Reads generated with {} were processed with simpleaf v{}. ",
+        chemistry,
+        clap::crate_version!()
+    );
+    match (pyroe_version, rlen) {
+        (Some(pv), Some(rlen)) => text.push_str(&format!(
+            "A spliced+intronic (splici) reference for a read length of {} was built \
+            with pyroe v{} and indexed with salmon v{}. ",
+            rlen,
+            pv,
+            index_salmon.unwrap_or(&qs.salmon.version)
+        )),
+        _ => text.push_str(&format!(
+            "The reference was indexed with salmon v{}. ",
+            index_salmon.unwrap_or(&qs.salmon.version)
+        )),
+    }
+    match &qs.from_rad {
+        // the mapping was run elsewhere, so only record
+        // the salmon version it reports, if any
+        Some(rad) => {
+            let map_salmon = std::fs::read_to_string(rad.join("aux_info").join("meta_info.json"))
+                .ok()
+                .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+                .and_then(|v| v["salmon_version"].as_str().map(String::from));
+            match map_salmon {
+                Some(v) => text.push_str(&format!(
+                    "Reads were mapped to the index with salmon alevin v{} (Patro et al., 2017). ",
+                    v
+                )),
+                None => text.push_str(
+                    "Reads were mapped to the index with salmon alevin (Patro et al., 2017). ",
+                ),
+            }
+        }
+        None => text.push_str(&format!(
+            "Reads were mapped to the index in sketch mode with salmon alevin v{} (Patro et al., 2017). ",
+            qs.salmon.version
+        )),
+    }
+    text.push_str(&format!(
+        "Using alevin-fry v{} (He et al., 2022), cell barcodes were identified by {}, \
+        and UMIs were resolved with the {} {}.",
+        qs.alevin_fry.version,
+        qs.filter_meth.describe(),
+        qs.resolutions.join(" and "),
+        if qs.resolutions.len() > 1 {
+            "strategies"
+        } else {
+            "strategy"
+        }
+    ));
+    if let Some(f) = qs.downsample {
+        text.push_str(&format!(
+            " The reads were first downsampled to a fraction of {} of the read pairs.",
+            f
+        ));
+    }
+    text.push_str(
+        "\n\nReferences\n\
+        Patro, R., Duggal, G., Love, M. I., Irizarry, R. A. & Kingsford, C. Salmon provides \
+        fast and bias-aware quantification of transcript expression. Nature Methods 14, \
+        417-419 (2017).\n\
+        He, D., Zakeri, M., Sarkar, H., Soneson, C., Srivastava, A. & Patro, R. Alevin-fry \
+        unlocks rapid, accurate and memory-frugal quantification of single-cell RNA-seq \
+        data. Nature Methods 19, 316-322 (2022).\n",
+    );
+    text
+}
+
+// Prints, for --explain, a plain-language description of what
+// `stage` is about to do, followed by any run-specific `detail`.
+fn explain_stage(stage: &str, detail: Option<String>) {
+    let what = match stage {
+        "make-splici" => {
+            "Building the splici reference: pyroe extracts the spliced transcripts \
+            and the introns of every gene from the genome and annotation, so that \
+            reads from both mature and still-unspliced RNA can be told apart."
+        }
+        "salmon-index" => {
+            "Indexing the reference: salmon builds a searchable index of every \
+            sequence in the reference so that reads can be matched to it quickly."
+        }
+        "map" => {
+            "Mapping reads: salmon finds where each read comes from in the reference, \
+            and records its cell barcode and UMI (the molecule tag) alongside."
+        }
+        "permit" => {
+            "Building the permit list: alevin-fry counts the reads of every cell \
+            barcode and decides which barcodes correspond to real cells, correcting \
+            barcodes with sequencing errors along the way."
+        }
+        "collate" => {
+            "Collating: alevin-fry groups the mapped reads by cell, so that each \
+            cell's reads can be counted together."
+        }
+        "quant" => {
+            "Quantifying: alevin-fry counts the distinct molecules (UMIs) of every \
+            gene in every cell to produce the gene-by-cell count matrix."
+        }
+        _ => return,
+    };
+    match detail {
+        Some(d) => eprintln!("\n{} {}\n", what, d),
+        None => eprintln!("\n{}\n", what),
+    }
+}
+
+// What a UMI resolution strategy does, and when to choose it.
+fn resolution_description(res: &str) -> Option<(&'static str, &'static str)> {
+    Some(match res {
+        "cr-like" => (
+            "counts a UMI seen with several genes only for the gene it was seen with most",
+            "the default for 10x data; closest to Cell Ranger's counts",
+        ),
+        "cr-like-em" => (
+            "is like cr-like, but shares UMIs seen with several genes out between them \
+            by their estimated abundance",
+            "to keep the reads of multi-gene UMIs (e.g. paralogs) rather than drop them",
+        ),
+        "parsimony" => (
+            "merges UMIs that differ by one error, explaining the reads with as few \
+            molecules as possible",
+            "when UMI sequencing errors would otherwise inflate the counts",
+        ),
+        "parsimony-em" => (
+            "is like parsimony, but shares molecules that could come from several genes \
+            out between them by their estimated abundance",
+            "for error correction that also keeps multi-gene molecules",
+        ),
+        "parsimony-gene" => (
+            "is a parsimony variant that resolves UMIs at the level of genes rather than \
+            transcripts",
+            "for faster, less memory-hungry error correction on large datasets",
+        ),
+        "parsimony-gene-em" => (
+            "is like parsimony-gene, but shares molecules that could come from several \
+            genes out between them by their estimated abundance",
+            "for gene-level error correction that also keeps multi-gene molecules",
+        ),
+        _ => return None,
+    })
+}
+
+// What a UMI resolution strategy does, for --explain.
+fn resolution_explanation(res: &str) -> Option<String> {
+    let (how, _) = resolution_description(res)?;
+    Some(format!("The {} resolution {}.", res, how))
+}
+
+// The file in $ALEVIN_FRY_HOME listing extra permit list mirrors
+// per chemistry, as `{"10xv3": ["https://...", ...]}`, where a mirror
+// may also be given as `{"url": "https://...", "sha256": "..."}` to
+// verify what it serves.
+const PERMIT_MIRRORS_FILE: &str = "permit_list_mirrors.json";
+
+// A location to download a permit list from, with the SHA-256 of
+// the decompressed list if it is known.
+struct PermitSource {
+    url: String,
+    sha256: Option<String>,
+}
+
+impl PermitSource {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            sha256: None,
+        }
+    }
+}
+
+// The number of barcodes in the 10x v2 (737K-august-2016) and v3
+// (3M-february-2018) permit lists, each a 16-base barcode per line.
+const TENX_V2_PERMIT_BARCODES: usize = 737_280;
+const TENX_V3_PERMIT_BARCODES: usize = 6_794_880;
+const TENX_BARCODE_LEN: usize = 16;
+
+// Checks that the downloaded permit list `p` is what `source` should
+// serve: the list with its SHA-256, if known, and one of exactly
+// `num_barcodes` barcodes of `bc_len` bases, if given. An HTML error
+// page or a truncated list from a broken mirror fails this.
+fn verify_permit_list(
+    p: &Path,
+    source: &PermitSource,
+    barcodes: Option<(usize, usize)>,
+) -> Result<()> {
+    if let Some(ref expected) = source.sha256 {
+        let digest = sha256_file(p)?;
+        if !digest.eq_ignore_ascii_case(expected.trim()) {
+            bail!(
+                "the permit list from {} has SHA-256 {}, but {} was expected",
+                source.url,
+                digest,
+                expected
+            );
+        }
+    }
+    if let Some((num_barcodes, bc_len)) = barcodes {
+        let f =
+            std::fs::File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+        let mut n = 0;
+        for (i, l) in BufReader::new(f).lines().enumerate() {
+            let l = l.with_context(|| format!("could not read {}", p.display()))?;
+            let l = l.trim_end();
+            if l.len() != bc_len || !l.bytes().all(|b| b"ACGT".contains(&b)) {
+                bail!(
+                    "line {} of the permit list from {} is not a {}-base barcode",
+                    i + 1,
+                    source.url,
+                    bc_len
+                );
+            }
+            n += 1;
+        }
+        if n != num_barcodes {
+            bail!(
+                "the permit list from {} has {} barcodes, but {} were expected",
+                source.url,
+                n,
+                num_barcodes
+            );
+        }
+    }
+    Ok(())
+}
+
+// The user-configured mirrors for `chem`, which are tried before
+// the built-in download location.
+fn permit_list_mirrors(chem: &str, af_home: &Path) -> Result<Vec<PermitSource>> {
+    let p = af_home.join(PERMIT_MIRRORS_FILE);
+    if !p.is_file() {
+        return Ok(Vec::new());
+    }
+    let f = std::fs::File::open(&p).with_context(|| format!("could not open {}", p.display()))?;
+    let v: serde_json::Value = serde_json::from_reader(BufReader::new(f))
+        .with_context(|| format!("could not parse {}", p.display()))?;
+    match v.get(chem) {
+        None => Ok(Vec::new()),
+        Some(serde_json::Value::Array(urls)) => urls
+            .iter()
+            .map(|u| {
+                let (url, sha256) = match u {
+                    serde_json::Value::Object(m) => (
+                        m.get("url").and_then(|u| u.as_str()),
+                        m.get("sha256").and_then(|s| s.as_str()).map(String::from),
+                    ),
+                    u => (u.as_str(), None),
+                };
+                match url {
+                    Some(url) if is_url(url) => Ok(PermitSource {
+                        url: url.to_string(),
+                        sha256,
+                    }),
+                    _ => Err(anyhow!(
+                        "the {} mirrors in {} must be a list of http(s) URLs",
+                        chem,
+                        p.display()
+                    )),
+                }
+            })
+            .collect(),
+        Some(_) => bail!(
+            "the {} mirrors in {} must be a list of http(s) URLs",
+            chem,
+            p.display()
+        ),
+    }
+}
+
+// Downloads a permit list from `source` to `pl_file`, decompressing
+// it if needed, and returns its size once it is known to be non-empty
+// and passes `verify_permit_list`. The list is fetched into
+// `pl_file.partial` and only renamed to `pl_file` once it is complete
+// and verified, so neither an interrupted download nor a bad mirror's
+// is ever mistaken for a cached list by a later run.
+fn download_permit_list(
+    source: &PermitSource,
+    barcodes: Option<(usize, usize)>,
+    pl_file: &Path,
+    stage_opts: &StageOpts,
+) -> Result<u64> {
+    let dl_url = &source.url;
+    let partial = partial_path(pl_file);
+    let res = (|| -> Result<u64> {
+        download_file(dl_url, &partial, stage_opts).context("failed to download permit list")?;
+        // the link may serve the list compressed or not, but
+        // alevin-fry needs it as plain text.
+        if gunzip_in_place(&partial)? {
+            info!(
+                "decompressed the downloaded permit list {}",
+                partial.display()
+            );
+        }
+        let nbytes = std::fs::metadata(&partial)?.len();
+        if nbytes == 0 {
+            bail!("the permit list downloaded from {} is empty", dl_url);
+        }
+        verify_permit_list(&partial, source, barcodes)?;
+        std::fs::rename(&partial, pl_file).with_context(|| {
+            format!(
+                "could not move {} to {}",
+                partial.display(),
+                pl_file.display()
+            )
+        })?;
+        Ok(nbytes)
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    res
 }
 
 enum PermitListResult {
@@ -151,67 +1157,852 @@ enum PermitListResult {
     UnregisteredChemistry,
 }
 
-fn get_permit_if_absent(chem: Chemistry) -> Result<PermitListResult> {
+fn get_permit_if_absent(
+    chem: &Chemistry,
+    af_home: &Path,
+    stage_opts: &StageOpts,
+) -> Result<PermitListResult> {
     let chem_file;
-    let dl_url;
+    let mut dl_urls;
+    // the shape of the lists we know, to verify every mirror by
+    let mut barcodes = None;
     match chem {
         Chemistry::TenxV2 => {
-            chem_file = "10x_v2_permit.txt";
-            dl_url = "https://umd.box.com/shared/static/jbs2wszgbj7k4ic2hass9ts6nhqkwq1p";
+            chem_file = String::from("10x_v2_permit.txt");
+            dl_urls = permit_list_mirrors("10xv2", af_home)?;
+            dl_urls.push(PermitSource::new(
+                "https://umd.box.com/shared/static/jbs2wszgbj7k4ic2hass9ts6nhqkwq1p",
+            ));
+            barcodes = Some((TENX_V2_PERMIT_BARCODES, TENX_BARCODE_LEN));
         }
         Chemistry::TenxV3 => {
-            chem_file = "10x_v3_permit.txt";
-            dl_url = "https://umd.box.com/shared/static/eo0qlkfqf2v24ws6dfnxty6gqk1otf2h";
+            chem_file = String::from("10x_v3_permit.txt");
+            dl_urls = permit_list_mirrors("10xv3", af_home)?;
+            dl_urls.push(PermitSource::new(
+                "https://umd.box.com/shared/static/eo0qlkfqf2v24ws6dfnxty6gqk1otf2h",
+            ));
+            barcodes = Some((TENX_V3_PERMIT_BARCODES, TENX_BARCODE_LEN));
         }
+        Chemistry::Custom(cc) => match &cc.permit_list {
+            Some(pl) if is_url(pl) => {
+                // cache remote custom permit lists under
+                // the last component of their URL
+                let name = pl.trim_end_matches('/').rsplit('/').next().unwrap_or(pl);
+                chem_file = format!("custom_{}", name);
+                dl_urls = vec![PermitSource::new(pl)];
+            }
+            Some(pl) => {
+                let p = PathBuf::from(pl);
+                if !p.is_file() {
+                    bail!(
+                        "custom chemistry permit list {} does not exist",
+                        p.display()
+                    );
+                }
+                return Ok(PermitListResult::AlreadyPresent(p));
+            }
+            None => {
+                return Ok(PermitListResult::UnregisteredChemistry);
+            }
+        },
         _ => {
             return Ok(PermitListResult::UnregisteredChemistry);
         }
     }
-    match env::var("ALEVIN_FRY_HOME") {
-        Ok(p) => {
-            let odir = PathBuf::from(p).join("plist");
-            if odir.join(chem_file).exists() {
-                Ok(PermitListResult::AlreadyPresent(odir.join(chem_file)))
-            } else {
-                run_fun!(mkdir -p $odir)?;
-                let mut dl_cmd = std::process::Command::new("wget");
-                dl_cmd
-                    .arg("-v")
-                    .arg("-O")
-                    .arg(odir.join(chem_file).to_string_lossy().to_string())
-                    .arg("-L")
-                    .arg(dl_url);
-                let r = dl_cmd.output()?;
-                if !r.status.success() {
-                    return Err(anyhow!("failed to download permit list {:?}", r.status));
-                }
-                Ok(PermitListResult::DownloadSuccessful(odir.join(chem_file)))
-            }
-        }
-        Err(e) => {
-            return Err(anyhow!(
-                "could not resolve $ALEVIN_FRY_HOME environment variable : {}",
+    let odir = af_home.join("plist");
+    // an hour without a write is well past any live download
+    let removed = remove_stale_partials(&odir, std::time::Duration::from_secs(3600))?;
+    if removed > 0 {
+        info!(
+            "removed {} interrupted permit list download(s) from {}",
+            removed,
+            odir.display()
+        );
+    }
+    if odir.join(&chem_file).exists() {
+        Ok(PermitListResult::AlreadyPresent(odir.join(&chem_file)))
+    } else {
+        // tell a cache directory we can't write to
+        // apart from a download that failed.
+        ensure_dir_writable(&odir, "permit list cache directory")?;
+        let pl_file = odir.join(&chem_file);
+        // try each mirror in turn until one yields a usable list
+        let mut failures = Vec::new();
+        for source in &dl_urls {
+            match download_permit_list(source, barcodes, &pl_file, stage_opts) {
+                Ok(nbytes) => {
+                    info!("permit list {} is {} bytes", pl_file.display(), nbytes);
+                    return Ok(PermitListResult::DownloadSuccessful(pl_file));
+                }
+                Err(e) => {
+                    warn!(
+                        "could not fetch the permit list from {} : {:#}",
+                        source.url, e
+                    );
+                    failures.push(format!("  {} : {:#}", source.url, e));
+                }
+            }
+        }
+        bail!(
+            "failed to download the permit list from any of its {} mirror(s):\n{}",
+            dl_urls.len(),
+            failures.join("\n")
+        )
+    }
+}
+
+// What a line pyroe wrote to stderr reports.
+#[derive(Debug, PartialEq)]
+enum PyroeLine {
+    // a problem that spoils the reference
+    Fatal,
+    // a problem that doesn't, such as a transcript that had to be
+    // skipped, worth a run warning
+    Warning,
+    // anything else, such as progress output
+    Info,
+}
+
+// Classifies a line of pyroe's stderr by its form rather than by
+// the words it happens to contain (which may be gene names or
+// paths): Python log records by their level, tracebacks by their
+// header, exceptions by their `SomeError: ...` line, and warnings
+// by the `<file>:<line>: SomeWarning: ...` form Python prints.
+fn classify_pyroe_line(line: &str) -> PyroeLine {
+    let line = line.trim();
+    if line.starts_with("Traceback (most recent call last)") {
+        return PyroeLine::Fatal;
+    }
+    // a log record such as `WARNING:root:...` or `ERROR - ...`
+    let level = line
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .next()
+        .unwrap_or_default();
+    match level {
+        "ERROR" | "CRITICAL" | "FATAL" => return PyroeLine::Fatal,
+        "WARNING" | "WARN" => return PyroeLine::Warning,
+        _ => {}
+    }
+    let is_name = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    };
+    // an exception, e.g. `ValueError: ...` or `KeyError`
+    let head = line.split(": ").next().unwrap_or_default();
+    if is_name(head) && (head.ends_with("Error") || head.ends_with("Exception")) {
+        return PyroeLine::Fatal;
+    }
+    // a warning, e.g. `/x/pyroe.py:12: UserWarning: ...`
+    if line
+        .split(": ")
+        .skip(1)
+        .any(|seg| is_name(seg) && seg.ends_with("Warning"))
+    {
+        return PyroeLine::Warning;
+    }
+    PyroeLine::Info
+}
+
+// The seed of the read downsampling, fixed so that
+// downsampled runs are reproducible.
+const DOWNSAMPLE_SEED: u64 = 0x5eed;
+
+fn parse_fraction(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if f > 0.0 && f <= 1.0 => Ok(f),
+        _ => Err(format!("{} is not a fraction in (0, 1]", s)),
+    }
+}
+
+// Parses a --threads value, with `auto` giving 0, which
+// `clamp_threads` resolves to all the threads that can be used.
+fn parse_threads(s: &str) -> std::result::Result<u32, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(0);
+    }
+    s.parse::<u32>()
+        .map_err(|_| format!("{} is neither a number of threads nor `auto`", s))
+}
+
+// Settings shared by every sample quantified
+// by a single invocation of `quant`.
+struct QuantSettings {
+    salmon: ProgInfo,
+    alevin_fry: ProgInfo,
+    index: PathBuf,
+    // the label the index was built with, if any
+    reference_name: Option<String>,
+    chem: Chemistry,
+    // the read geometry of a feature-barcode index, replacing
+    // that of the chemistry
+    feature_read_geometry: Option<String>,
+    filter_meth: CellFilterMethod,
+    // the most barcodes the filtering may permit (0 for no limit),
+    // and whether exceeding it is only a warning
+    max_barcodes: u64,
+    warn_max_barcodes: bool,
+    // more than one resolution gives one quant output per resolution
+    resolutions: Vec<String>,
+    output_format: String,
+    velocity: bool,
+    output_barcodes: bool,
+    gene_allowlist: Option<(PathBuf, HashSet<String>)>,
+    knee_plot: bool,
+    summary_stat: bool,
+    expected_ori: String,
+    // salmon's library type, matching an orientation that was chosen
+    salmon_libtype: &'static str,
+    mapping_output_format: String,
+    sketch_opts: SketchOpts,
+    dump_features: bool,
+    collate_opts: CollateOpts,
+    parsimony_opts: ParsimonyOpts,
+    t2g_map: PathBuf,
+    stage_opts: StageOpts,
+    version_pins: Vec<VersionPin>,
+    summary_tsv: Option<PathBuf>,
+    manifest_checksums: bool,
+    note: Option<String>,
+    profile: Option<String>,
+    explain: bool,
+    downsample: Option<f64>,
+    // an existing mapping output to start from instead of mapping
+    from_rad: Option<PathBuf>,
+    // warnings raised while resolving these settings
+    warnings: RunWarnings,
+}
+
+impl QuantSettings {
+    // The read layout the samples are mapped with.
+    fn geometry(&self) -> ChemistryGeometry {
+        let g = self.chem.geometry(&self.expected_ori);
+        match self.feature_read_geometry {
+            Some(ref r) => g.with_read_geometry(r),
+            None => g,
+        }
+    }
+}
+
+// Runs the map, generate-permit-list, collate and quant stages
+// for one sample, writing its results to `output`, and returns
+// the rendered stage commands. If `emit_only` is set, the commands
+// are rendered without being run.
+fn quant_sample(
+    qs: &QuantSettings,
+    sample: &SampleReads,
+    output: &Path,
+    threads: u32,
+    emit_only: bool,
+    events: &EventLog,
+) -> Result<Vec<String>> {
+    let mut warnings = qs.warnings.clone();
+
+    // hold the lock on the output directory until this sample
+    // completes, from before anything is written to it.
+    let _output_lock = if emit_only {
+        None
+    } else {
+        ensure_output_writable(output)?;
+        Some(OutputLock::acquire(output)?)
+    };
+
+    // map a downsampled copy of the reads instead, if requested
+    let ds_sample;
+    let sample = match qs.downsample {
+        Some(fraction) if !emit_only => {
+            ds_sample = downsample_reads(
+                sample,
+                fraction,
+                DOWNSAMPLE_SEED,
+                &output.join("downsampled_reads"),
+            )?;
+            warnings.push(format!(
+                "only about {} of the read pairs were quantified (--downsample); \
+                these results are approximate",
+                fraction
+            ));
+            &ds_sample
+        }
+        _ => sample,
+    };
+
+    let mut salmon_quant_cmd = std::process::Command::new(&qs.salmon.exe_path);
+
+    // set the input index and library type
+    salmon_quant_cmd
+        .arg("alevin")
+        .arg("--index")
+        .arg(&qs.index)
+        .arg("-l")
+        .arg(qs.salmon_libtype);
+
+    // location of the reads, as the comma-separated lists salmon
+    // expects (`check_read_paths` rules out commas in the paths)
+    salmon_quant_cmd
+        .arg("-1")
+        .arg(join_read_paths(&sample.reads1))
+        .arg("-2")
+        .arg(join_read_paths(&sample.reads2));
+
+    // location of outptu directory, number of threads
+    let map_output = match &qs.from_rad {
+        Some(rad) => rad.clone(),
+        None => output.join("af_map"),
+    };
+    salmon_quant_cmd
+        .arg("--threads")
+        .arg(format!("{}", threads))
+        .arg("-o")
+        .arg(&map_output);
+    salmon_quant_cmd.args(mapping_output_args(&qs.mapping_output_format)?);
+    add_sketch_args(
+        &qs.sketch_opts,
+        &qs.salmon.version,
+        &mut salmon_quant_cmd,
+        &mut warnings,
+    )?;
+
+    if qs.dump_features {
+        salmon_quant_cmd.arg("--dumpFeatures");
+    }
+
+    // setting the technology / chemistry
+    salmon_quant_cmd.args(qs.geometry().salmon_args);
+
+    // alevin-fry generate permit list
+    let mut alevin_gpl_cmd = std::process::Command::new(&qs.alevin_fry.exe_path);
+
+    alevin_gpl_cmd.arg("generate-permit-list");
+    alevin_gpl_cmd.arg("-i").arg(&map_output);
+    alevin_gpl_cmd.arg("-d").arg(&qs.expected_ori);
+
+    // add the filter mode
+    add_to_args(&qs.filter_meth, &mut alevin_gpl_cmd);
+
+    let gpl_output = output.join("af_quant");
+    alevin_gpl_cmd.arg("-o").arg(&gpl_output);
+
+    //
+    // collate
+    //
+    let mut alevin_collate_cmd = std::process::Command::new(&qs.alevin_fry.exe_path);
+
+    alevin_collate_cmd.arg("collate");
+    alevin_collate_cmd.arg("-i").arg(&gpl_output);
+    alevin_collate_cmd.arg("-r").arg(&map_output);
+    alevin_collate_cmd.arg("-t").arg(format!("{}", threads));
+    add_collate_args(
+        &qs.collate_opts,
+        &qs.alevin_fry.version,
+        &mut alevin_collate_cmd,
+        &mut warnings,
+    )?;
+
+    //
+    // quant
+    //
+    // a single resolution is quantified in place, while several
+    // each get their own copy of the quant output.
+    let mut alevin_quant_cmds = Vec::new();
+    let mut quant_outputs = Vec::new();
+    let mut summary_stat = false;
+    for res in &qs.resolutions {
+        let quant_output = if qs.resolutions.len() == 1 {
+            gpl_output.clone()
+        } else {
+            output.join(format!("af_quant_{}", res))
+        };
+        let mut alevin_quant_cmd = std::process::Command::new(&qs.alevin_fry.exe_path);
+
+        alevin_quant_cmd
+            .arg("quant")
+            .arg("-i")
+            .arg(&gpl_output)
+            .arg("-o")
+            .arg(&quant_output);
+        alevin_quant_cmd.arg("-t").arg(format!("{}", threads));
+        alevin_quant_cmd.arg("-m").arg(&qs.t2g_map);
+        alevin_quant_cmd.arg("-r").arg(res);
+        alevin_quant_cmd.args(output_format_args(
+            &qs.output_format,
+            &qs.alevin_fry.version,
+        )?);
+        add_parsimony_args(
+            &qs.parsimony_opts,
+            res,
+            &qs.alevin_fry.version,
+            &mut alevin_quant_cmd,
+            &mut warnings,
+        )?;
+        if qs.summary_stat {
+            summary_stat =
+                add_summary_stat_arg(&qs.alevin_fry.version, &mut alevin_quant_cmd, &mut warnings)?;
+        }
+        alevin_quant_cmds.push((res, alevin_quant_cmd));
+        quant_outputs.push(quant_output);
+    }
+
+    let mut lines = Vec::new();
+    if qs.from_rad.is_none() {
+        lines.push(command_to_shell(&salmon_quant_cmd));
+    }
+    lines.extend([&alevin_gpl_cmd, &alevin_collate_cmd].map(command_to_shell));
+    lines.extend(alevin_quant_cmds.iter().map(|(_, c)| command_to_shell(c)));
+    if emit_only {
+        return Ok(lines);
+    }
+
+    // every stage is rerun, so drop any stale markers
+    clear_stage_markers(output)?;
+
+    let stage_opts = &qs.stage_opts;
+    let mut resources = ResourceLog::default();
+
+    let map_duration = if qs.from_rad.is_some() {
+        info!(
+            "skipping the mapping; using the RAD output in {}",
+            map_output.display()
+        );
+        Duration::ZERO
+    } else {
+        info!("cmd : {:?}", salmon_quant_cmd);
+        if qs.explain {
+            explain_stage("map", None);
+        }
+        events.stage_started("map", &salmon_quant_cmd);
+        let map_start = Instant::now();
+        let map_proc_out = run_stage(&mut salmon_quant_cmd, stage_opts)
+            .context("failed to execute salmon alevin [mapping phase]")?;
+        let map_duration = map_start.elapsed();
+        events.stage_finished("map", &map_proc_out.status, map_duration);
+        resources.record("map", map_duration, &map_proc_out);
+
+        if !map_proc_out.status.success() {
+            bail!(map_proc_out.failure_message("mapping"));
+        }
+        record_map_identity(&map_output, &map_output)?;
+        mark_stage_done(output, "map")?;
+        map_duration
+    };
+
+    // the unstranded mapping shows which orientation
+    // generate-permit-list should expect
+    let detected_ori = if qs.expected_ori == "auto" {
+        let d = detect_expected_ori(&map_output)?;
+        info!(
+            "{:.1}% of {} oriented mappings were forward, so using --expected-ori {}",
+            100.0 * d.fw_fraction,
+            d.num_reads,
+            d.ori
+        );
+        alevin_gpl_cmd = with_arg_value(&alevin_gpl_cmd, "-d", &d.ori);
+        // the permit-list command follows the mapping one, if any
+        let gpl_line = usize::from(qs.from_rad.is_none());
+        lines[gpl_line] = command_to_shell(&alevin_gpl_cmd);
+        Some(d)
+    } else {
+        None
+    };
+
+    let mut qc_files = Vec::new();
+    if qs.dump_features {
+        let features = map_output.join("alevin").join("featureDump.txt");
+        if features.is_file() {
+            let qc_dir = output.join("qc");
+            std::fs::create_dir_all(&qc_dir)
+                .with_context(|| format!("could not create {}", qc_dir.display()))?;
+            let dest = qc_dir.join("featureDump.txt");
+            std::fs::copy(&features, &dest)
+                .with_context(|| format!("could not copy {}", features.display()))?;
+            qc_files.push(dest);
+        } else {
+            warnings.push(format!(
+                "--dump-features was given but salmon wrote no {}",
+                features.display()
+            ));
+        }
+    }
+
+    info!("cmd : {:?}", alevin_gpl_cmd);
+    if qs.explain {
+        explain_stage(
+            "permit",
+            Some(format!("This run is {}.", qs.filter_meth.describe())),
+        );
+    }
+    events.stage_started("permit", &alevin_gpl_cmd);
+    let gpl_start = Instant::now();
+    let gpl_proc_out = run_stage(&mut alevin_gpl_cmd, stage_opts)
+        .context("could not execute [generate permit list]")?;
+    let gpl_duration = gpl_start.elapsed();
+    events.stage_finished("permit", &gpl_proc_out.status, gpl_duration);
+    resources.record("permit", gpl_duration, &gpl_proc_out);
+
+    if !gpl_proc_out.status.success() {
+        bail!(gpl_proc_out.failure_message("generate-permit-list"));
+    }
+    record_map_identity(&gpl_output, &map_output)?;
+    mark_stage_done(output, "permit")?;
+
+    // a runaway permit list makes collate and quant crawl
+    // through what are mostly not cells at all
+    if qs.max_barcodes > 0 {
+        match permitted_barcode_count(&gpl_output) {
+            Ok(n) if n > qs.max_barcodes => {
+                let msg = format!(
+                    "generate-permit-list permitted {} barcodes, more than --max-barcodes {}; \
+                    the cell filtering method is likely unsuited to this sample",
+                    n, qs.max_barcodes
+                );
+                if !qs.warn_max_barcodes {
+                    bail!(
+                        "{}. Choose another filtering method, raise --max-barcodes, \
+                        or pass --warn-max-barcodes to continue anyway",
+                        msg
+                    );
+                }
+                warnings.push(msg);
+            }
+            Ok(n) => info!("generate-permit-list permitted {} barcodes", n),
+            Err(e) => warnings.push(format!(
+                "could not count the permitted barcodes, so --max-barcodes was not checked : {:#}",
+                e
+            )),
+        }
+    }
+
+    check_map_identity(&gpl_output, &map_output)?;
+    info!("cmd : {:?}", alevin_collate_cmd);
+    if qs.explain {
+        explain_stage("collate", None);
+    }
+    events.stage_started("collate", &alevin_collate_cmd);
+    let collate_start = Instant::now();
+    let collate_proc_out =
+        run_stage(&mut alevin_collate_cmd, stage_opts).context("could not execute [collate]")?;
+    let collate_duration = collate_start.elapsed();
+    events.stage_finished("collate", &collate_proc_out.status, collate_duration);
+    resources.record("collate", collate_duration, &collate_proc_out);
+
+    if !collate_proc_out.status.success() {
+        bail!(collate_proc_out.failure_message("collate"));
+    }
+    mark_stage_done(output, "collate")?;
+
+    let mut quant_duration = Duration::ZERO;
+    for (res, mut alevin_quant_cmd) in alevin_quant_cmds {
+        let stage = if qs.resolutions.len() == 1 {
+            String::from("quant")
+        } else {
+            format!("quant_{}", res)
+        };
+        info!("cmd : {:?}", alevin_quant_cmd);
+        if qs.explain {
+            explain_stage("quant", resolution_explanation(res));
+        }
+        events.stage_started(&stage, &alevin_quant_cmd);
+        let quant_start = Instant::now();
+        let quant_proc_out =
+            run_stage(&mut alevin_quant_cmd, stage_opts).context("could not execute [quant]")?;
+        let duration = quant_start.elapsed();
+        events.stage_finished(&stage, &quant_proc_out.status, duration);
+        resources.record(&stage, duration, &quant_proc_out);
+        quant_duration += duration;
+
+        if !quant_proc_out.status.success() {
+            bail!(quant_proc_out.failure_message(&format!("quant with resolution {}", res)));
+        }
+    }
+    mark_stage_done(output, "quant")?;
+
+    // report the size of each count matrix as a quick sanity check
+    let mut matrix_shapes = serde_json::Map::new();
+    for (res, quant_output) in qs.resolutions.iter().zip(&quant_outputs) {
+        match matrix_shape(quant_output) {
+            Ok(shape) => {
+                let mut msg = format!("Quantified {}", shape.describe());
+                if qs.resolutions.len() > 1 {
+                    msg.push_str(&format!(" with resolution {}", res));
+                }
+                // name the sample when several are being quantified
+                if let Some(name) = events.sample() {
+                    msg = format!("[{}] {}", name, msg);
+                }
+                // stdout carries only the JSON events in that mode
+                if events.is_enabled() {
+                    events.emit(
+                        "matrix_shape",
+                        "quant",
+                        json!({ "resolution" : res, "shape" : shape }),
+                    );
+                } else {
+                    println!("{}", msg);
+                }
+                matrix_shapes.insert(res.clone(), json!(shape));
+            }
+            Err(e) => warnings.push(format!(
+                "could not read the shape of the count matrix in {} : {:#}",
+                quant_output.display(),
                 e
+            )),
+        }
+    }
+
+    let mut velocity_files = Vec::new();
+    if qs.velocity {
+        for (res, quant_output) in qs.resolutions.iter().zip(&quant_outputs) {
+            let vel_dir = if qs.resolutions.len() == 1 {
+                output.join("velocity")
+            } else {
+                output.join(format!("velocity_{}", res))
+            };
+            let files = split_usa_matrix(quant_output, &vel_dir)
+                .context("could not split the count matrix for --velocity")?;
+            info!(
+                "wrote spliced, unspliced and ambiguous matrices to {}",
+                vel_dir.display()
+            );
+            velocity_files.extend(files);
+        }
+    }
+
+    let mut allowlist_info = None;
+    if let Some((ref p, ref genes)) = qs.gene_allowlist {
+        // every resolution has the same genes
+        let mut num_genes = 0;
+        let mut missing = Vec::new();
+        for (res, quant_output) in qs.resolutions.iter().zip(&quant_outputs) {
+            let out_dir = if qs.resolutions.len() == 1 {
+                output.join("allowlisted")
+            } else {
+                output.join(format!("allowlisted_{}", res))
+            };
+            let summary = filter_matrix_genes(quant_output, genes, &out_dir)
+                .context("could not restrict the count matrix to --gene-allowlist")?;
+            info!(
+                "wrote the count matrix of the {} allowlisted genes to {}",
+                summary.num_genes,
+                out_dir.display()
+            );
+            num_genes = summary.num_genes;
+            missing = summary.missing;
+        }
+        if !missing.is_empty() {
+            warnings.push(format!(
+                "{} of the {} genes in {} are not in the count matrix (e.g. {})",
+                missing.len(),
+                genes.len(),
+                p.display(),
+                missing[..missing.len().min(5)].join(", ")
             ));
         }
+        allowlist_info = Some(json!({ "file" : p, "num_genes" : num_genes }));
+    }
+
+    if qs.knee_plot {
+        let counts = barcode_read_counts(&gpl_output)?;
+        let qc_dir = output.join("qc");
+        std::fs::create_dir_all(&qc_dir)
+            .with_context(|| format!("could not create {}", qc_dir.display()))?;
+        let dest = qc_dir.join("knee.png");
+        write_knee_plot(&counts, &dest)?;
+        info!("wrote the barcode rank plot to {}", dest.display());
+        qc_files.push(dest);
+    }
+
+    let mut summary_stats = Vec::new();
+    if summary_stat {
+        let qc_dir = output.join("qc");
+        std::fs::create_dir_all(&qc_dir)
+            .with_context(|| format!("could not create {}", qc_dir.display()))?;
+        for (res, quant_output) in qs.resolutions.iter().zip(&quant_outputs) {
+            let stats = quant_output.join("featureDump.txt");
+            if !stats.is_file() {
+                warnings.push(format!(
+                    "--summary-stat was given but alevin-fry wrote no {}",
+                    stats.display()
+                ));
+                continue;
+            }
+            let dest = if qs.resolutions.len() == 1 {
+                qc_dir.join("quant_featureDump.txt")
+            } else {
+                qc_dir.join(format!("quant_featureDump_{}.txt", res))
+            };
+            std::fs::copy(&stats, &dest)
+                .with_context(|| format!("could not copy {}", stats.display()))?;
+            let summary = summarize_feature_dump(&dest)?;
+            info!(
+                "the {} quantified cells{} have a median of {}",
+                summary.num_cells,
+                if qs.resolutions.len() > 1 {
+                    format!(" of resolution {}", res)
+                } else {
+                    String::new()
+                },
+                summary
+                    .medians
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", v, k))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            summary_stats.push(json!({ "resolution" : res, "file" : dest, "summary" : summary }));
+            qc_files.push(dest);
+        }
+    }
+
+    // every resolution quantifies the same permitted cells
+    let barcodes_file = if qs.output_barcodes {
+        let dest = output.join("permitted_barcodes.txt");
+        let n = write_barcodes(&quant_outputs[0], &dest)?;
+        info!("wrote {} permitted barcodes to {}", n, dest.display());
+        Some(dest)
+    } else {
+        None
+    };
+
+    resources.log_cpu_summary(threads);
+
+    let af_quant_info_file = output.join("simpleaf_quant_log.json");
+    let af_quant_info = json!({
+        "sample" : sample.name,
+        "simpleaf_version" : env!("CARGO_PKG_VERSION"),
+        "reference_name" : qs.reference_name,
+        "note" : qs.note,
+        "profile" : qs.profile,
+        "downsample" : qs.downsample.map(|f| json!({ "fraction" : f, "seed" : DOWNSAMPLE_SEED })),
+        "version_info" : {
+            "salmon" : qs.salmon.version,
+            "alevin_fry" : qs.alevin_fry.version,
+        },
+        "pinned_versions" : qs.version_pins,
+        "chemistry" : qs.geometry(),
+        "resolutions" : qs.resolutions,
+        "output_format" : qs.output_format,
+        "expected_ori" : qs.expected_ori,
+        "salmon_libtype" : qs.salmon_libtype,
+        "detected_ori" : detected_ori,
+        "time_info" : {
+        "map_time" : map_duration,
+        "gpl_time" : gpl_duration,
+        "collate_time" : collate_duration,
+        "quant_time" : quant_duration
+        },
+        "from_rad" : qs.from_rad,
+        "mapping_output_format" : qs.mapping_output_format,
+        "mapping_args" : match qs.from_rad {
+            Some(_) => None,
+            None => Some(salmon_quant_cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect::<Vec<String>>()),
+        },
+        "collate_args" : alevin_collate_cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect::<Vec<String>>(),
+        "qc_files" : qc_files,
+        "summary_stats" : summary_stats,
+        "matrix_shapes" : matrix_shapes,
+        "velocity_files" : velocity_files,
+        "barcodes_file" : barcodes_file,
+        "gene_allowlist" : allowlist_info,
+        "resources" : resources,
+        "warnings" : warnings
+    });
+
+    std::fs::write(
+        &af_quant_info_file,
+        serde_json::to_string_pretty(&af_quant_info).unwrap(),
+    )
+    .with_context(|| format!("could not write {}", af_quant_info_file.display()))?;
+
+    let n = write_run_manifest(output, qs.manifest_checksums)?;
+    info!(
+        "listed the {} files of the run in {}",
+        n,
+        output.join(RUN_MANIFEST_FILE).display()
+    );
+
+    if let Some(ref tsv) = qs.summary_tsv {
+        let read_json = |p: PathBuf| -> serde_json::Value {
+            std::fs::read_to_string(p)
+                .ok()
+                .and_then(|s| serde_json::from_str(&s).ok())
+                .unwrap_or_default()
+        };
+        // missing values are left as empty cells
+        let cell = |v: &serde_json::Value| match v {
+            serde_json::Value::Null => String::new(),
+            v => v.to_string(),
+        };
+        let quant_info = read_json(quant_outputs[0].join("quant.json"));
+        let map_info = read_json(map_output.join("aux_info").join("meta_info.json"));
+        let date = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let chemistry = qs.chem.name();
+        append_tsv_row(
+            tsv,
+            &[
+                "sample",
+                "date",
+                "chemistry",
+                "num_cells",
+                "mapping_rate",
+                "resolution",
+                "simpleaf_version",
+                "salmon_version",
+                "alevin_fry_version",
+            ],
+            &[
+                sample.name.clone(),
+                date,
+                chemistry,
+                cell(&quant_info["num_quantified_cells"]),
+                cell(&map_info["percent_mapped"]),
+                qs.resolutions.join(","),
+                String::from(env!("CARGO_PKG_VERSION")),
+                qs.salmon.version.clone(),
+                qs.alevin_fry.version.clone(),
+            ],
+        )?;
     }
+    Ok(lines)
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     const AF_HOME: &str = "ALEVIN_FRY_HOME";
-    let af_home_path = match env::var(AF_HOME) {
-        Ok(p) => PathBuf::from(p),
-        Err(e) => {
-            bail!(
-                "${} is unset {}, please set this environment variable to continue.",
-                AF_HOME,
-                e
-            );
-        }
-    };
 
-    let cli_args = Cli::parse();
+    let cli_args = Cli::parse_from(args_with_rc_defaults(env::args_os().collect())?);
+    // the flag wins over the environment, which
+    // wins over the default in the home directory
+    let af_home_path = match (&cli_args.alevin_fry_home, env::var_os(AF_HOME)) {
+        (Some(p), _) => p.clone(),
+        (None, Some(p)) => PathBuf::from(p),
+        (None, None) => match env::var_os("HOME") {
+            Some(h) => {
+                let p = PathBuf::from(h).join(".afhome");
+                std::fs::create_dir_all(&p)
+                    .with_context(|| format!("could not create {}", p.display()))?;
+                p
+            }
+            None => bail!(
+                "neither --alevin-fry-home nor ${} is set, and there is no home directory \
+                to default to; please set one of them to continue.",
+                AF_HOME
+            ),
+        },
+    };
+    let events = EventLog::new(cli_args.json_logs);
+    let explain = cli_args.explain;
+    let version_pins = match cli_args.require_versions {
+        Some(ref s) => parse_version_pins(s)?,
+        None => Vec::new(),
+    };
 
     match cli_args.command {
         Commands::SetPaths {
@@ -219,38 +2010,312 @@ fn main() -> anyhow::Result<()> {
             alevin_fry,
             pyroe,
         } => {
-            let rp = get_required_progs_from_paths(salmon, alevin_fry, pyroe)?;
+            let rp =
+                get_required_progs_from_paths(salmon, alevin_fry, pyroe, cli_args.allow_newer)?;
 
             if rp.salmon.is_none() {
                 bail!("Suitable salmon executable not found");
             }
-            if rp.alevin_fry.is_none() {
-                bail!("Suitable alevin_fry executable not found");
+            if rp.alevin_fry.is_none() {
+                bail!("Suitable alevin_fry executable not found");
+            }
+            if rp.pyroe.is_none() {
+                bail!("Suitable pyroe executable not found");
+            }
+            check_version_pins(&rp, &version_pins)?;
+
+            let simpleaf_info_file = af_home_path.join("simpleaf_info.json");
+            let simpleaf_info = json!({ "prog_info": rp });
+
+            std::fs::write(
+                &simpleaf_info_file,
+                serde_json::to_string_pretty(&simpleaf_info).unwrap(),
+            )
+            .with_context(|| format!("could not write {}", simpleaf_info_file.display()))?;
+        }
+        Commands::Setup {
+            manifest,
+            stage_timeout,
+            max_retries,
+        } => {
+            let stage_opts = StageOpts::new(stage_timeout, max_retries);
+            let f = std::fs::File::open(&manifest)
+                .with_context(|| format!("could not open {}", manifest.display()))?;
+            let mut platforms: HashMap<String, HashMap<String, ToolDownload>> =
+                serde_json::from_reader(BufReader::new(f))
+                    .with_context(|| format!("could not parse {}", manifest.display()))?;
+            let platform = platform_key();
+            let mut downloads = match platforms.remove(&platform) {
+                Some(d) => d,
+                None => bail!(
+                    "{} lists no downloads for this platform ({}); it has {}",
+                    manifest.display(),
+                    platform,
+                    platforms
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            };
+            if let Some(t) = downloads
+                .keys()
+                .find(|t| !["salmon", "alevin-fry", "pyroe"].contains(&t.as_str()))
+            {
+                bail!(
+                    "unknown tool {:?} in {}; expected salmon, alevin-fry or pyroe",
+                    t,
+                    manifest.display()
+                );
+            }
+
+            let bin_dir = af_home_path.join("bin");
+            let mut install = |tool: &str| -> Result<Option<PathBuf>> {
+                match downloads.remove(tool) {
+                    Some(td) => {
+                        let exe = install_tool(tool, &td, &bin_dir, &stage_opts)?;
+                        println!("installed {} at {}", tool, exe.display());
+                        Ok(Some(exe))
+                    }
+                    None => Ok(None),
+                }
+            };
+            let salmon = install("salmon")?;
+            let alevin_fry = install("alevin-fry")?;
+            let pyroe = install("pyroe")?;
+
+            // record the paths just as set-paths would
+            let rp =
+                get_required_progs_from_paths(salmon, alevin_fry, pyroe, cli_args.allow_newer)?;
+            for (name, p) in [
+                ("salmon", &rp.salmon),
+                ("alevin-fry", &rp.alevin_fry),
+                ("pyroe", &rp.pyroe),
+            ] {
+                if p.is_none() {
+                    bail!("Suitable {} executable not found", name);
+                }
+            }
+            check_version_pins(&rp, &version_pins)?;
+            let simpleaf_info_file = af_home_path.join("simpleaf_info.json");
+            let simpleaf_info = json!({ "prog_info": rp });
+            std::fs::write(
+                &simpleaf_info_file,
+                serde_json::to_string_pretty(&simpleaf_info).unwrap(),
+            )
+            .with_context(|| format!("could not write {}", simpleaf_info_file.display()))?;
+        }
+        Commands::Diff {
+            a,
+            b,
+            include_timing,
+        } => {
+            let (pa, pb) = (resolve_provenance(&a)?, resolve_provenance(&b)?);
+            let (diffs, same) = diff_provenance(
+                &read_provenance(&pa)?,
+                &read_provenance(&pb)?,
+                include_timing,
+            );
+            println!("a: {}\nb: {}", pa.display(), pb.display());
+            let show = |v: &Option<String>| v.clone().unwrap_or_else(|| String::from("(absent)"));
+            for d in &diffs {
+                println!("{}\n  a: {}\n  b: {}", d.field, show(&d.a), show(&d.b));
+            }
+            println!("{} fields differ, {} are identical", diffs.len(), same);
+        }
+        Commands::Merge { inputs, output } => {
+            ensure_output_writable(&output)?;
+            let summary = merge_quants(&inputs, &output)?;
+            println!(
+                "merged {} inputs into {} cells and {} genes ({} barcodes were present in more than one input)",
+                inputs.len(),
+                summary.num_cells,
+                summary.num_genes,
+                summary.num_shared_cells
+            );
+        }
+        Commands::FetchPermitList {
+            chemistry,
+            stage_timeout,
+            max_retries,
+        } => {
+            let chem = Chemistry::from_arg(&chemistry)?;
+            let stage_opts = StageOpts::new(stage_timeout, max_retries);
+            let (p, how) = match get_permit_if_absent(&chem, &af_home_path, &stage_opts)? {
+                PermitListResult::DownloadSuccessful(p) => (p, "downloaded"),
+                PermitListResult::AlreadyPresent(p) => (p, "already present"),
+                PermitListResult::UnregisteredChemistry => {
+                    bail!("chemistry {} has no registered permit list", chemistry)
+                }
+            };
+            let contents = std::fs::read_to_string(&p)
+                .with_context(|| format!("could not read permit list {}", p.display()))?;
+            let num_barcodes = contents.lines().filter(|l| !l.trim().is_empty()).count();
+            println!(
+                "permit list for {} {} at {} ({} barcodes)",
+                chemistry,
+                how,
+                p.display(),
+                num_barcodes
+            );
+        }
+        Commands::EmitEnv {
+            output,
+            from,
+            format,
+        } => {
+            // of a run's provenance files, use the first that
+            // records the tool versions
+            let candidates = match from {
+                Some(ref p) if p.is_dir() && has_provenance(p) => PROVENANCE_FILES
+                    .iter()
+                    .rev()
+                    .map(|f| p.join(f))
+                    .filter(|f| f.is_file())
+                    .collect::<Vec<PathBuf>>(),
+                Some(ref p) => vec![resolve_provenance(p)?],
+                None => vec![af_home_path.join("simpleaf_info.json")],
+            };
+            let mut found = None;
+            for c in &candidates {
+                let v = read_provenance(c).with_context(|| {
+                    format!(
+                        "could not read the tool versions from {}; run set-paths first \
+                        or pass --from",
+                        c.display()
+                    )
+                })?;
+                let mut versions = provenance_tool_versions(&v);
+                if versions.iter().any(|(p, _)| p != "simpleaf") {
+                    // set-paths configured the tools for this simpleaf
+                    if from.is_none() {
+                        versions.insert(
+                            0,
+                            (
+                                String::from("simpleaf"),
+                                String::from(env!("CARGO_PKG_VERSION")),
+                            ),
+                        );
+                    }
+                    found = Some((c, versions));
+                    break;
+                }
+            }
+            let Some((source, versions)) = found else {
+                bail!("found no recorded tool versions in {:?}", candidates);
+            };
+            std::fs::write(&output, environment_spec(&versions, &format))
+                .with_context(|| format!("could not write {}", output.display()))?;
+            info!(
+                "wrote the versions recorded in {} to {}",
+                source.display(),
+                output.display()
+            );
+        }
+        Commands::ListResolutions => {
+            for res in RESOLUTIONS {
+                if let Some((how, when)) = resolution_description(res) {
+                    println!(
+                        "{}\n  The {} resolution {}.\n  When to use: {}.\n",
+                        res, res, how, when
+                    );
+                }
+            }
+        }
+        Commands::PermitListPath { chemistry } => {
+            let chem = Chemistry::from_arg(&chemistry)?;
+            match get_permit_if_absent(&chem, &af_home_path, &StageOpts::default())? {
+                PermitListResult::DownloadSuccessful(p) | PermitListResult::AlreadyPresent(p) => {
+                    println!("{}", p.display());
+                }
+                PermitListResult::UnregisteredChemistry => {
+                    bail!("chemistry {} has no registered permit list", chemistry)
+                }
+            }
+        }
+        Commands::Check { dir } => {
+            if let Some(problem) = run_completion_problem(&dir) {
+                println!("{}", problem);
+                std::process::exit(1);
+            }
+        }
+        Commands::ValidateT2g { t2g } => {
+            let v = validate_t2g(&t2g)?;
+            if v.rows == 0 {
+                bail!("{} is empty", t2g.display());
+            }
+            if !v.problems.is_empty() {
+                // a badly broken file can have a problem on every line
+                const SHOWN: usize = 50;
+                for p in v.problems.iter().take(SHOWN) {
+                    println!("{}", p);
+                }
+                if v.problems.len() > SHOWN {
+                    println!("... and {} more", v.problems.len() - SHOWN);
+                }
+                bail!(
+                    "{} has {} problem(s) in its {} lines",
+                    t2g.display(),
+                    v.problems.len(),
+                    v.rows
+                );
+            }
+            println!(
+                "{} is a valid {}-column t2g file with {} transcripts",
+                t2g.display(),
+                v.columns.unwrap_or(0),
+                v.rows
+            );
+        }
+        Commands::Clean { dirs, level, force } => {
+            let mut total = 0u64;
+            for d in &dirs {
+                let reclaimed = clean_run_dir(d, &level, force)?;
+                println!("reclaimed {} bytes from {}", reclaimed, d.display());
+                total += reclaimed;
             }
-            if rp.pyroe.is_none() {
-                bail!("Suitable pyroe executable not found");
+            if dirs.len() > 1 {
+                println!("reclaimed {} bytes in total", total);
             }
-
-            let simpleaf_info_file = af_home_path.join("simpleaf_info.json");
-            let simpleaf_info = json!({ "prog_info": rp });
-
-            std::fs::write(
-                &simpleaf_info_file,
-                serde_json::to_string_pretty(&simpleaf_info).unwrap(),
-            )
-            .with_context(|| format!("could not write {}", simpleaf_info_file.display()))?;
         }
         Commands::Index {
             fasta,
             gtf,
             rlen,
+            flank_trim_length,
+            gtf_gene_id_attr,
+            gtf_transcript_id_attr,
+            gtf_exon_feature,
+            transcripts,
+            t2g_map,
+            derive_t2g,
+            feature_csv,
             output,
             spliced,
             unspliced,
+            extra_sequences,
+            decoy,
+            decoy_names,
             dedup,
             sparse,
             mut threads,
+            stage_timeout,
+            max_retries,
+            emit_script,
+            reference_name,
+            note,
+            validate_only,
+            estimate,
+            keep_going,
+            strict,
+            overwrite,
         } => {
+            // fail fast if we won't be able to write our output;
+            // --validate-only reports this among its checks instead
+            if !(validate_only || estimate) {
+                ensure_output_writable(&output)?;
+            }
+            let stage_opts = StageOpts::new(stage_timeout, max_retries);
             // Open the file in read-only mode with buffer.
             let af_info_p = af_home_path.join("simpleaf_info.json");
             let simpleaf_info_file = std::fs::File::open(&af_info_p).with_context({
@@ -264,25 +2329,402 @@ fn main() -> anyhow::Result<()> {
             let v: serde_json::Value = serde_json::from_reader(simpleaf_info_reader)?;
             let rp: ReqProgs = serde_json::from_value(v["prog_info"].clone())?;
 
-            run_fun!(mkdir -p $output)?;
-            let ref_file = format!("splici_fl{}.fa", rlen - 5);
+            if estimate {
+                let est_threads = clamp_threads(threads, &mut RunWarnings::default());
+                estimate_index(
+                    fasta.as_ref(),
+                    // a feature reference is indexed as it is
+                    transcripts.as_ref().or(feature_csv.as_ref()),
+                    decoy.as_ref(),
+                    sparse,
+                    est_threads,
+                )?
+                .print();
+                if !validate_only {
+                    return Ok(());
+                }
+            }
+            if validate_only {
+                // (passed, description) for each check, leaving no
+                // output directory behind that wasn't there before
+                let created = !output.exists();
+                let mut checks = vec![match ensure_output_writable(&output) {
+                    Ok(()) => (true, format!("{} is writable", output.display())),
+                    Err(e) => (false, format!("{:#}", e)),
+                }];
+                if created {
+                    let _ = std::fs::remove_dir(&output);
+                }
+
+                for (name, prog) in [("salmon", &rp.salmon), ("pyroe", &rp.pyroe)] {
+                    // pyroe is only needed to build a splici reference
+                    if name == "pyroe" && (transcripts.is_some() || feature_csv.is_some()) {
+                        continue;
+                    }
+                    checks.push(match prog {
+                        Some(p) if p.exe_path.is_file() => (
+                            true,
+                            format!("{} {} found at {}", name, p.version, p.exe_path.display()),
+                        ),
+                        Some(p) => (
+                            false,
+                            format!(
+                                "{} is no longer at {}; rerun set-paths",
+                                name,
+                                p.exe_path.display()
+                            ),
+                        ),
+                        None => (false, format!("{} is not configured; run set-paths", name)),
+                    });
+                }
+                if !version_pins.is_empty() {
+                    checks.push(match check_version_pins(&rp, &version_pins) {
+                        Ok(()) => (true, String::from("tool versions match their pins")),
+                        Err(e) => (false, format!("{:#}", e)),
+                    });
+                }
+
+                let inputs = [
+                    ("FASTA", &fasta),
+                    ("GTF", &gtf),
+                    ("extra spliced FASTA", &spliced),
+                    ("extra unspliced FASTA", &unspliced),
+                    ("transcriptome FASTA", &transcripts),
+                    ("t2g map", &t2g_map),
+                    ("feature reference CSV", &feature_csv),
+                    ("decoy FASTA", &decoy),
+                    ("decoy names", &decoy_names),
+                ];
+                for (desc, p) in inputs {
+                    if let Some(p) = p {
+                        checks.push((
+                            p.is_file(),
+                            format!(
+                                "{} {} {}",
+                                desc,
+                                p.display(),
+                                if p.is_file() {
+                                    "exists"
+                                } else {
+                                    "does not exist"
+                                }
+                            ),
+                        ));
+                    }
+                }
+
+                if let (Some(f), Some(g)) = (&fasta, &gtf) {
+                    if f.is_file() && g.is_file() {
+                        checks.push(match seq_name_concordance(f, g) {
+                            Ok(c) => (
+                                !c.is_poor(),
+                                format!(
+                                    "{} of the {} GTF sequence names are present in the FASTA",
+                                    c.matching,
+                                    c.matching + c.gtf_only.len()
+                                ),
+                            ),
+                            Err(e) => (false, format!("{:#}", e)),
+                        });
+                    }
+                }
+                if let Some(rlen) = rlen {
+                    checks.push((
+                        flank_trim_length < rlen,
+                        format!(
+                            "the flank trim of {} must be shorter than the read length {}",
+                            flank_trim_length, rlen
+                        ),
+                    ));
+                }
+
+                // the splici reference and index take several
+                // times the size of the genome
+                if let Some(avail) = available_space(&output) {
+                    let needed = estimate_index(
+                        fasta.as_ref(),
+                        transcripts.as_ref().or(feature_csv.as_ref()),
+                        decoy.as_ref(),
+                        sparse,
+                        1,
+                    );
+                    checks.push(match needed {
+                        Ok(e) => (
+                            avail >= e.disk,
+                            format!(
+                                "{} free for an estimated {} of output",
+                                format_bytes(avail),
+                                format_bytes(e.disk)
+                            ),
+                        ),
+                        Err(e) => (
+                            false,
+                            format!("could not estimate the disk use of the index : {:#}", e),
+                        ),
+                    });
+                }
+
+                let failed = checks.iter().filter(|(ok, _)| !ok).count();
+                for (ok, desc) in &checks {
+                    println!("[{}] {}", if *ok { " ok " } else { "FAIL" }, desc);
+                }
+                if failed > 0 {
+                    bail!("{} of {} preflight checks failed", failed, checks.len());
+                }
+                println!(
+                    "all {} preflight checks passed; ready to build the index",
+                    checks.len()
+                );
+                return Ok(());
+            }
+            check_version_pins(&rp, &version_pins)?;
+
+            let _group_writable = cli_args.group_writable.then(|| GroupWritable::new(&output));
+            // hold the lock on the output directory until
+            // this command completes.
+            let _output_lock = OutputLock::acquire(&output)?;
+            let mut warnings = RunWarnings::default();
+
+            // rebuilding an index is expensive, so never replace
+            // one unless asked to
+            let existing_index = output.join("index");
+            let has_index = std::fs::read_dir(&existing_index)
+                .map(|mut d| d.next().is_some())
+                .unwrap_or(false);
+            if has_index && !overwrite {
+                bail!(
+                    "{} already holds a salmon index; pass --overwrite to rebuild it",
+                    existing_index.display()
+                );
+            }
 
+            let gtf_overrides = GtfOverrides {
+                gene_id_attr: gtf_gene_id_attr,
+                transcript_id_attr: gtf_transcript_id_attr,
+                exon_feature: gtf_exon_feature,
+            };
             let outref = output.join("ref");
             run_fun!(mkdir -p $outref)?;
 
-            let t2g_file = outref.join(format!("splici_fl{}_t2g_3col.tsv", rlen - 5));
+            // gather the listed extra sequences into one spliced and
+            // one unspliced FASTA for pyroe, remembering the genes
+            // their records should be assigned to.
+            let mut extra_genes = HashMap::new();
+            let (extra_spliced, extra_unspliced) = match extra_sequences {
+                Some(ref es) => {
+                    let extras = read_extra_sequences(es)?;
+                    let mut merged = [None, None];
+                    for (i, spliced) in [true, false].into_iter().enumerate() {
+                        let files = extras
+                            .iter()
+                            .filter(|e| e.spliced == spliced)
+                            .map(|e| e.fasta.as_path())
+                            .collect::<Vec<&Path>>();
+                        if !files.is_empty() {
+                            let dest = outref.join(if spliced {
+                                "extra_spliced.fa"
+                            } else {
+                                "extra_unspliced.fa"
+                            });
+                            concat_fastas(&files, &dest)?;
+                            merged[i] = Some(dest);
+                        }
+                    }
+                    for e in &extras {
+                        if let Some(ref g) = e.gene_id {
+                            for n in fasta_seq_names(&e.fasta)? {
+                                extra_genes.insert(n, g.clone());
+                            }
+                        }
+                    }
+                    let [s, u] = merged;
+                    (s, u)
+                }
+                None => (spliced.clone(), unspliced.clone()),
+            };
+
+            // salmon wants the decoys named in a file and placed after
+            // the targets, so gather the decoy records and their names
+            // now; a full decoy is used as it is.
+            let decoys = match decoy {
+                Some(ref d) => {
+                    let names = read_decoy_names(d, decoy_names.as_deref())?;
+                    let names_file = outref.join("decoys.txt");
+                    std::fs::write(&names_file, names.join("\n") + "\n")
+                        .with_context(|| format!("could not write {}", names_file.display()))?;
+                    let decoy_seqs = if decoy_names.is_some() {
+                        let dest = outref.join("decoys.fa");
+                        let keep = names.iter().cloned().collect::<HashSet<String>>();
+                        extract_fasta_records(d, &keep, &dest)?;
+                        dest
+                    } else {
+                        d.clone()
+                    };
+                    info!("using {} decoy sequences from {}", names.len(), d.display());
+                    Some((decoy_seqs, names_file))
+                }
+                None => None,
+            };
+
+            // either build the splici reference with pyroe, or index
+            // the provided transcriptome or feature barcodes as is
+            let mut feature_ref = None;
+            let (splici_cmd, ref_seq, t2g_file) = match (&feature_csv, &transcripts) {
+                (Some(csv), _) => {
+                    let features = outref.join("features.tsv");
+                    let t2g_file = outref.join("features_t2g.tsv");
+                    let fr = write_feature_reference(csv, &features, &t2g_file)?;
+                    info!(
+                        "wrote {} feature barcodes from {} to {}",
+                        fr.num_features,
+                        csv.display(),
+                        features.display()
+                    );
+                    if fr.read_geometry.is_none() {
+                        warnings.push(format!(
+                            "{} has no pattern column, so quant will use the chemistry's own \
+                            read geometry and expect the feature barcodes at the start of read 2",
+                            csv.display()
+                        ));
+                    }
+                    feature_ref = Some(fr);
+                    (None, features, t2g_file)
+                }
+                (None, Some(txome)) => {
+                    let t2g_file = match t2g_map {
+                        Some(ref t) => t.clone(),
+                        None => {
+                            let t2g_file = outref.join("transcripts_t2g.tsv");
+                            let ntx = write_identity_t2g(txome, &t2g_file)?;
+                            info!(
+                                "wrote a one-to-one t2g for {} transcripts to {}",
+                                ntx,
+                                t2g_file.display()
+                            );
+                            t2g_file
+                        }
+                    };
+                    (None, txome.clone(), t2g_file)
+                }
+                (None, None) => {
+                    // clap guarantees these are present when
+                    // neither --transcripts nor --feature-csv is given
+                    let rlen = rlen.unwrap();
+
+                    // pyroe only understands the standard GTF attribute
+                    // keys, so translate any others to those first.
+                    let gtf = if gtf_overrides.is_empty() {
+                        gtf.clone().unwrap()
+                    } else {
+                        let normalized = outref.join("normalized.gtf");
+                        let n = normalize_gtf(gtf.as_ref().unwrap(), &normalized, &gtf_overrides)?;
+                        info!(
+                            "wrote {} GTF records with standard attribute keys to {}",
+                            n,
+                            normalized.display()
+                        );
+                        normalized
+                    };
+
+                    // a GTF and FASTA that name their sequences differently
+                    // (e.g. `1` vs `chr1`) silently yield an empty splici
+                    // reference, so check that before running pyroe.
+                    let conc = seq_name_concordance(fasta.as_ref().unwrap(), &gtf)?;
+                    info!(
+                        "{} GTF sequence names match the FASTA, {} are only in the GTF and {} only in the FASTA",
+                        conc.matching,
+                        conc.gtf_only.len(),
+                        conc.fasta_only
+                    );
+                    if conc.is_poor() {
+                        let msg = format!(
+                            "only {} of the {} sequence names used in the GTF are present in the FASTA \
+                            (missing e.g. {:?}); check that both use the same naming scheme",
+                            conc.matching,
+                            conc.matching + conc.gtf_only.len(),
+                            &conc.gtf_only[..conc.gtf_only.len().min(5)]
+                        );
+                        if strict {
+                            bail!(msg);
+                        }
+                        warnings.push(msg);
+                    }
+
+                    if flank_trim_length >= rlen {
+                        bail!(
+                            "--flank-trim-length ({}) must be smaller than the read length ({})",
+                            flank_trim_length,
+                            rlen
+                        );
+                    }
+                    let flank_len = rlen - flank_trim_length;
+                    let ref_file = format!("splici_fl{}.fa", flank_len);
+                    let t2g_file = outref.join(format!("splici_fl{}_t2g_3col.tsv", flank_len));
+
+                    let mut cmd = std::process::Command::new(&rp.pyroe.as_ref().unwrap().exe_path);
+                    // we will run the make-splici command
+                    cmd.arg("make-splici");
+
+                    // if the user wants to dedup output sequences
+                    if dedup {
+                        cmd.arg(String::from("--dedup-seqs"));
+                    }
+
+                    // extra spliced sequence
+                    if let Some(ref es) = extra_spliced {
+                        cmd.arg("--extra-spliced");
+                        cmd.arg(es);
+                    }
+
+                    // extra unspliced sequence
+                    if let Some(ref eu) = extra_unspliced {
+                        cmd.arg("--extra-unspliced");
+                        cmd.arg(eu);
+                    }
+
+                    cmd.arg("--flank-trimming-length")
+                        .arg(format!("{}", flank_trim_length));
+
+                    cmd.arg(fasta.as_ref().unwrap())
+                        .arg(&gtf)
+                        .arg(format!("{}", rlen))
+                        .arg(&outref);
+                    (Some(cmd), outref.join(ref_file), t2g_file)
+                }
+            };
+
             let info_file = output.join("index_info.json");
             let index_info = json!({
                 "command" : "index",
+                "simpleaf_version" : env!("CARGO_PKG_VERSION"),
+                "reference_name" : reference_name,
+                "note" : note,
                 "version_info" : rp,
+                "pinned_versions" : version_pins,
                 "t2g_file" : t2g_file,
+                // kept so that quant can check a t2g map against the index
+                "ref_seq" : std::env::current_dir()?.join(&ref_seq),
+                // the read geometry quant uses for the feature reads
+                "feature_barcodes" : feature_ref.as_ref().map(|f| json!({
+                    "num_features" : f.num_features,
+                    "read_geometry" : f.read_geometry,
+                })),
                 "args" : {
                     "fasta" : fasta,
                     "gtf" : gtf,
                     "rlen" : rlen,
+                    "flank_trim_length" : flank_trim_length,
+                    "gtf_overrides" : gtf_overrides,
+                    "transcripts" : transcripts,
+                    "t2g_map" : t2g_map,
+                    "derive_t2g" : derive_t2g,
+                    "feature_csv" : feature_csv,
                     "output" : output,
                     "spliced" : spliced,
                     "unspliced" : unspliced,
+                    "extra_sequences" : extra_sequences,
+                    "decoy" : decoy,
+                    "decoy_names" : decoy_names,
                     "dedup" : dedup,
                     "sparse" : sparse,
                     "threads" : threads
@@ -295,89 +2737,204 @@ fn main() -> anyhow::Result<()> {
             )
             .with_context(|| format!("could not write {}", info_file.display()))?;
 
-            let mut cmd =
-                std::process::Command::new(format!("{}", rp.pyroe.unwrap().exe_path.display()));
-            // we will run the make-splici command
-            cmd.arg("make-splici");
-
-            // if the user wants to dedup output sequences
-            if dedup {
-                cmd.arg(String::from("--dedup-seqs"));
-            }
-
-            // extra spliced sequence
-            match spliced {
-                Some(es) => {
-                    cmd.arg(String::from("--extra-spliced"));
-                    cmd.arg(format!("{}", es.display()));
-                }
-                None => {}
-            }
-
-            // extra unspliced sequence
-            match unspliced {
-                Some(eu) => {
-                    cmd.arg(String::from("--extra-unspliced"));
-                    cmd.arg(format!("{}", eu.display()));
-                }
-                None => {}
-            }
-
-            cmd.arg(fasta)
-                .arg(gtf)
-                .arg(format!("{}", rlen))
-                .arg(&outref);
-
-            let pyroe_start = Instant::now();
-            let cres = cmd.output()?;
-            let pyroe_duration = pyroe_start.elapsed();
-
-            if !cres.status.success() {
-                bail!("pyroe failed to return succesfully {:?}", cres.status);
-            }
-
-            let mut salmon_index_cmd =
-                std::process::Command::new(format!("{}", rp.salmon.unwrap().exe_path.display()));
-            let ref_seq = outref.join(ref_file);
+            let mut salmon_index_cmd = std::process::Command::new(rp.salmon.unwrap().exe_path);
 
             let output_index_dir = output.join("index");
+            // with decoys, salmon indexes the reference followed by them
+            let gentrome = outref.join("gentrome.fa");
             salmon_index_cmd
                 .arg("index")
                 .arg("-i")
                 .arg(&output_index_dir)
                 .arg("-t")
-                .arg(ref_seq);
+                .arg(if decoys.is_some() {
+                    &gentrome
+                } else {
+                    &ref_seq
+                });
+            if let Some((_, ref names_file)) = decoys {
+                salmon_index_cmd.arg("-d").arg(names_file);
+            }
 
             // if the user requested a sparse index.
             if sparse {
                 salmon_index_cmd.arg("--sparse");
             }
 
-            // if the user requested more threads than can be used
-            if let Ok(max_threads_usize) = std::thread::available_parallelism() {
-                let max_threads = max_threads_usize.get() as u32;
-                if threads > max_threads {
-                    warn!(
-                        "The maximum available parallelism is {}, but {} threads were requested.",
-                        max_threads, threads
-                    );
-                    warn!("setting number of threads to {}", max_threads);
-                    threads = max_threads;
-                }
+            // feature barcodes are short, so they need salmon's
+            // feature mode and a small k-mer size
+            if feature_ref.is_some() {
+                salmon_index_cmd.arg("--features").arg("-k").arg("7");
             }
 
+            threads = clamp_threads(threads, &mut warnings);
+
             salmon_index_cmd
                 .arg("--threads")
                 .arg(format!("{}", threads));
 
-            let index_start = Instant::now();
-            salmon_index_cmd
-                .output()
-                .expect("failed to run salmon index");
-            let index_duration = index_start.elapsed();
+            let index_t2g_path = if transcripts.is_some() || feature_csv.is_some() {
+                output_index_dir.join("t2g.tsv")
+            } else {
+                output_index_dir.join("t2g_3col.tsv")
+            };
+
+            // if requested, write the resolved commands
+            // out instead of running them.
+            if let Some(script_path) = emit_script {
+                let mut cp_cmd = std::process::Command::new("cp");
+                cp_cmd.arg(&t2g_file).arg(&index_t2g_path);
+                let mut lines = splici_cmd
+                    .iter()
+                    .map(command_to_shell)
+                    .collect::<Vec<String>>();
+                if let Some((ref decoy_seqs, _)) = decoys {
+                    // `gzip -dcf` passes uncompressed files through as is
+                    let mut cat_cmd = if is_gzipped(&ref_seq) || is_gzipped(decoy_seqs) {
+                        let mut c = std::process::Command::new("gzip");
+                        c.arg("-dcf");
+                        c
+                    } else {
+                        std::process::Command::new("cat")
+                    };
+                    cat_cmd.arg(&ref_seq).arg(decoy_seqs);
+                    lines.push(format!(
+                        "{} > {}",
+                        command_to_shell(&cat_cmd),
+                        shell_quote(gentrome.as_os_str())
+                    ));
+                }
+                lines.push(command_to_shell(&salmon_index_cmd));
+                if decoys.is_some() {
+                    let mut rm_cmd = std::process::Command::new("rm");
+                    rm_cmd.arg("-f").arg(&gentrome);
+                    lines.push(command_to_shell(&rm_cmd));
+                }
+                lines.push(command_to_shell(&cp_cmd));
+                write_command_script(&script_path, &lines)?;
+                info!("wrote the index commands to {}", script_path.display());
+                return Ok(());
+            }
+
+            // run the stages, keeping (or removing) what they have
+            // written so far if one of them fails
+            let run_stages = || -> Result<(ResourceLog, Option<Duration>, Duration)> {
+                let mut resources = ResourceLog::default();
+                let mut pyroe_duration = None;
+                if let Some(mut cmd) = splici_cmd {
+                    if explain {
+                        explain_stage("make-splici", None);
+                    }
+                    events.stage_started("make-splici", &cmd);
+                    let pyroe_start = Instant::now();
+                    let cres = run_stage(&mut cmd, &stage_opts)?;
+                    let duration = pyroe_start.elapsed();
+                    events.stage_finished("make-splici", &cres.status, duration);
+                    resources.record("make-splici", duration, &cres);
+
+                    let stderr = String::from_utf8_lossy(&cres.stderr);
+                    let mut fatal = false;
+                    for l in stderr.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                        match classify_pyroe_line(l) {
+                            PyroeLine::Fatal => fatal = true,
+                            PyroeLine::Warning => warnings.push(format!("pyroe: {}", l)),
+                            PyroeLine::Info => info!("pyroe: {}", l),
+                        }
+                    }
+
+                    // don't go on to build an index over a reference that
+                    // pyroe failed to produce, unless asked to and pyroe
+                    // only complained about things that are not fatal. A
+                    // pyroe killed midway (e.g. out of memory) may have
+                    // left partial files and said nothing, so never then.
+                    if !cres.status.success() {
+                        let produced = ref_seq.is_file() && t2g_file.is_file();
+                        let killed = cres.status.signal().is_some();
+                        if keep_going && !fatal && !killed && produced {
+                            warnings.push(format!(
+                                "{} but reported only non-fatal problems; continuing (--keep-going)",
+                                cres.failure_message("pyroe make-splici")
+                            ));
+                        } else {
+                            bail!(
+                                "{}:\n{}",
+                                cres.failure_message("pyroe make-splici"),
+                                stderr.trim_end()
+                            );
+                        }
+                    }
+                    pyroe_duration = Some(duration);
+                }
+
+                if let Some((ref decoy_seqs, _)) = decoys {
+                    concat_fastas(&[&ref_seq, decoy_seqs], &gentrome)?;
+                }
+
+                // only now that the new reference is ready, so a
+                // failed make-splici leaves the old index in place
+                if has_index {
+                    info!(
+                        "removing the existing index {} (--overwrite)",
+                        output_index_dir.display()
+                    );
+                    std::fs::remove_dir_all(&output_index_dir).with_context(|| {
+                        format!("could not remove {}", output_index_dir.display())
+                    })?;
+                }
+
+                if explain {
+                    explain_stage("salmon-index", None);
+                }
+                events.stage_started("salmon-index", &salmon_index_cmd);
+                let index_start = Instant::now();
+                let index_proc_out = run_stage(&mut salmon_index_cmd, &stage_opts)
+                    .context("failed to run salmon index")?;
+                let index_duration = index_start.elapsed();
+                events.stage_finished("salmon-index", &index_proc_out.status, index_duration);
+                resources.record("salmon-index", index_duration, &index_proc_out);
+                resources.log_cpu_summary(threads);
+
+                if !index_proc_out.status.success() {
+                    bail!(
+                        "{}:\n{}",
+                        index_proc_out.failure_message("salmon index"),
+                        String::from_utf8_lossy(&index_proc_out.stderr).trim_end()
+                    );
+                }
+                Ok((resources, pyroe_duration, index_duration))
+            };
+            let (resources, pyroe_duration, index_duration) = run_stages().map_err(|e| {
+                with_failed_intermediates(
+                    e,
+                    &output,
+                    INDEX_INTERMEDIATES,
+                    cli_args.keep_tmp_on_failure,
+                )
+            })?;
+
+            // the concatenated reference (and any decoys extracted for
+            // it) is about the size of the genome and is of no use once
+            // it is indexed
+            if let Some((ref decoy_seqs, _)) = decoys {
+                let mut temps = vec![&gentrome];
+                if decoy_names.is_some() {
+                    temps.push(decoy_seqs);
+                }
+                for p in temps {
+                    std::fs::remove_file(p)
+                        .with_context(|| format!("could not remove {}", p.display()))?;
+                }
+            }
+
+            if !extra_genes.is_empty() {
+                let n = retag_t2g_genes(&t2g_file, &extra_genes)?;
+                info!(
+                    "assigned {} extra sequence records to their listed genes",
+                    n
+                );
+            }
 
             // copy over the t2g file to the index
-            let index_t2g_path = output_index_dir.join("t2g_3col.tsv");
             std::fs::copy(t2g_file, index_t2g_path)?;
 
             let index_log_file = output.join("simpleaf_index_log.json");
@@ -385,7 +2942,9 @@ fn main() -> anyhow::Result<()> {
                 "time_info" : {
                     "pyroe_time" : pyroe_duration,
                     "index_time" : index_duration
-                }
+                },
+                "resources" : resources,
+                "warnings" : warnings
             });
 
             std::fs::write(
@@ -396,19 +2955,82 @@ fn main() -> anyhow::Result<()> {
         }
         Commands::Quant {
             index,
+            index_sha256,
             reads1,
             reads2,
+            from_rad,
+            downsample,
+            fail_on_empty_reads,
+            read_geometry_check,
+            manifest,
+            parallel_samples,
+            continue_on_error,
             threads,
-            knee,
-            unfiltered_pl,
+            stage_timeout,
+            max_retries,
+            emit_script,
+            estimate,
+            summary_tsv,
+            manifest_checksums,
+            methods_out,
+            note,
+            mut knee,
+            mut unfiltered_pl,
+            unfiltered_min_reads,
+            max_barcodes,
+            warn_max_barcodes,
             explicit_pl,
             forced_cells,
             expect_cells,
             resolution,
+            large_graph_thresh,
+            pug_exact_umi,
+            output_format,
+            velocity,
+            output_barcodes,
+            gene_allowlist,
+            knee_plot,
+            summary_stat,
+            expected_ori,
+            mapping_output_format,
+            hit_filter_policy,
+            max_read_occ,
+            dump_features,
+            collate_max_records,
+            collate_compress,
             t2g_map,
+            check_t2g,
+            check_index,
+            strict,
+            strict_read_order,
             chemistry,
+            profile,
+            sample_sheet,
             output,
         } => {
+            if velocity && output_format != "mtx" {
+                bail!(
+                    "--velocity splits an MTX count matrix; please also pass --output-format mtx"
+                );
+            }
+            if gene_allowlist.is_some() && output_format != "mtx" {
+                bail!(
+                    "--gene-allowlist filters an MTX count matrix; please also pass --output-format mtx"
+                );
+            }
+
+            if knee_plot && !cfg!(feature = "knee-plot") {
+                bail!(
+                    "--knee-plot needs simpleaf built with the knee-plot feature \
+                    (`cargo build --features knee-plot`)"
+                );
+            }
+            // fail fast if we won't be able to write our output
+            // (a templated output is checked once it is expanded)
+            if !is_output_template(&output) {
+                ensure_output_writable(&output)?;
+            }
+            let stage_opts = StageOpts::new(stage_timeout, max_retries);
             // Open the file in read-only mode with buffer.
             let af_info_p = af_home_path.join("simpleaf_info.json");
             let simpleaf_info_file = std::fs::File::open(&af_info_p).with_context({
@@ -422,27 +3044,186 @@ fn main() -> anyhow::Result<()> {
             info!("deserializing from {:?}", simpleaf_info_file);
             let v: serde_json::Value = serde_json::from_reader(simpleaf_info_reader)?;
             let rp: ReqProgs = serde_json::from_value(v["prog_info"].clone())?;
+            check_version_pins(&rp, &version_pins)?;
 
             info!("prog info = {:?}", rp);
+            let mut warnings = RunWarnings::default();
+
+            // fetch (or reuse the cached copy of) a remote index
+            let index = match index.to_str() {
+                Some(u) if is_url(u) => {
+                    fetch_remote_index(u, index_sha256.as_deref(), &af_home_path, &stage_opts)?
+                }
+                _ => {
+                    if index_sha256.is_some() {
+                        bail!("--index-sha256 can only be used with an http(s) URL as --index");
+                    }
+                    index
+                }
+            };
+            // accept the output directory of `index` in
+            // place of the salmon index inside it
+            let index = if index.join("index_info.json").is_file() && index.join("index").is_dir() {
+                let inner = index.join("index");
+                info!(
+                    "{} is a simpleaf index output directory; using the index in {}",
+                    index.display(),
+                    inner.display()
+                );
+                inner
+            } else {
+                index
+            };
+
+            let reference_name = index_reference_name(&index);
+            if let Some(ref r) = reference_name {
+                info!("quantifying against reference {}", r);
+            }
+            if let Some(msg) = index_version_mismatch(&index) {
+                warnings.push(msg);
+            }
+
+            let threads = clamp_threads(threads, &mut warnings);
+
+            // make sure collate will be able to read what
+            // salmon writes before starting the mapping.
+            check_mapping_output_format(&mapping_output_format, &rp)?;
+            check_rad_compatibility(&rp, cli_args.allow_newer, &mut warnings)?;
+
+            // fill in what the flags leave unset from the profile
+            let profile_settings = match profile {
+                Some(ref name) => {
+                    let pr = load_profile(name, &af_home_path)?;
+                    info!("using profile {} : {:?}", name, pr);
+                    Some(pr)
+                }
+                None => None,
+            };
+            let mut resolution = resolution;
+            let mut expected_ori = expected_ori;
+            let mut chemistry = chemistry;
+            if let Some(pr) = profile_settings {
+                let filter_given = knee
+                    || unfiltered_pl
+                    || explicit_pl.is_some()
+                    || forced_cells.is_some()
+                    || expect_cells.is_some();
+                if !filter_given {
+                    match pr.filter.as_deref() {
+                        Some("knee") => knee = true,
+                        Some("unfiltered-pl") => unfiltered_pl = true,
+                        _ => {}
+                    }
+                }
+                if resolution.is_empty() {
+                    resolution = pr.resolution.unwrap_or_default();
+                }
+                expected_ori = expected_ori.or(pr.expected_ori);
+                // the sample sheet still overrides the profile's chemistry
+                if sample_sheet.is_none() {
+                    chemistry = chemistry.or(pr.chemistry);
+                }
+            }
 
             let mut filter_meth_opt = None;
-            let chem = match chemistry.as_str() {
-                "10xv2" => Chemistry::TenxV2,
-                "10xv3" => Chemistry::TenxV3,
-                s => Chemistry::Other(s.to_string()),
+            // an explicit chemistry always overrides the sample sheet
+            let chemistry = match (chemistry, sample_sheet) {
+                (Some(c), _) => c,
+                (None, Some(ss)) => match chemistry_from_sample_sheet(&ss)? {
+                    Some(c) => {
+                        info!("inferred chemistry {} from {}", c, ss.display());
+                        c
+                    }
+                    None => bail!(
+                        "could not infer the chemistry from {}; please provide --chemistry",
+                        ss.display()
+                    ),
+                },
+                (None, None) => bail!(
+                    "profile {} sets no chemistry; please provide --chemistry",
+                    profile.as_deref().unwrap_or_default()
+                ),
+            };
+            let chem = Chemistry::from_arg(&chemistry)?;
+
+            // explicit values always override the chemistry defaults
+            let mut resolutions = resolution;
+            if resolutions.is_empty() {
+                match chem.default_resolution() {
+                    Some(r) => resolutions.push(r.to_string()),
+                    None => bail!(
+                        "chemistry {} has no default resolution; please provide --resolution",
+                        chemistry
+                    ),
+                }
+            }
+            // quantifying the same resolution twice would only overwrite it
+            let mut seen = HashSet::new();
+            resolutions.retain(|r| seen.insert(r.clone()));
+            let parsimony_opts = ParsimonyOpts {
+                large_graph_thresh,
+                pug_exact_umi,
+            };
+            check_parsimony_opts(&parsimony_opts, &resolutions)?;
+
+            let output = if is_output_template(&output) {
+                let now = time::OffsetDateTime::now_utc();
+                let chem_name = match &chem {
+                    Chemistry::Custom(_) => Path::new(&chemistry)
+                        .file_stem()
+                        .map_or(chemistry.clone(), |s| s.to_string_lossy().into_owned()),
+                    _ => chemistry.clone(),
+                };
+                let expanded = expand_output_template(
+                    &output,
+                    &[
+                        ("chemistry", Some(chem_name)),
+                        ("date", Some(now.date().to_string())),
+                        (
+                            "time",
+                            Some(format!(
+                                "{:02}{:02}{:02}",
+                                now.hour(),
+                                now.minute(),
+                                now.second()
+                            )),
+                        ),
+                        ("reference_name", reference_name.clone()),
+                        ("resolution", Some(resolutions.join("_"))),
+                    ],
+                )?;
+                info!("writing output to {}", expanded.display());
+                ensure_output_writable(&expanded)?;
+                expanded
+            } else {
+                output
             };
+            let _group_writable = cli_args.group_writable.then(|| GroupWritable::new(&output));
+            // only an orientation someone chose changes salmon's library type
+            let chosen_ori = expected_ori.clone().or_else(|| match &chem {
+                Chemistry::Custom(cc) => cc.expected_ori.clone(),
+                _ => None,
+            });
+            let salmon_libtype = salmon_libtype_for_ori(chosen_ori.as_deref());
+            let expected_ori =
+                expected_ori.unwrap_or_else(|| chem.default_expected_ori().to_string());
+            if expected_ori == "auto" && emit_script.is_some() {
+                bail!(
+                    "--expected-ori auto chooses the orientation from the mapping, so it \
+                    can't be used with --emit-script; pass fw, rc or both"
+                );
+            }
 
             // based on the filtering method
             if unfiltered_pl {
                 // check the chemistry
-                let pl_res = get_permit_if_absent(chem)?;
-                let min_cells = 10usize;
+                let pl_res = get_permit_if_absent(&chem, &af_home_path, &stage_opts)?;
                 match pl_res {
                     PermitListResult::DownloadSuccessful(p)
                     | PermitListResult::AlreadyPresent(p) => {
                         filter_meth_opt = Some(CellFilterMethod::UnfilteredExternalList(
-                            p.to_string_lossy().into_owned(),
-                            min_cells,
+                            p,
+                            unfiltered_min_reads,
                         ));
                     }
                     PermitListResult::UnregisteredChemistry => {
@@ -453,25 +3234,14 @@ fn main() -> anyhow::Result<()> {
                     }
                 }
             } else {
-                match explicit_pl {
-                    Some(filtered_path) => {
-                        filter_meth_opt = Some(CellFilterMethod::ExplicitList(
-                            filtered_path.to_string_lossy().into_owned(),
-                        ));
-                    }
-                    None => {}
+                if let Some(filtered_path) = explicit_pl {
+                    filter_meth_opt = Some(CellFilterMethod::ExplicitList(filtered_path));
                 };
-                match forced_cells {
-                    Some(num_forced) => {
-                        filter_meth_opt = Some(CellFilterMethod::ForceCells(num_forced));
-                    }
-                    None => {}
+                if let Some(num_forced) = forced_cells {
+                    filter_meth_opt = Some(CellFilterMethod::ForceCells(num_forced));
                 };
-                match expect_cells {
-                    Some(num_expected) => {
-                        filter_meth_opt = Some(CellFilterMethod::ExpectCells(num_expected));
-                    }
-                    None => {}
+                if let Some(num_expected) = expect_cells {
+                    filter_meth_opt = Some(CellFilterMethod::ExpectCells(num_expected));
                 };
             }
             // otherwise it must have been knee;
@@ -480,168 +3250,474 @@ fn main() -> anyhow::Result<()> {
             }
 
             if filter_meth_opt.is_none() {
-                bail!("It seems no valid filtering strategy was provided!");
+                bail!(
+                    "It seems no valid filtering strategy was provided! Please pass one of \
+                    --knee, --unfiltered-pl, --explicit-pl, --forced-cells or --expect-cells, \
+                    or a --profile that sets one."
+                );
             }
 
             // here we must be safe to unwrap
             let filter_meth = filter_meth_opt.unwrap();
 
-            let mut salmon_quant_cmd =
-                std::process::Command::new(format!("{}", rp.salmon.unwrap().exe_path.display()));
+            if let Some(ref rad) = from_rad {
+                if !rad.join("map.rad").is_file() {
+                    bail!(
+                        "{} is not a salmon alevin RAD output directory (it has no map.rad)",
+                        rad.display()
+                    );
+                }
+                check_reused_mapping(rad)?;
+            }
 
-            // set the input index and library type
-            let index_path = format!("{}", index.display());
-            salmon_quant_cmd
-                .arg("alevin")
-                .arg("--index")
-                .arg(index_path)
-                .arg("-l")
-                .arg("A");
+            // a feature-barcode index says where in read 2 the
+            // feature barcodes are
+            let feature_read_geometry = index_feature_read_geometry(&index);
+            if let Some(ref g) = feature_read_geometry {
+                if let Chemistry::Other(ref s) = chem {
+                    bail!(
+                        "{} is a feature-barcode index, which needs a chemistry with a known \
+                        barcode and UMI layout, not salmon's {}; use 10xv2, 10xv3 or a custom \
+                        chemistry JSON file",
+                        index.display(),
+                        s
+                    );
+                }
+                info!(
+                    "mapping against a feature-barcode index, with the feature barcodes at {}",
+                    g
+                );
+            }
 
-            // location of the reads
-            let r1_str = reads1
-                .iter()
-                .map(|x| format!("{}", x.display()))
-                .collect::<Vec<String>>()
-                .join(",");
-            let r2_str = reads2
-                .iter()
-                .map(|x| format!("{}", x.display()))
-                .collect::<Vec<String>>()
-                .join(",");
-            salmon_quant_cmd.arg("-1").arg(r1_str).arg("-2").arg(r2_str);
-
-            // location of outptu directory, number of threads
-            let map_output = output.join("af_map");
-            salmon_quant_cmd
-                .arg("--threads")
-                .arg(format!("{}", threads))
-                .arg("-o")
-                .arg(&map_output);
-            salmon_quant_cmd.arg("--sketch");
+            let salmon = rp.salmon.unwrap();
+            let alevin_fry = rp.alevin_fry.unwrap();
+            let mut qs = QuantSettings {
+                salmon,
+                alevin_fry,
+                index,
+                reference_name,
+                chem,
+                feature_read_geometry,
+                filter_meth,
+                max_barcodes,
+                warn_max_barcodes,
+                resolutions,
+                output_format,
+                velocity,
+                output_barcodes,
+                gene_allowlist: match gene_allowlist {
+                    Some(p) => {
+                        let genes = read_gene_allowlist(&p)?;
+                        Some((p, genes))
+                    }
+                    None => None,
+                },
+                knee_plot,
+                summary_stat,
+                expected_ori,
+                salmon_libtype,
+                mapping_output_format,
+                sketch_opts: SketchOpts {
+                    hit_filter_policy,
+                    max_read_occ,
+                },
+                dump_features,
+                collate_opts: CollateOpts {
+                    max_records: collate_max_records,
+                    compress: collate_compress,
+                },
+                parsimony_opts,
+                t2g_map,
+                stage_opts,
+                version_pins,
+                summary_tsv,
+                manifest_checksums,
+                note,
+                profile,
+                explain,
+                downsample,
+                from_rad,
+                warnings,
+            };
 
-            // setting the technology / chemistry
-            match chemistry.as_str() {
-                "10xv2" => {
-                    salmon_quant_cmd.arg("--chromium");
+            for w in parsimony_warnings(&qs.resolutions, &qs.geometry(), &qs.filter_meth) {
+                qs.warnings.push(w);
+            }
+
+            // either the samples listed in the manifest, each written
+            // to its own subdirectory, or the single sample given by
+            // the read arguments, written directly to `output`.
+            let samples = match manifest {
+                Some(ref m) => read_manifest(m)?
+                    .into_iter()
+                    .map(|s| {
+                        let o = output.join(&s.name);
+                        (s, o)
+                    })
+                    .collect::<Vec<(SampleReads, PathBuf)>>(),
+                None if qs.from_rad.is_none() && (reads1.is_empty() || reads2.is_empty()) => {
+                    bail!(
+                        "please provide --reads1 and --reads2, a --manifest of samples, \
+                        or the --from-rad output of an earlier mapping"
+                    )
+                }
+                None => vec![(
+                    SampleReads {
+                        name: output
+                            .file_name()
+                            .map_or(String::from("sample"), |n| n.to_string_lossy().into_owned()),
+                        reads1,
+                        reads2,
+                        chemistry: None,
+                    },
+                    output.clone(),
+                )],
+            };
+            // one run maps every sample with the same chemistry,
+            // so it can't honour a manifest that names several
+            // (comparing layouts, so that e.g. SC3Pv3 matches 10xv3)
+            let mut other_chems = Vec::new();
+            for (s, _) in &samples {
+                if let Some(ref c) = s.chemistry {
+                    let sc = Chemistry::from_arg(tenx_chemistry_name(c))
+                        .with_context(|| format!("invalid chemistry for sample {}", s.name))?;
+                    if !sc.same_layout(&qs.chem) {
+                        other_chems.push(format!("{} ({})", s.name, c));
+                    }
                 }
-                "10xv3" => {
-                    salmon_quant_cmd.arg("--chromiumV3");
+            }
+            if !other_chems.is_empty() {
+                bail!(
+                    "this run uses chemistry {}, but the manifest gives {} sample(s) another \
+                    chemistry: {}. Samples of different chemistries can't be quantified in one \
+                    run; split the manifest by chemistry, quantify each part with its own \
+                    --chemistry, and combine the results with `simpleaf merge` if needed",
+                    chemistry,
+                    other_chems.len(),
+                    other_chems.join(", ")
+                );
+            }
+            for (s, _) in &samples {
+                check_read_paths(s)?;
+            }
+            if fail_on_empty_reads {
+                check_read_files(samples.iter().map(|(s, _)| s))?;
+            }
+            // over-trimmed or swapped reads can't hold the barcode
+            // and UMI, which salmon doesn't always report clearly
+            if read_geometry_check {
+                match qs.geometry().required_r1_length() {
+                    Some(needed) => {
+                        for (s, _) in &samples {
+                            for r1 in &s.reads1 {
+                                let lens = read_lengths(r1, 1000)?;
+                                let short = lens.iter().filter(|l| **l < needed as usize).count();
+                                if short * 2 > lens.len() {
+                                    bail!(
+                                        "{} of the first {} reads of {} are shorter than the {} \
+                                        bases of read 1 that chemistry {} needs for the barcode \
+                                        and UMI; check that the reads are not over-trimmed and \
+                                        that R1 and R2 are not swapped",
+                                        short,
+                                        lens.len(),
+                                        r1.display(),
+                                        needed,
+                                        chemistry
+                                    );
+                                } else if short > 0 {
+                                    qs.warnings.push(format!(
+                                        "{} of the first {} reads of {} are shorter than the {} \
+                                        bases chemistry {} needs for the barcode and UMI",
+                                        short,
+                                        lens.len(),
+                                        r1.display(),
+                                        needed,
+                                        chemistry
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    None => qs.warnings.push(format!(
+                        "the barcode and UMI layout of chemistry {} is not known to simpleaf, \
+                        so --read-geometry-check was skipped",
+                        chemistry
+                    )),
                 }
-                s => {
-                    salmon_quant_cmd.arg(format!("--{}", s));
+            }
+            // salmon reads either, but a pair that mixes them was
+            // most likely put together by mistake.
+            for (s, _) in &samples {
+                for w in compression_mismatches(s) {
+                    qs.warnings.push(w);
                 }
-            };
-
-            info!("cmd : {:?}", salmon_quant_cmd);
-            let map_start = Instant::now();
-            let map_proc_out = salmon_quant_cmd
-                .output()
-                .expect("failed to execute salmon alevin [mapping phase]");
-            let map_duration = map_start.elapsed();
-
-            if !map_proc_out.status.success() {
-                bail!("mapping failed with exit status {:?}", map_proc_out.status);
             }
 
-            let alevin_fry = rp.alevin_fry.unwrap().exe_path;
-            // alevin-fry generate permit list
-            let mut alevin_gpl_cmd =
-                std::process::Command::new(format!("{}", &alevin_fry.display()));
+            if check_index {
+                let how = validate_index(&qs.index, &qs.salmon)?;
+                info!("the index {} passed the {}", qs.index.display(), how);
+            }
 
-            alevin_gpl_cmd.arg("generate-permit-list");
-            alevin_gpl_cmd.arg("-i").arg(&map_output);
-            alevin_gpl_cmd.arg("-d").arg("fw");
+            // transcripts missing from the t2g map leave their reads
+            // unassigned, which otherwise goes unnoticed.
+            if check_t2g {
+                let ref_seq = read_index_info(&qs.index)
+                    .and_then(|v| v["ref_seq"].as_str().map(PathBuf::from));
+                match ref_seq {
+                    Some(ref_seq) if ref_seq.is_file() => {
+                        let cov = t2g_coverage(&ref_seq, &qs.index, &qs.t2g_map)?;
+                        if cov.missing.is_empty() {
+                            info!(
+                                "all {} index transcripts are in the t2g map",
+                                cov.num_transcripts
+                            );
+                        } else {
+                            let msg = format!(
+                                "{} of the {} index transcripts have no entry in the t2g map {} \
+                                (e.g. {}); their reads will be unassigned",
+                                cov.missing.len(),
+                                cov.num_transcripts,
+                                qs.t2g_map.display(),
+                                cov.missing
+                                    .iter()
+                                    .take(5)
+                                    .cloned()
+                                    .collect::<Vec<String>>()
+                                    .join(", ")
+                            );
+                            if strict {
+                                bail!(msg);
+                            }
+                            qs.warnings.push(msg);
+                        }
+                    }
+                    _ => qs.warnings.push(format!(
+                        "could not find the reference sequences of the index {} \
+                        (it must be built by simpleaf index), so --check-t2g was skipped",
+                        qs.index.display()
+                    )),
+                }
+            }
 
-            // add the filter mode
-            add_to_args(&filter_meth, &mut alevin_gpl_cmd);
+            // the index is built for a read length, so warn when
+            // the reads are much shorter or longer than that.
+            let index_rlen = read_index_info(&qs.index).and_then(|v| v["args"]["rlen"].as_u64());
+            if let Some(rlen) = index_rlen {
+                for (s, _) in &samples {
+                    let len = match s.reads2.first() {
+                        Some(r2) => first_read_length(r2)?,
+                        None => None,
+                    };
+                    if let Some(len) = len {
+                        let diff = (len as f64 - rlen as f64).abs();
+                        if diff > 0.1 * rlen as f64 {
+                            qs.warnings.push(format!(
+                                "the reads of sample {} are {} bases long, but the index was \
+                                built for reads of length {}; mapping may be suboptimal",
+                                s.name, len, rlen
+                            ));
+                        }
+                    }
+                }
+            }
+            // swapped -1/-2 arguments map almost nothing, so catch
+            // them from the lengths of the first reads, for the
+            // chemistries whose R1 holds just the barcode and UMI
+            if let Some(needed) = qs.geometry().barcode_only_r1_length() {
+                for (s, _) in &samples {
+                    for (r1, r2) in s.reads1.iter().zip(&s.reads2) {
+                        if let Some(msg) = swapped_reads_problem(r1, r2, needed)? {
+                            if strict_read_order {
+                                bail!(msg);
+                            }
+                            qs.warnings.push(msg);
+                        }
+                    }
+                }
+            }
+            let multi_sample = manifest.is_some();
+            let sample_events = |s: &SampleReads| {
+                if multi_sample {
+                    events.for_sample(&s.name)
+                } else {
+                    events.clone()
+                }
+            };
 
-            let gpl_output = output.join("af_quant");
-            alevin_gpl_cmd.arg("-o").arg(&gpl_output);
+            if estimate {
+                let reads = samples
+                    .iter()
+                    .map(|(s, _)| s)
+                    .collect::<Vec<&SampleReads>>();
+                estimate_quant(&reads, &qs.index, threads)?.print();
+                return Ok(());
+            }
 
-            info!("cmd : {:?}", alevin_gpl_cmd);
+            // if requested, write the resolved commands
+            // out instead of running them.
+            if let Some(script_path) = emit_script {
+                let mut lines = Vec::new();
+                for (s, o) in &samples {
+                    if multi_sample {
+                        lines.push(format!("# sample {}", s.name));
+                    }
+                    lines.extend(quant_sample(&qs, s, o, threads, true, &events)?);
+                }
+                write_command_script(&script_path, &lines)?;
+                info!("wrote the quant commands to {}", script_path.display());
+                return Ok(());
+            }
 
-            let gpl_start = Instant::now();
-            let gpl_proc_out = alevin_gpl_cmd
-                .output()
-                .expect("could not execute [generate permit list]");
-            let gpl_duration = gpl_start.elapsed();
+            if multi_sample {
+                let names = samples
+                    .iter()
+                    .map(|(s, _)| s.name.as_str())
+                    .collect::<Vec<&str>>();
+                write_sample_list(&output, &names)?;
+            }
 
-            if !gpl_proc_out.status.success() {
-                bail!(
-                    "generate-permit-list failed with exit status {:?}",
-                    gpl_proc_out.status
+            // split the threads among the samples that run at once
+            // rather than giving each of them all of the threads.
+            let parallel_samples = (parallel_samples as usize).clamp(1, samples.len());
+            let sample_threads = (threads / parallel_samples as u32).max(1);
+            if parallel_samples > 1 {
+                info!(
+                    "quantifying {} samples at a time with {} threads each",
+                    parallel_samples, sample_threads
                 );
             }
 
-            //
-            // collate
-            //
-            let mut alevin_collate_cmd =
-                std::process::Command::new(format!("{}", &alevin_fry.display()));
-
-            alevin_collate_cmd.arg("collate");
-            alevin_collate_cmd.arg("-i").arg(&gpl_output);
-            alevin_collate_cmd.arg("-r").arg(&map_output);
-            alevin_collate_cmd.arg("-t").arg(format!("{}", threads));
+            let next_sample = AtomicUsize::new(0);
+            let failed = AtomicBool::new(false);
+            let failures = Mutex::new(Vec::new());
+            std::thread::scope(|scope| {
+                for _ in 0..parallel_samples {
+                    scope.spawn(|| {
+                        // don't start new samples once one has failed
+                        while !failed.load(Ordering::SeqCst) {
+                            let i = next_sample.fetch_add(1, Ordering::SeqCst);
+                            let Some((s, o)) = samples.get(i) else {
+                                break;
+                            };
+                            let r =
+                                quant_sample(&qs, s, o, sample_threads, false, &sample_events(s))
+                                    .map_err(|e| {
+                                        with_failed_intermediates(
+                                            e,
+                                            o,
+                                            QUANT_INTERMEDIATES,
+                                            cli_args.keep_tmp_on_failure,
+                                        )
+                                    });
+                            if let Err(e) = r {
+                                if continue_on_error {
+                                    error!("quantifying sample {} failed: {:#}", s.name, e);
+                                } else {
+                                    failed.store(true, Ordering::SeqCst);
+                                }
+                                failures.lock().unwrap().push((s.name.clone(), e));
+                            }
+                        }
+                    });
+                }
+            });
 
-            info!("cmd : {:?}", alevin_collate_cmd);
-            let collate_start = Instant::now();
-            let collate_proc_out = alevin_collate_cmd
-                .output()
-                .expect("could not execute [collate]");
-            let collate_duration = collate_start.elapsed();
+            let failures = failures.into_inner().unwrap();
+            if continue_on_error {
+                for (s, _) in &samples {
+                    match failures.iter().find(|(n, _)| *n == s.name) {
+                        Some((_, e)) => info!("sample {} : failed ({:#})", s.name, e),
+                        None => info!("sample {} : ok", s.name),
+                    }
+                }
+                if !failures.is_empty() {
+                    bail!(
+                        "{} of {} samples failed: {}",
+                        failures.len(),
+                        samples.len(),
+                        failures
+                            .iter()
+                            .map(|(n, _)| n.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    );
+                }
+            }
+            if let Some((name, e)) = failures.into_iter().next() {
+                if multi_sample {
+                    return Err(e.context(format!("quantifying sample {} failed", name)));
+                }
+                return Err(e);
+            }
 
-            if !collate_proc_out.status.success() {
-                bail!(
-                    "collate failed with exit status {:?}",
-                    collate_proc_out.status
-                );
+            if let Some(ref p) = methods_out {
+                std::fs::write(p, methods_text(&qs))
+                    .with_context(|| format!("could not write {}", p.display()))?;
+                info!("wrote a methods paragraph to {}", p.display());
             }
+        }
+    }
+    Ok(())
+}
 
-            //
-            // quant
-            //
-            let mut alevin_quant_cmd =
-                std::process::Command::new(format!("{}", &alevin_fry.display()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quant_args(extra: &[&str]) -> Vec<std::ffi::OsString> {
+        [
+            "simpleaf", "quant", "-i", "idx", "-c", "10xv3", "-m", "t2g", "-o", "out",
+        ]
+        .iter()
+        .chain(extra)
+        .map(std::ffi::OsString::from)
+        .collect()
+    }
 
-            alevin_quant_cmd
-                .arg("quant")
-                .arg("-i")
-                .arg(&gpl_output)
-                .arg("-o")
-                .arg(&gpl_output);
-            alevin_quant_cmd.arg("-t").arg(format!("{}", threads));
-            alevin_quant_cmd.arg("-m").arg(t2g_map);
-            alevin_quant_cmd.arg("-r").arg(resolution);
-
-            info!("cmd : {:?}", alevin_quant_cmd);
-            let quant_start = Instant::now();
-            let quant_proc_out = alevin_quant_cmd
-                .output()
-                .expect("could not execute [quant]");
-            let quant_duration = quant_start.elapsed();
-
-            if !quant_proc_out.status.success() {
-                bail!("quant failed with exit status {:?}", quant_proc_out.status);
-            }
-
-            let af_quant_info_file = output.join("simpleaf_quant_log.json");
-            let af_quant_info = json!({
-                "time_info" : {
-                "map_time" : map_duration,
-                "gpl_time" : gpl_duration,
-                "collate_time" : collate_duration,
-                "quant_time" : quant_duration
-                }
-            });
+    fn rc(section: &str, key: &str, val: serde_json::Value) -> HashMap<String, RcSection> {
+        HashMap::from([(
+            section.to_string(),
+            vec![(key.to_string(), val, PathBuf::from(RC_FILE))],
+        )])
+    }
 
-            std::fs::write(
-                &af_quant_info_file,
-                serde_json::to_string_pretty(&af_quant_info).unwrap(),
-            )
-            .with_context(|| format!("could not write {}", af_quant_info_file.display()))?;
+    #[test]
+    fn rc_default_fills_an_unset_option() {
+        let args = apply_rc_defaults(quant_args(&[]), &rc("quant", "knee", json!(true))).unwrap();
+        assert!(args.iter().any(|a| a == "--knee"));
+    }
+
+    #[test]
+    fn rc_default_yields_to_a_conflicting_command_line_option() {
+        let sections = rc("quant", "knee", json!(true));
+        for given in [&["-u"][..], &["--expect-cells", "100"]] {
+            let args = apply_rc_defaults(quant_args(given), &sections).unwrap();
+            assert!(!args.iter().any(|a| a == "--knee"), "{:?}", given);
+        }
+    }
+
+    #[test]
+    fn pyroe_lines_are_classified_by_their_form() {
+        for (line, class) in [
+            ("Traceback (most recent call last):", PyroeLine::Fatal),
+            ("ValueError: no exon features found", PyroeLine::Fatal),
+            ("pyranges.SomeException: bad interval", PyroeLine::Fatal),
+            ("ERROR:root:could not read the GTF", PyroeLine::Fatal),
+            ("WARNING:root:skipped 2 transcripts", PyroeLine::Warning),
+            (
+                "/opt/pyroe/make_splici.py:120: UserWarning: transcripts without exons",
+                PyroeLine::Warning,
+            ),
+            ("Processing the GTF file", PyroeLine::Info),
+            // words merely mentioning errors or warnings aren't either
+            (
+                "wrote gene ERRFI1 to /data/error_runs/splici.fa",
+                PyroeLine::Info,
+            ),
+            ("Exception handling of the GTF is done", PyroeLine::Info),
+            ("no warnings were raised", PyroeLine::Info),
+        ] {
+            assert_eq!(classify_pyroe_line(line), class, "{}", line);
         }
     }
-    Ok(())
 }
@@ -1,3 +1,81 @@
+use anyhow::{bail, Context, Result};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::utils::run_utils::RunWarnings;
+
+pub const RESOLUTIONS: [&str; 6] = [
+    "cr-like",
+    "cr-like-em",
+    "parsimony",
+    "parsimony-em",
+    "parsimony-gene",
+    "parsimony-gene-em",
+];
+
+// `auto` maps the reads unstranded and then picks one of the
+// others from the orientations they mapped in
+pub const EXPECTED_ORIS: [&str; 4] = ["fw", "rc", "both", "auto"];
+
+// The share of the reads that must map in one orientation for
+// `--expected-ori auto` to choose it rather than `both`.
+const AUTO_ORI_MIN_FRACTION: f64 = 0.8;
+
+// The salmon library type that makes the mapping step keep the
+// same read orientation that generate-permit-list will expect,
+// so that the two steps can't disagree, when an orientation was
+// chosen; otherwise salmon's automatic detection (`A`) is kept.
+pub fn salmon_libtype_for_ori(ori: Option<&str>) -> &'static str {
+    match ori {
+        None => "A",
+        Some("fw") => "SF",
+        Some("rc") => "SR",
+        Some(_) => "U",
+    }
+}
+
+// The orientation chosen by `--expected-ori auto`.
+#[derive(Debug, Serialize)]
+pub struct DetectedOri {
+    pub ori: String,
+    // the share of the oriented mappings that were forward
+    pub fw_fraction: f64,
+    pub num_reads: u64,
+}
+
+// Chooses the orientation for generate-permit-list from the counts of
+// forward (SF) and reverse (SR) mapped reads salmon recorded in the
+// `lib_format_counts.json` of the mapping output `map_output`.
+pub fn detect_expected_ori(map_output: &Path) -> Result<DetectedOri> {
+    let p = map_output.join("lib_format_counts.json");
+    let text = std::fs::read_to_string(&p)
+        .with_context(|| format!("could not read {} to detect the orientation", p.display()))?;
+    let v: serde_json::Value =
+        serde_json::from_str(&text).with_context(|| format!("could not parse {}", p.display()))?;
+    let (sf, sr) = (v["SF"].as_u64().unwrap_or(0), v["SR"].as_u64().unwrap_or(0));
+    if sf + sr == 0 {
+        bail!(
+            "no reads were mapped in either orientation according to {}, so \
+            --expected-ori auto can't choose one; pass fw, rc or both",
+            p.display()
+        );
+    }
+    let fw_fraction = sf as f64 / (sf + sr) as f64;
+    let ori = if fw_fraction >= AUTO_ORI_MIN_FRACTION {
+        "fw"
+    } else if 1.0 - fw_fraction >= AUTO_ORI_MIN_FRACTION {
+        "rc"
+    } else {
+        "both"
+    };
+    Ok(DetectedOri {
+        ori: ori.to_string(),
+        fw_fraction,
+        num_reads: sf + sr,
+    })
+}
+
 #[derive(Debug, Clone)]
 pub enum CellFilterMethod {
     // cut off at this cell in
@@ -9,17 +87,49 @@ pub enum CellFilterMethod {
     // correct all cells in an
     // edit distance of 1 of these
     // barcodes
-    ExplicitList(String),
+    ExplicitList(PathBuf),
     // barcodes will be provided in the
     // form of an *unfiltered* external
     // permit list
-    UnfilteredExternalList(String, usize),
+    UnfilteredExternalList(PathBuf, usize),
     // use the distance method to
     // automatically find the knee
     // in the curve
     KneeFinding,
 }
 
+impl CellFilterMethod {
+    // How this method picks out the real cells, in plain language.
+    pub fn describe(&self) -> String {
+        match self {
+            CellFilterMethod::ForceCells(nc) => format!(
+                "keeping exactly the {} barcodes with the most reads as cells",
+                nc
+            ),
+            CellFilterMethod::ExpectCells(nc) => format!(
+                "using the expectation of about {} cells as a hint to where the \
+                real cells end in the list of barcodes sorted by read count",
+                nc
+            ),
+            CellFilterMethod::ExplicitList(l) => format!(
+                "keeping the barcodes listed in {} (and those one error away from them)",
+                l.display()
+            ),
+            CellFilterMethod::UnfilteredExternalList(l, m) => format!(
+                "keeping every barcode from the list of all possible barcodes {} \
+                that has at least {} reads, leaving the call of which are real cells \
+                to you",
+                l.display(),
+                m
+            ),
+            CellFilterMethod::KneeFinding => String::from(
+                "looking for the \"knee\" in the curve of read counts per barcode, \
+                where the many reads of real cells drop off to the few of empty droplets",
+            ),
+        }
+    }
+}
+
 pub fn add_to_args(fm: &CellFilterMethod, cmd: &mut std::process::Command) {
     match fm {
         CellFilterMethod::ForceCells(nc) => {
@@ -37,8 +147,779 @@ pub fn add_to_args(fm: &CellFilterMethod, cmd: &mut std::process::Command) {
                 .arg("--min-reads")
                 .arg(format!("{}", m));
         }
+        // alevin-fry's knee method takes no tuning options;
+        // --min-reads only applies to an unfiltered permit list
         CellFilterMethod::KneeFinding => {
             cmd.arg("--knee");
         }
     }
 }
+
+// The single-cell technologies salmon alevin knows by name,
+// selected with `--<name>`.
+pub const SALMON_TECH_FLAGS: [&str; 10] = [
+    "dropseq",
+    "chromium",
+    "chromiumV3",
+    "gemcode",
+    "celseq",
+    "celseq2",
+    "quartzseq2",
+    "sciseq3",
+    "splitseqV1",
+    "splitseqV2",
+];
+
+pub enum Chemistry {
+    TenxV2,
+    TenxV3,
+    Custom(CustomChemistry),
+    Other(String),
+}
+
+impl Chemistry {
+    // Parses a `--chemistry` argument: a registered name, the path
+    // of a custom chemistry JSON file, or one of salmon's technology
+    // flags, which is passed on to salmon as is.
+    pub fn from_arg(s: &str) -> Result<Self> {
+        Ok(match s {
+            "10xv2" => Chemistry::TenxV2,
+            "10xv3" => Chemistry::TenxV3,
+            s if Path::new(s).is_file() => {
+                Chemistry::Custom(CustomChemistry::from_json_file(Path::new(s))?)
+            }
+            s if SALMON_TECH_FLAGS.contains(&s) => Chemistry::Other(s.to_string()),
+            s => bail!(
+                "unknown chemistry {:?}; expected 10xv2, 10xv3, one of salmon's technologies \
+                ({}), or the path to a custom chemistry JSON file giving the read geometry",
+                s,
+                SALMON_TECH_FLAGS.join(", ")
+            ),
+        })
+    }
+
+    // A short name for the chemistry, as used in reports.
+    pub fn name(&self) -> String {
+        match self {
+            Chemistry::TenxV2 => String::from("10xv2"),
+            Chemistry::TenxV3 => String::from("10xv3"),
+            Chemistry::Custom(_) => String::from("custom"),
+            Chemistry::Other(s) => s.clone(),
+        }
+    }
+
+    // Whether `other` describes the same read layout, e.g. 10xv3
+    // and salmon's chromiumV3, or two custom chemistry files with
+    // the same geometry.
+    pub fn same_layout(&self, other: &Chemistry) -> bool {
+        let (a, b) = (self.geometry("fw"), other.geometry("fw"));
+        let layout = |g: &ChemistryGeometry| match (
+            &g.barcode_geometry,
+            &g.umi_geometry,
+            &g.read_geometry,
+        ) {
+            (Some(bc), Some(umi), Some(read)) => Some((bc.clone(), umi.clone(), read.clone())),
+            _ => None,
+        };
+        match (layout(&a), layout(&b)) {
+            (Some(la), Some(lb)) => la == lb,
+            _ => a.salmon_args == b.salmon_args,
+        }
+    }
+
+    // The resolution used when `--resolution` is omitted.
+    pub fn default_resolution(&self) -> Option<&str> {
+        match self {
+            Chemistry::TenxV2 | Chemistry::TenxV3 => Some("cr-like"),
+            Chemistry::Custom(cc) => cc.resolution.as_deref(),
+            Chemistry::Other(_) => None,
+        }
+    }
+
+    // The orientation used when `--expected-ori` is omitted.
+    pub fn default_expected_ori(&self) -> &str {
+        match self {
+            Chemistry::Custom(cc) => cc.expected_ori.as_deref().unwrap_or("fw"),
+            _ => "fw",
+        }
+    }
+
+    // The read layout the chemistry implies, as mapped with
+    // orientation `expected_ori`, for the provenance log.
+    pub fn geometry(&self, expected_ori: &str) -> ChemistryGeometry {
+        let (salmon_args, bc, umi, read) = match self {
+            Chemistry::TenxV2 => (
+                vec![String::from("--chromium")],
+                "1[1-16]",
+                "1[17-26]",
+                "2[1-end]",
+            ),
+            Chemistry::TenxV3 => (
+                vec![String::from("--chromiumV3")],
+                "1[1-16]",
+                "1[17-28]",
+                "2[1-end]",
+            ),
+            Chemistry::Custom(cc) => (
+                vec![
+                    String::from("--bc-geometry"),
+                    cc.barcode_geometry.clone(),
+                    String::from("--umi-geometry"),
+                    cc.umi_geometry.clone(),
+                    String::from("--read-geometry"),
+                    cc.read_geometry.clone(),
+                ],
+                cc.barcode_geometry.as_str(),
+                cc.umi_geometry.as_str(),
+                cc.read_geometry.as_str(),
+            ),
+            // only salmon knows the layout of its other technologies
+            Chemistry::Other(s) => {
+                return ChemistryGeometry {
+                    name: self.name(),
+                    salmon_args: vec![format!("--{}", s)],
+                    barcode_geometry: None,
+                    umi_geometry: None,
+                    read_geometry: None,
+                    barcode_length: None,
+                    umi_length: None,
+                    expected_ori: expected_ori.to_string(),
+                };
+            }
+        };
+        ChemistryGeometry {
+            name: self.name(),
+            salmon_args,
+            barcode_geometry: Some(bc.to_string()),
+            umi_geometry: Some(umi.to_string()),
+            read_geometry: Some(read.to_string()),
+            barcode_length: geometry_length(bc),
+            umi_length: geometry_length(umi),
+            expected_ori: expected_ori.to_string(),
+        }
+    }
+}
+
+// The full read layout of a resolved chemistry.
+#[derive(Debug, Serialize)]
+pub struct ChemistryGeometry {
+    pub name: String,
+    // the arguments that tell salmon alevin the chemistry
+    pub salmon_args: Vec<String>,
+    pub barcode_geometry: Option<String>,
+    pub umi_geometry: Option<String>,
+    pub read_geometry: Option<String>,
+    pub barcode_length: Option<u32>,
+    pub umi_length: Option<u32>,
+    pub expected_ori: String,
+}
+
+impl ChemistryGeometry {
+    // The same layout with the biological read at `read` instead, e.g.
+    // a feature barcode at an offset into read 2. Layouts only salmon
+    // knows are returned as they are.
+    pub fn with_read_geometry(mut self, read: &str) -> Self {
+        let (Some(bc), Some(umi)) = (&self.barcode_geometry, &self.umi_geometry) else {
+            return self;
+        };
+        self.salmon_args = vec![
+            String::from("--bc-geometry"),
+            bc.clone(),
+            String::from("--umi-geometry"),
+            umi.clone(),
+            String::from("--read-geometry"),
+            read.to_string(),
+        ];
+        self.read_geometry = Some(read.to_string());
+        self
+    }
+
+    // The number of bases read 1 needs to hold the barcode and
+    // UMI, or None if that isn't known.
+    pub fn required_r1_length(&self) -> Option<u32> {
+        let mut needed = None;
+        for g in [&self.barcode_geometry, &self.umi_geometry] {
+            let g = g.as_deref()?;
+            let (read, ranges) = g.split_once('[')?;
+            if read != "1" {
+                continue;
+            }
+            for r in ranges.strip_suffix(']')?.split(',') {
+                let end = r.split_once('-')?.1.parse::<u32>().ok()?;
+                needed = needed.max(Some(end));
+            }
+        }
+        needed
+    }
+
+    // The number of bases read 1 needs when it holds just the
+    // barcode and UMI, with the biological sequence on read 2,
+    // or None for any other layout (or if that isn't known).
+    pub fn barcode_only_r1_length(&self) -> Option<u32> {
+        let on_read = |g: &Option<String>, read: &str| {
+            g.as_deref()
+                .is_some_and(|g| g.split_once('[').is_some_and(|(r, _)| r == read))
+        };
+        if !(on_read(&self.barcode_geometry, "1")
+            && on_read(&self.umi_geometry, "1")
+            && on_read(&self.read_geometry, "2"))
+        {
+            return None;
+        }
+        self.required_r1_length()
+    }
+}
+
+// The total number of bases a salmon geometry such as `1[1-16]` or
+// `1[1-4,10-21]` selects, or None if a range runs to the end of the
+// read or the geometry can't be parsed.
+pub fn geometry_length(geom: &str) -> Option<u32> {
+    let ranges = geom.split_once('[')?.1.strip_suffix(']')?;
+    let mut len = 0;
+    for r in ranges.split(',') {
+        let (start, end) = r.split_once('-')?;
+        let (start, end) = (start.parse::<u32>().ok()?, end.parse::<u32>().ok()?);
+        if end < start {
+            return None;
+        }
+        len += end - start + 1;
+    }
+    Some(len)
+}
+
+// A chemistry described by a user-provided JSON file.
+// The geometry strings use the salmon custom geometry
+// syntax, e.g. `1[1-16]` or `2[1-end]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomChemistry {
+    pub barcode_geometry: String,
+    pub umi_geometry: String,
+    pub read_geometry: String,
+    // an optional local path or http(s) URL
+    // to the unfiltered permit list
+    #[serde(default)]
+    pub permit_list: Option<String>,
+    // optional defaults used when `--resolution`
+    // and `--expected-ori` are not given
+    #[serde(default)]
+    pub resolution: Option<String>,
+    #[serde(default)]
+    pub expected_ori: Option<String>,
+}
+
+impl CustomChemistry {
+    pub fn from_json_file(p: &Path) -> Result<Self> {
+        let f = std::fs::File::open(p)
+            .with_context(|| format!("could not open chemistry file {}", p.display()))?;
+        let cc: CustomChemistry = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| {
+                format!(
+                    "could not parse chemistry file {}; it must contain the keys \
+                    \"barcode_geometry\", \"umi_geometry\", \"read_geometry\" \
+                    and optionally \"permit_list\"",
+                    p.display()
+                )
+            })?;
+        validate_geometry("barcode_geometry", &cc.barcode_geometry)?;
+        validate_geometry("umi_geometry", &cc.umi_geometry)?;
+        validate_geometry("read_geometry", &cc.read_geometry)?;
+        if let Some(r) = &cc.resolution {
+            if !RESOLUTIONS.contains(&r.as_str()) {
+                bail!(
+                    "invalid \"resolution\" {:?} in {}; expected one of {:?}",
+                    r,
+                    p.display(),
+                    RESOLUTIONS
+                );
+            }
+        }
+        if let Some(o) = &cc.expected_ori {
+            if !EXPECTED_ORIS.contains(&o.as_str()) {
+                bail!(
+                    "invalid \"expected_ori\" {:?} in {}; expected one of {:?}",
+                    o,
+                    p.display(),
+                    EXPECTED_ORIS
+                );
+            }
+        }
+        if let Some(pl) = &cc.permit_list {
+            if pl.trim().is_empty() {
+                bail!("the \"permit_list\" entry in {} is empty", p.display());
+            }
+        }
+        Ok(cc)
+    }
+}
+
+// checks that `geo` is a sequence of segments of the form
+// `<read>[<start>-<end>(,<start>-<end>)*]`, where `read` is
+// 1 or 2, `start` is a positive integer and `end` is a
+// positive integer or `end`.
+fn validate_geometry(key: &str, geo: &str) -> Result<()> {
+    let malformed = || {
+        anyhow::anyhow!(
+            "malformed {} {:?}; expected a salmon geometry such as 1[1-16] or 2[1-end]",
+            key,
+            geo
+        )
+    };
+    let mut rest = geo.trim();
+    if rest.is_empty() {
+        return Err(malformed());
+    }
+    while !rest.is_empty() {
+        let tail = rest
+            .strip_prefix(['1', '2'])
+            .and_then(|t| t.strip_prefix('['))
+            .ok_or_else(malformed)?;
+        let close = tail.find(']').ok_or_else(malformed)?;
+        for range in tail[..close].split(',') {
+            let (start, end) = range.split_once('-').ok_or_else(malformed)?;
+            let start: usize = start.parse().map_err(|_| malformed())?;
+            if start == 0 {
+                return Err(malformed());
+            }
+            if end != "end" {
+                let end: usize = end.parse().map_err(|_| malformed())?;
+                if end < start {
+                    return Err(malformed());
+                }
+            }
+        }
+        rest = &tail[close + 1..];
+    }
+    Ok(())
+}
+
+// Optional tuning for salmon's sketch (pseudoalignment)
+// mapping mode; any option left as `None` is not passed.
+#[derive(Debug, Clone, Default)]
+pub struct SketchOpts {
+    pub hit_filter_policy: Option<String>,
+    pub max_read_occ: Option<u32>,
+}
+
+// The arguments that have salmon alevin write its mapping in
+// `format`. Formats alevin-fry can't read are rejected up front by
+// `check_mapping_output_format`, and any other than RAD are refused
+// here, since none of them can be consumed.
+pub fn mapping_output_args(format: &str) -> Result<&'static [&'static str]> {
+    match format {
+        // sketch mode writes a RAD file
+        "rad" => Ok(&["--sketch"]),
+        f => bail!(
+            "simpleaf can't have salmon write {} mapping output; only rad is supported",
+            f
+        ),
+    }
+}
+
+// Adds any requested tuning flags of the sketch mode to the salmon
+// mapping command. Flags the detected salmon version does not
+// support are skipped with a warning rather than passed along.
+pub fn add_sketch_args(
+    so: &SketchOpts,
+    salmon_version: &str,
+    cmd: &mut std::process::Command,
+    warnings: &mut RunWarnings,
+) -> Result<()> {
+    let ver = Version::parse(salmon_version)?;
+    // each flag with the salmon versions accepting it
+    let requested = [
+        ("--hitFilterPolicy", ">=1.4.0", so.hit_filter_policy.clone()),
+        (
+            "--maxReadOcc",
+            ">=1.0.0",
+            so.max_read_occ.map(|x| x.to_string()),
+        ),
+    ];
+    for (flag, req, val) in requested {
+        if let Some(v) = val {
+            if VersionReq::parse(req)?.matches(&ver) {
+                cmd.arg(flag).arg(v);
+            } else {
+                warnings.push(format!(
+                    "salmon {} does not support {} (requires {}); not passing it",
+                    ver, flag, req
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Optional tuning of alevin-fry collate's memory use; any
+// option left unset is not passed.
+#[derive(Debug, Clone, Default)]
+pub struct CollateOpts {
+    pub max_records: Option<u32>,
+    pub compress: bool,
+}
+
+// Adds the requested tuning flags to the collate command,
+// skipping (with a warning) those the detected alevin-fry
+// version does not support.
+pub fn add_collate_args(
+    co: &CollateOpts,
+    alevin_fry_version: &str,
+    cmd: &mut std::process::Command,
+    warnings: &mut RunWarnings,
+) -> Result<()> {
+    let ver = Version::parse(alevin_fry_version)?;
+    // each flag with the alevin-fry versions accepting it
+    let requested = [
+        (
+            "--max-records",
+            ">=0.4.0",
+            co.max_records.map(|x| x.to_string()),
+        ),
+        ("--compress", ">=0.4.0", co.compress.then(String::new)),
+    ];
+    for (flag, req, val) in requested {
+        if let Some(v) = val {
+            if VersionReq::parse(req)?.matches(&ver) {
+                cmd.arg(flag);
+                if !v.is_empty() {
+                    cmd.arg(v);
+                }
+            } else {
+                warnings.push(format!(
+                    "alevin-fry {} does not support collate {} (requires {}); not passing it",
+                    ver, flag, req
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The alevin-fry versions whose quant writes MTX by default and takes
+// --use-eds for EDS; earlier ones wrote EDS unless given --use-mtx.
+const MTX_DEFAULT_VERSIONS: &str = ">=0.5.0";
+
+// The alevin-fry quant arguments that write the count matrix in
+// `format` (eds or mtx), passed explicitly whenever the version has a
+// flag for it, so that the format recorded is the one written.
+pub fn output_format_args(format: &str, alevin_fry_version: &str) -> Result<Vec<&'static str>> {
+    let mtx_default =
+        VersionReq::parse(MTX_DEFAULT_VERSIONS)?.matches(&Version::parse(alevin_fry_version)?);
+    Ok(match (format, mtx_default) {
+        ("mtx", _) => vec!["--use-mtx"],
+        ("eds", true) => vec!["--use-eds"],
+        // EDS is the default, and there is no flag for it
+        ("eds", false) => Vec::new(),
+        (f, _) => bail!("unknown count matrix format {}", f),
+    })
+}
+
+// Optional tuning of alevin-fry quant's parsimony resolutions; any
+// option left unset is not passed.
+#[derive(Debug, Clone, Default)]
+pub struct ParsimonyOpts {
+    pub large_graph_thresh: Option<usize>,
+    pub pug_exact_umi: bool,
+}
+
+// The resolutions that correct UMI errors through a parsimony
+// graph, and those of them that build it per gene (the "pug" modes).
+fn is_parsimony(res: &str) -> bool {
+    res.starts_with("parsimony")
+}
+
+fn is_pug(res: &str) -> bool {
+    res.starts_with("parsimony-gene")
+}
+
+// Checks that the parsimony tuning requested applies to one of the
+// `resolutions` quantified, rather than being silently ignored.
+pub fn check_parsimony_opts(po: &ParsimonyOpts, resolutions: &[String]) -> Result<()> {
+    if po.large_graph_thresh.is_some() && !resolutions.iter().any(|r| is_parsimony(r)) {
+        bail!(
+            "--large-graph-thresh only applies to the parsimony resolutions, \
+            but the resolution is {}",
+            resolutions.join(",")
+        );
+    }
+    if po.pug_exact_umi && !resolutions.iter().any(|r| is_pug(r)) {
+        bail!(
+            "--pug-exact-umi only applies to the parsimony-gene and parsimony-gene-em \
+            resolutions, but the resolution is {}",
+            resolutions.join(",")
+        );
+    }
+    Ok(())
+}
+
+// Problems that don't stop the parsimony resolutions among
+// `resolutions` from running, but make their results (or their
+// run time) poor with this chemistry and cell filtering.
+pub fn parsimony_warnings(
+    resolutions: &[String],
+    geometry: &ChemistryGeometry,
+    filter_meth: &CellFilterMethod,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let Some(res) = resolutions.iter().find(|r| is_parsimony(r)) else {
+        return warnings;
+    };
+    // among short UMIs, many distinct molecules are one edit
+    // apart and get merged as if they were sequencing errors
+    if let Some(len) = geometry.umi_length.filter(|l| *l < 8) {
+        warnings.push(format!(
+            "chemistry {} has UMIs of only {} bases, so the {} resolution will merge many \
+            distinct molecules as UMI errors; consider cr-like instead",
+            geometry.name, len, res
+        ));
+    }
+    // the graph is built for every barcode quantified
+    if let CellFilterMethod::UnfilteredExternalList(..) = filter_meth {
+        warnings.push(format!(
+            "the {} resolution builds a UMI graph for each of the many barcodes kept by \
+            --unfiltered-pl, which can make quant very slow",
+            res
+        ));
+    }
+    warnings
+}
+
+// Adds the requested parsimony tuning flags that apply to
+// resolution `res` to its quant command, skipping (with a warning)
+// those the detected alevin-fry version does not support.
+pub fn add_parsimony_args(
+    po: &ParsimonyOpts,
+    res: &str,
+    alevin_fry_version: &str,
+    cmd: &mut std::process::Command,
+    warnings: &mut RunWarnings,
+) -> Result<()> {
+    let ver = Version::parse(alevin_fry_version)?;
+    // each flag with the alevin-fry versions accepting it
+    let requested = [
+        (
+            "--large-graph-thresh",
+            ">=0.4.1",
+            po.large_graph_thresh
+                .filter(|_| is_parsimony(res))
+                .map(|x| x.to_string()),
+        ),
+        (
+            "--pug-exact-umi",
+            ">=0.5.0",
+            (po.pug_exact_umi && is_pug(res)).then(String::new),
+        ),
+    ];
+    for (flag, req, val) in requested {
+        if let Some(v) = val {
+            if VersionReq::parse(req)?.matches(&ver) {
+                cmd.arg(flag);
+                if !v.is_empty() {
+                    cmd.arg(v);
+                }
+            } else {
+                warnings.push(format!(
+                    "alevin-fry {} does not support quant {} (requires {}); not passing it",
+                    ver, flag, req
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Adds `--summary-stat`, which has alevin-fry quant write extra
+// per-cell statistics, if the detected alevin-fry version supports
+// it, warning otherwise. Returns whether it was added.
+pub fn add_summary_stat_arg(
+    alevin_fry_version: &str,
+    cmd: &mut std::process::Command,
+    warnings: &mut RunWarnings,
+) -> Result<bool> {
+    let ver = Version::parse(alevin_fry_version)?;
+    let req = ">=0.4.1";
+    if !VersionReq::parse(req)?.matches(&ver) {
+        warnings.push(format!(
+            "alevin-fry {} does not support quant --summary-stat (requires {}); not passing it",
+            ver, req
+        ));
+        return Ok(false);
+    }
+    cmd.arg("--summary-stat");
+    Ok(true)
+}
+
+// The names 10x Genomics uses for its chemistries in sample sheets
+// and multi config files, with the chemistry simpleaf knows them as.
+const TENX_CHEMISTRY_NAMES: [(&str, &str); 4] = [
+    ("SC3Pv2", "10xv2"),
+    ("SC3Pv3", "10xv3"),
+    ("SC3Pv3HT", "10xv3"),
+    ("SC3Pv3LT", "10xv3"),
+];
+
+// The chemistry name simpleaf uses for the 10x name `c`, such
+// as 10xv3 for SC3Pv3, or `c` itself if it isn't one.
+pub fn tenx_chemistry_name(c: &str) -> &str {
+    TENX_CHEMISTRY_NAMES
+        .iter()
+        .find(|(tenx, _)| tenx.eq_ignore_ascii_case(c))
+        .map_or(c, |(_, ours)| *ours)
+}
+
+// Infers the chemistry from a sample sheet or run metadata CSV,
+// either from a `chemistry` column or from a `chemistry,<value>`
+// line as found in cellranger multi config files. Returns `None`
+// if no single chemistry can be inferred.
+pub fn chemistry_from_sample_sheet(p: &Path) -> Result<Option<String>> {
+    let text = std::fs::read_to_string(p)
+        .with_context(|| format!("could not read sample sheet {}", p.display()))?;
+    let rows = text
+        .lines()
+        .map(|l| l.split(',').map(str::trim).collect::<Vec<&str>>())
+        .collect::<Vec<Vec<&str>>>();
+
+    let mut found = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        match row.iter().position(|c| c.eq_ignore_ascii_case("chemistry")) {
+            // a key-value line
+            Some(0) if row.len() == 2 => found.push(row[1]),
+            // a header row; the values are in the rows below
+            Some(col) => found.extend(
+                rows[i + 1..]
+                    .iter()
+                    .take_while(|r| !r.first().is_some_and(|c| c.starts_with('[')))
+                    .filter_map(|r| r.get(col).copied()),
+            ),
+            None => {}
+        }
+    }
+    found.retain(|c| !c.is_empty());
+    found.sort_unstable();
+    found.dedup();
+
+    let names = found
+        .iter()
+        .map(|c| tenx_chemistry_name(c))
+        .collect::<std::collections::BTreeSet<&str>>();
+    match names.len() {
+        1 => Ok(names.into_iter().next().map(String::from)),
+        0 => Ok(None),
+        _ => bail!(
+            "sample sheet {} lists more than one chemistry ({:?}); please provide --chemistry",
+            p.display(),
+            names
+        ),
+    }
+}
+
+// A named preset of quant parameters; each field a run's own
+// flags leave unset is taken from the profile.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuantProfile {
+    pub chemistry: Option<String>,
+    // `knee` or `unfiltered-pl`
+    pub filter: Option<String>,
+    pub resolution: Option<Vec<String>>,
+    pub expected_ori: Option<String>,
+}
+
+// The file in $ALEVIN_FRY_HOME defining user profiles, as a JSON
+// object mapping profile names to `QuantProfile`s.
+pub const PROFILES_FILE: &str = "profiles.json";
+
+// The built-in profiles for the common protocols.
+fn builtin_profile(name: &str) -> Option<QuantProfile> {
+    let chemistry = match name {
+        "10x-3prime-v2-standard" => "10xv2",
+        "10x-3prime-v3-standard" => "10xv3",
+        _ => return None,
+    };
+    Some(QuantProfile {
+        chemistry: Some(chemistry.to_string()),
+        filter: Some(String::from("unfiltered-pl")),
+        resolution: Some(vec![String::from("cr-like")]),
+        expected_ori: Some(String::from("fw")),
+    })
+}
+
+// Looks up the profile `name`, preferring one defined in the
+// profiles file of `af_home` over a built-in one.
+pub fn load_profile(name: &str, af_home: &Path) -> Result<QuantProfile> {
+    let p = af_home.join(PROFILES_FILE);
+    let mut user: std::collections::HashMap<String, QuantProfile> = if p.is_file() {
+        let f =
+            std::fs::File::open(&p).with_context(|| format!("could not open {}", p.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("could not parse {}", p.display()))?
+    } else {
+        Default::default()
+    };
+    let profile = match user.remove(name).or_else(|| builtin_profile(name)) {
+        Some(pr) => pr,
+        None => bail!(
+            "unknown profile {:?}; the built-in profiles are 10x-3prime-v2-standard and \
+            10x-3prime-v3-standard, and others can be defined in {}",
+            name,
+            p.display()
+        ),
+    };
+    if let Some(ref f) = profile.filter {
+        if !["knee", "unfiltered-pl"].contains(&f.as_str()) {
+            bail!(
+                "profile {} has filter {:?}; expected knee or unfiltered-pl",
+                name,
+                f
+            );
+        }
+    }
+    for r in profile.resolution.iter().flatten() {
+        if !RESOLUTIONS.contains(&r.as_str()) {
+            bail!(
+                "profile {} has unknown resolution {:?}; expected one of {:?}",
+                name,
+                r,
+                RESOLUTIONS
+            );
+        }
+    }
+    if let Some(ref o) = profile.expected_ori {
+        if !EXPECTED_ORIS.contains(&o.as_str()) {
+            bail!(
+                "profile {} has expected orientation {:?}; expected one of {:?}",
+                name,
+                o,
+                EXPECTED_ORIS
+            );
+        }
+    }
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_args(fm: &CellFilterMethod) -> Vec<String> {
+        let mut cmd = std::process::Command::new("alevin-fry");
+        add_to_args(fm, &mut cmd);
+        cmd.get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn knee_filtering_passes_only_knee() {
+        assert_eq!(filter_args(&CellFilterMethod::KneeFinding), vec!["--knee"]);
+    }
+
+    #[test]
+    fn unfiltered_list_passes_its_min_reads() {
+        let fm = CellFilterMethod::UnfilteredExternalList(PathBuf::from("pl.txt"), 10);
+        assert_eq!(
+            filter_args(&fm),
+            vec!["--unfiltered-pl", "pl.txt", "--min-reads", "10"]
+        );
+    }
+}
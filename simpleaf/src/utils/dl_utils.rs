@@ -0,0 +1,341 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cmd_lib::run_fun;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::utils::run_utils::{run_stage, StageOpts};
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+// Downloads `url` to `dest` using wget, following redirects.
+pub fn download_file(url: &str, dest: &Path, stage_opts: &StageOpts) -> Result<()> {
+    let mut dl_cmd = std::process::Command::new("wget");
+    dl_cmd.arg("-v").arg("-O").arg(dest).arg("-L").arg(url);
+    let r = run_stage(&mut dl_cmd, stage_opts)?;
+    if !r.status.success() {
+        return Err(anyhow!("failed to download {} {:?}", url, r.status));
+    }
+    Ok(())
+}
+
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+// The temporary file `dest` is downloaded to, so that an
+// interrupted download never leaves a truncated `dest` behind.
+pub fn partial_path(dest: &Path) -> PathBuf {
+    let mut p = dest.as_os_str().to_owned();
+    p.push(PARTIAL_SUFFIX);
+    PathBuf::from(p)
+}
+
+// Removes the `.partial` files in `dir` left by interrupted
+// downloads and returns how many were removed. Files written to
+// in the last `max_age` may belong to a download still in
+// progress in another run and are kept.
+pub fn remove_stale_partials(dir: &Path, max_age: std::time::Duration) -> Result<usize> {
+    let mut removed = 0;
+    if !dir.is_dir() {
+        return Ok(removed);
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let p = entry?.path();
+        if !p.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+        let stale = std::fs::metadata(&p)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_none_or(|age| age > max_age);
+        if stale {
+            std::fs::remove_file(&p)
+                .with_context(|| format!("could not remove {}", p.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Decompresses `p` in place if it is gzip-compressed, as detected
+// from its magic bytes rather than its name, and returns whether
+// it was.
+pub fn gunzip_in_place(p: &Path) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    let n = std::fs::File::open(p)
+        .and_then(|mut f| f.read(&mut magic))
+        .with_context(|| format!("could not read {}", p.display()))?;
+    if n < 2 || magic != [0x1f, 0x8b] {
+        return Ok(false);
+    }
+
+    let mut gz = p.as_os_str().to_owned();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    std::fs::rename(p, &gz)?;
+    let r = std::process::Command::new("gzip")
+        .arg("-d")
+        .arg("-f")
+        .arg(&gz)
+        .output()?;
+    if !r.status.success() {
+        bail!(
+            "failed to decompress {} : {}",
+            p.display(),
+            String::from_utf8_lossy(&r.stderr)
+        );
+    }
+    Ok(true)
+}
+
+// Returns a stable, filesystem-safe key for `url`.
+pub fn url_cache_key(url: &str) -> String {
+    let digest = Sha256::digest(url.as_bytes());
+    format!("{:x}", digest)[..16].to_string()
+}
+
+// Lists the members of the `.tar.gz` archive at `tarball` and fails
+// if any would be extracted outside of the target directory, i.e.
+// has an absolute path or a `..` component.
+fn check_archive_members(tarball: &Path, url: &str) -> Result<()> {
+    let r = std::process::Command::new("tar")
+        .arg("-tzf")
+        .arg(tarball)
+        .output()?;
+    if !r.status.success() {
+        bail!(
+            "failed to list the archive downloaded from {} : {}",
+            url,
+            String::from_utf8_lossy(&r.stderr)
+        );
+    }
+    let listing = String::from_utf8_lossy(&r.stdout);
+    if let Some(m) = listing
+        .lines()
+        .find(|m| m.starts_with('/') || m.split('/').any(|c| c == ".."))
+    {
+        bail!(
+            "the archive downloaded from {} has the member {:?}, which would be \
+            extracted outside of the index directory; refusing to extract it",
+            url,
+            m
+        );
+    }
+    Ok(())
+}
+
+// Downloads the `.tar.gz` index at `url` into a cache directory
+// under `af_home`, keyed by the hash of the URL, extracts it and
+// returns the path of the extracted index. If `sha256` is given, the
+// archive must have that checksum. A previously completed extraction
+// of the same URL is reused without re-downloading, as long as it
+// was of an archive with that checksum.
+pub fn fetch_remote_index(
+    url: &str,
+    sha256: Option<&str>,
+    af_home: &Path,
+    stage_opts: &StageOpts,
+) -> Result<PathBuf> {
+    let cache_dir = af_home.join("index_cache").join(url_cache_key(url));
+    let extract_dir = cache_dir.join("index");
+    // holds the URL and the checksum of the extracted archive
+    let complete_marker = cache_dir.join(".complete");
+
+    let cached_digest = std::fs::read_to_string(&complete_marker)
+        .ok()
+        .map(|m| m.lines().nth(1).unwrap_or("").to_string());
+    let cache_ok = match (&cached_digest, sha256) {
+        (Some(d), Some(expected)) => d.eq_ignore_ascii_case(expected.trim()),
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    if cache_ok {
+        info!("using cached index for {} at {}", url, cache_dir.display());
+    } else {
+        if cached_digest.is_some() {
+            info!(
+                "the cached index for {} is of an archive with a different checksum; \
+                downloading it again",
+                url
+            );
+        }
+        // clear out any partial attempt from a previous run
+        if cache_dir.exists() {
+            std::fs::remove_dir_all(&cache_dir)
+                .with_context(|| format!("could not clear {}", cache_dir.display()))?;
+        }
+        run_fun!(mkdir -p $extract_dir)?;
+
+        let tarball = cache_dir.join("index.tar.gz");
+        info!("downloading index from {}", url);
+        download_file(url, &tarball, stage_opts)?;
+        let digest = sha256_file(&tarball)?;
+        if let Some(expected) = sha256 {
+            if !digest.eq_ignore_ascii_case(expected.trim()) {
+                bail!(
+                    "the index archive from {} has SHA-256 {}, but {} was expected; \
+                    refusing to extract it",
+                    url,
+                    digest,
+                    expected
+                );
+            }
+        }
+        check_archive_members(&tarball, url)?;
+
+        let mut tar_cmd = std::process::Command::new("tar");
+        tar_cmd
+            .arg("-xzf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(&extract_dir);
+        let r = tar_cmd.output()?;
+        if !r.status.success() {
+            bail!(
+                "failed to extract index archive downloaded from {} : {}",
+                url,
+                String::from_utf8_lossy(&r.stderr)
+            );
+        }
+        std::fs::remove_file(&tarball)?;
+        std::fs::write(&complete_marker, format!("{}\n{}\n", url, digest))?;
+    }
+
+    // descend into the archive's top-level directory
+    // if it wraps the index in one.
+    let entries = std::fs::read_dir(&extract_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    if entries.len() == 1 && entries[0].path().is_dir() {
+        Ok(entries[0].path())
+    } else {
+        Ok(extract_dir)
+    }
+}
+
+// The SHA-256 digest of the file at `p`, as lowercase hex.
+pub fn sha256_file(p: &Path) -> Result<String> {
+    let mut f =
+        std::fs::File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Where to download one of the backing programs from, as listed in
+// a `simpleaf setup` manifest.
+#[derive(Debug, Deserialize)]
+pub struct ToolDownload {
+    pub url: String,
+    pub sha256: String,
+    // the path of the executable inside the `.tar.gz` archive at
+    // `url`; if absent, `url` is the executable itself
+    pub executable: Option<String>,
+}
+
+// The platform key under which a setup manifest lists the downloads
+// for this machine, e.g. `linux-x86_64`.
+pub fn platform_key() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+// Downloads `tool` as described by `td` into `bin_dir/<tool>`,
+// verifying its checksum, and returns the path of its executable.
+// The download is prepared in a sibling directory that only
+// replaces an existing installation once it has been verified,
+// so a failed download leaves the previous one in place.
+pub fn install_tool(
+    tool: &str,
+    td: &ToolDownload,
+    bin_dir: &Path,
+    stage_opts: &StageOpts,
+) -> Result<PathBuf> {
+    let tool_dir = bin_dir.join(tool);
+    let staging_dir = partial_path(&tool_dir);
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)
+            .with_context(|| format!("could not clear {}", staging_dir.display()))?;
+    }
+    run_fun!(mkdir -p $staging_dir)?;
+
+    let rel_exe = match fetch_tool(tool, td, &staging_dir, stage_opts) {
+        Ok(rel_exe) => rel_exe,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging_dir);
+            return Err(e);
+        }
+    };
+
+    if tool_dir.exists() {
+        std::fs::remove_dir_all(&tool_dir)
+            .with_context(|| format!("could not clear {}", tool_dir.display()))?;
+    }
+    std::fs::rename(&staging_dir, &tool_dir).with_context(|| {
+        format!(
+            "could not move {} to {}",
+            staging_dir.display(),
+            tool_dir.display()
+        )
+    })?;
+    Ok(tool_dir.join(rel_exe))
+}
+
+// Downloads and verifies `tool` into `dir`, returning the path
+// of its executable relative to `dir`.
+fn fetch_tool(
+    tool: &str,
+    td: &ToolDownload,
+    dir: &Path,
+    stage_opts: &StageOpts,
+) -> Result<PathBuf> {
+    let download = dir.join("download");
+    info!("downloading {} from {}", tool, td.url);
+    download_file(&td.url, &download, stage_opts)?;
+    let digest = sha256_file(&download)?;
+    if !digest.eq_ignore_ascii_case(td.sha256.trim()) {
+        bail!(
+            "the {} download from {} has SHA-256 {}, but {} was expected; \
+            refusing to install it",
+            tool,
+            td.url,
+            digest,
+            td.sha256
+        );
+    }
+
+    let rel_exe = match td.executable {
+        Some(ref rel) => {
+            let mut tar_cmd = std::process::Command::new("tar");
+            tar_cmd.arg("-xzf").arg(&download).arg("-C").arg(dir);
+            let r = tar_cmd.output()?;
+            if !r.status.success() {
+                bail!(
+                    "failed to extract the {} archive downloaded from {} : {}",
+                    tool,
+                    td.url,
+                    String::from_utf8_lossy(&r.stderr)
+                );
+            }
+            std::fs::remove_file(&download)?;
+            if !dir.join(rel).is_file() {
+                bail!(
+                    "the {} archive from {} has no executable {}",
+                    tool,
+                    td.url,
+                    rel
+                );
+            }
+            PathBuf::from(rel)
+        }
+        None => {
+            std::fs::rename(&download, dir.join(tool))?;
+            PathBuf::from(tool)
+        }
+    };
+    let exe = dir.join(&rel_exe);
+    std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("could not make {} executable", exe.display()))?;
+    Ok(rel_exe)
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::utils::fs_utils::path_size;
+use crate::utils::run_utils::{format_bytes, format_duration};
+use crate::utils::sample_utils::{is_gzipped, mean_record_bytes, SampleReads};
+
+// The constants of the (deliberately simple) models behind
+// `--estimate`. They are rough fits to typical runs on human data
+// and only meant to get the order of magnitude right.
+
+// How much larger the uncompressed text of a gzipped FASTA/FASTQ is.
+const GZIP_RATIO: f64 = 3.5;
+// The size of a splici reference relative to the genome it is built from.
+const SPLICI_GENOME_FRACTION: f64 = 0.5;
+// The bytes pyroe make-splici reads per second.
+const SPLICI_BYTES_PER_SEC: f64 = 50e6;
+// The seconds salmon index spends per MB of reference, serially
+// and split among the threads.
+const INDEX_SERIAL_SECS_PER_MB: f64 = 1.5;
+const INDEX_PARALLEL_SECS_PER_MB: f64 = 30.0;
+// The index size and peak memory of salmon index per reference byte,
+// for a dense and a sparse index.
+const INDEX_DISK_PER_REF_BYTE: (f64, f64) = (8.0, 4.0);
+const INDEX_MEMORY_PER_REF_BYTE: (f64, f64) = (12.0, 8.0);
+// The read pairs salmon maps, and that the alevin-fry stages
+// process, per second and thread.
+const MAP_PAIRS_PER_SEC_PER_THREAD: f64 = 40e3;
+const AF_PAIRS_PER_SEC_PER_THREAD: f64 = 400e3;
+// The bytes of the mapping, and again of the collated mapping, per read pair.
+const RAD_BYTES_PER_PAIR: f64 = 40.0;
+// The memory salmon alevin needs beyond the loaded index, per thread.
+const MAP_MEMORY_PER_THREAD: f64 = 256.0 * 1024.0 * 1024.0;
+// The count matrices and everything else simpleaf writes.
+const QUANT_FIXED_DISK: f64 = 200.0 * 1024.0 * 1024.0;
+// The reads sampled from each file to estimate the size of a record.
+const SAMPLED_RECORDS: usize = 1000;
+
+// A rough estimate of the resources a run needs.
+#[derive(Debug)]
+pub struct Estimate {
+    // what the estimate is based on
+    pub basis: String,
+    pub runtime: Duration,
+    pub peak_memory: u64,
+    pub disk: u64,
+}
+
+impl Estimate {
+    pub fn print(&self) {
+        println!("rough estimate, based on {}:", self.basis);
+        println!("  runtime      ~{}", format_duration(self.runtime));
+        println!("  peak memory  ~{}", format_bytes(self.peak_memory));
+        println!("  output disk  ~{}", format_bytes(self.disk));
+        println!("(order-of-magnitude guidance only; actual use varies with the data)");
+    }
+}
+
+// The size of the text in `p`, whether it is gzipped or not.
+fn uncompressed_size(p: &Path) -> Result<f64> {
+    let size = std::fs::metadata(p)
+        .with_context(|| format!("could not stat {}", p.display()))?
+        .len() as f64;
+    Ok(if is_gzipped(p) {
+        size * GZIP_RATIO
+    } else {
+        size
+    })
+}
+
+// Estimates building an index from `genome` (made into a splici
+// reference first) or from the ready-made reference `transcripts`,
+// plus any `decoy` sequences.
+pub fn estimate_index(
+    genome: Option<&PathBuf>,
+    transcripts: Option<&PathBuf>,
+    decoy: Option<&PathBuf>,
+    sparse: bool,
+    threads: u32,
+) -> Result<Estimate> {
+    let mut ref_bytes = 0.0;
+    let mut secs = 0.0;
+    if let Some(t) = transcripts {
+        ref_bytes += uncompressed_size(t)?;
+    } else if let Some(g) = genome {
+        let genome_bytes = uncompressed_size(g)?;
+        ref_bytes += genome_bytes * SPLICI_GENOME_FRACTION;
+        secs += genome_bytes / SPLICI_BYTES_PER_SEC;
+    }
+    if let Some(d) = decoy {
+        ref_bytes += uncompressed_size(d)?;
+    }
+    let ref_mb = ref_bytes / 1e6;
+    secs +=
+        ref_mb * (INDEX_SERIAL_SECS_PER_MB + INDEX_PARALLEL_SECS_PER_MB / threads.max(1) as f64);
+    let pick = |(dense, sparse_v): (f64, f64)| if sparse { sparse_v } else { dense };
+    Ok(Estimate {
+        basis: format!(
+            "a reference of about {} to index",
+            format_bytes(ref_bytes as u64)
+        ),
+        runtime: Duration::from_secs_f64(secs),
+        peak_memory: (ref_bytes * pick(INDEX_MEMORY_PER_REF_BYTE)) as u64,
+        // the reference itself is kept next to the index
+        disk: (ref_bytes * (1.0 + pick(INDEX_DISK_PER_REF_BYTE))) as u64,
+    })
+}
+
+// The number of read pairs of `sample`, from the sizes of its R1
+// files and the size of their first records.
+fn estimated_read_pairs(sample: &SampleReads) -> Result<f64> {
+    let mut pairs = 0.0;
+    for r1 in &sample.reads1 {
+        if let Some(rec) = mean_record_bytes(r1, SAMPLED_RECORDS)? {
+            pairs += uncompressed_size(r1)? / rec;
+        }
+    }
+    Ok(pairs)
+}
+
+// Estimates quantifying `samples` one after another against the
+// salmon index `index`, each with `threads` threads.
+pub fn estimate_quant(samples: &[&SampleReads], index: &Path, threads: u32) -> Result<Estimate> {
+    let mut pairs = 0.0;
+    for s in samples {
+        pairs += estimated_read_pairs(s)?;
+    }
+    let threads = threads.max(1) as f64;
+    let secs = pairs / (MAP_PAIRS_PER_SEC_PER_THREAD * threads)
+        + pairs / (AF_PAIRS_PER_SEC_PER_THREAD * threads);
+    // salmon holds the whole index in memory while mapping
+    let index_bytes = path_size(index).unwrap_or(0) as f64;
+    Ok(Estimate {
+        basis: format!(
+            "about {:.1} million read pairs in {} sample(s)",
+            pairs / 1e6,
+            samples.len()
+        ),
+        runtime: Duration::from_secs_f64(secs),
+        peak_memory: (index_bytes * 1.1 + MAP_MEMORY_PER_THREAD * threads) as u64,
+        disk: (pairs * 2.0 * RAD_BYTES_PER_PAIR + QUANT_FIXED_DISK * samples.len() as f64) as u64,
+    })
+}
@@ -0,0 +1,680 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE_NAME: &str = ".simpleaf.lock";
+
+// Guards an output directory against concurrent
+// simpleaf runs. The lock file is created when the
+// guard is acquired and removed when it is dropped,
+// so it is released on both success and error paths.
+#[derive(Debug)]
+pub struct OutputLock {
+    path: PathBuf,
+}
+
+impl OutputLock {
+    pub fn acquire(output: &Path) -> Result<Self> {
+        let path = output.join(LOCK_FILE_NAME);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut f) => {
+                // record who holds the lock to help with
+                // diagnosing a stale lock after a crash.
+                writeln!(f, "{}", std::process::id())
+                    .with_context(|| format!("could not write lock file {}", path.display()))?;
+                Ok(Self { path })
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                bail!(
+                    "another simpleaf run is in progress here (lock file {} exists); \
+                    if no other run is active, remove this file and try again.",
+                    path.display()
+                );
+            }
+            Err(e) => {
+                Err(e).with_context(|| format!("could not create lock file {}", path.display()))
+            }
+        }
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("could not remove lock file {} : {}", self.path.display(), e);
+        }
+    }
+}
+
+// Makes everything under `path` group-writable when dropped, so
+// that members of a shared lab group can clean up or rerun a run's
+// output whether the run succeeded or failed.
+#[derive(Debug)]
+pub struct GroupWritable {
+    path: PathBuf,
+}
+
+impl GroupWritable {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Drop for GroupWritable {
+    fn drop(&mut self) {
+        if let Err(e) = make_group_writable(&self.path) {
+            warn!(
+                "could not make {} group-writable : {:#}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Adds group read and write permission to `p` and everything
+// beneath it, and group search permission to directories.
+// Symbolic links are left alone.
+pub fn make_group_writable(p: &Path) -> Result<()> {
+    let md = std::fs::symlink_metadata(p)
+        .with_context(|| format!("could not read the metadata of {}", p.display()))?;
+    if md.file_type().is_symlink() {
+        return Ok(());
+    }
+    let extra = if md.is_dir() { 0o070 } else { 0o060 };
+    let mode = md.permissions().mode();
+    if mode & extra != extra {
+        std::fs::set_permissions(p, std::fs::Permissions::from_mode(mode | extra))
+            .with_context(|| format!("could not change the permissions of {}", p.display()))?;
+    }
+    if md.is_dir() {
+        for entry in std::fs::read_dir(p)? {
+            make_group_writable(&entry?.path())?;
+        }
+    }
+    Ok(())
+}
+
+// The stages of `quant` that leave a completion marker
+// (`output/.<stage>.done`) behind once they succeed.
+pub const QUANT_STAGES: [&str; 4] = ["map", "permit", "collate", "quant"];
+
+pub fn stage_marker_path(output: &Path, stage: &str) -> PathBuf {
+    output.join(format!(".{}.done", stage))
+}
+
+// Writes the (empty) completion marker for `stage`.
+pub fn mark_stage_done(output: &Path, stage: &str) -> Result<()> {
+    let marker = stage_marker_path(output, stage);
+    std::fs::write(&marker, "")
+        .with_context(|| format!("could not write stage marker {}", marker.display()))
+}
+
+// Removes the completion markers left by a previous run,
+// since every stage is about to be recomputed.
+pub fn clear_stage_markers(output: &Path) -> Result<()> {
+    for stage in QUANT_STAGES {
+        let marker = stage_marker_path(output, stage);
+        if marker.exists() {
+            std::fs::remove_file(&marker)
+                .with_context(|| format!("could not remove stage marker {}", marker.display()))?;
+        }
+    }
+    Ok(())
+}
+
+// The file in a mapping output recording the map.rad its mapping
+// wrote, and in a generate-permit-list output recording which
+// mapping output it was generated from.
+pub const MAP_ID_FILE: &str = "simpleaf_map_id.txt";
+
+// Identifies the `map.rad` of the mapping output `map_dir` by its
+// size, modification time and a digest of its first 64 KiB, which
+// is enough to tell apart the outputs of different mapping runs.
+pub fn rad_identity(map_dir: &Path) -> Result<String> {
+    let rad = map_dir.join("map.rad");
+    let md =
+        std::fs::metadata(&rad).with_context(|| format!("could not stat {}", rad.display()))?;
+    let mtime = md
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let mut head = Vec::new();
+    std::fs::File::open(&rad)
+        .and_then(|f| std::io::Read::read_to_end(&mut std::io::Read::take(f, 64 * 1024), &mut head))
+        .with_context(|| format!("could not read {}", rad.display()))?;
+    Ok(format!(
+        "{}\t{}\t{:x}",
+        md.len(),
+        mtime,
+        Sha256::digest(&head)
+    ))
+}
+
+// Records in `dir` the identity of the map.rad of `map_dir`.
+pub fn record_map_identity(dir: &Path, map_dir: &Path) -> Result<()> {
+    let p = dir.join(MAP_ID_FILE);
+    std::fs::write(&p, rad_identity(map_dir)? + "\n")
+        .with_context(|| format!("could not write {}", p.display()))
+}
+
+// Checks that the permit list in `gpl_dir` was generated from the
+// mapping output `map_dir`, since collating the records of one
+// mapping against the permit list of another gives wrong counts.
+pub fn check_map_identity(gpl_dir: &Path, map_dir: &Path) -> Result<()> {
+    let p = gpl_dir.join(MAP_ID_FILE);
+    let recorded =
+        std::fs::read_to_string(&p).with_context(|| format!("could not read {}", p.display()))?;
+    if recorded.trim_end() != rad_identity(map_dir)? {
+        bail!(
+            "the permit list in {} was not generated from the mapping output in {} \
+            (its map.rad has changed since); rerun quant from the start",
+            gpl_dir.display(),
+            map_dir.display()
+        );
+    }
+    Ok(())
+}
+
+// Checks that the map.rad of the mapping output `map_dir`, which is
+// being reused, is still the one simpleaf's mapping wrote there, if it
+// recorded that. Only the size and content digest are compared, as
+// copying the directory elsewhere changes the modification time.
+pub fn check_reused_mapping(map_dir: &Path) -> Result<()> {
+    let p = map_dir.join(MAP_ID_FILE);
+    if !p.is_file() {
+        return Ok(());
+    }
+    let recorded =
+        std::fs::read_to_string(&p).with_context(|| format!("could not read {}", p.display()))?;
+    let size_and_digest = |id: &str| {
+        let fields = id
+            .trim_end()
+            .split('\t')
+            .map(String::from)
+            .collect::<Vec<String>>();
+        (fields.first().cloned(), fields.get(2).cloned())
+    };
+    let current = rad_identity(map_dir)?;
+    if size_and_digest(&recorded) != size_and_digest(&current) {
+        bail!(
+            "the map.rad in {} is not the one its mapping wrote (it has been modified or \
+            overwritten since); map the reads again",
+            map_dir.display()
+        );
+    }
+    Ok(())
+}
+
+// The file listing every file a run produced.
+pub const RUN_MANIFEST_FILE: &str = "manifest.json";
+
+// One file of a run manifest, with its path relative to the run
+// directory.
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+fn collect_manifest_entries(
+    root: &Path,
+    dir: &Path,
+    hash: bool,
+    entries: &mut Vec<ManifestEntry>,
+) -> Result<()> {
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("could not list {}", dir.display()))?
+    {
+        let p = entry?.path();
+        if p.is_dir() {
+            collect_manifest_entries(root, &p, hash, entries)?;
+            continue;
+        }
+        let rel = p.strip_prefix(root).unwrap_or(&p);
+        // neither the lock nor the manifest itself is a result
+        if dir == root
+            && [LOCK_FILE_NAME, RUN_MANIFEST_FILE]
+                .map(Path::new)
+                .contains(&rel)
+        {
+            continue;
+        }
+        let sha256 = if hash {
+            let mut f = std::fs::File::open(&p)
+                .with_context(|| format!("could not open {}", p.display()))?;
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut f, &mut hasher)
+                .with_context(|| format!("could not read {}", p.display()))?;
+            Some(format!("{:x}", hasher.finalize()))
+        } else {
+            None
+        };
+        entries.push(ManifestEntry {
+            path: rel.to_string_lossy().into_owned(),
+            size: std::fs::metadata(&p)?.len(),
+            sha256,
+        });
+    }
+    Ok(())
+}
+
+// Writes `dir/manifest.json`, listing every file under `dir` with
+// its size and, if `hash` is set, its SHA-256, so that a copy of
+// the results can be checked to be complete. Returns the number of
+// files listed.
+pub fn write_run_manifest(dir: &Path, hash: bool) -> Result<usize> {
+    let mut entries = Vec::new();
+    collect_manifest_entries(dir, dir, hash, &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    let manifest = serde_json::json!({
+        "simpleaf_version" : env!("CARGO_PKG_VERSION"),
+        "total_size" : entries.iter().map(|e| e.size).sum::<u64>(),
+        "files" : entries,
+    });
+    let p = dir.join(RUN_MANIFEST_FILE);
+    std::fs::write(&p, serde_json::to_string_pretty(&manifest)?)
+        .with_context(|| format!("could not write {}", p.display()))?;
+    Ok(entries.len())
+}
+
+// The files simpleaf writes to record how a run was produced.
+pub const PROVENANCE_FILES: [&str; 3] = [
+    "index_info.json",
+    "simpleaf_index_log.json",
+    "simpleaf_quant_log.json",
+];
+
+pub fn has_provenance(dir: &Path) -> bool {
+    PROVENANCE_FILES.iter().any(|f| dir.join(f).is_file())
+}
+
+// The total size in bytes of `p` and, if it is a
+// directory, everything beneath it.
+pub fn path_size(p: &Path) -> Result<u64> {
+    let md = std::fs::symlink_metadata(p)?;
+    if md.is_dir() {
+        let mut total = 0;
+        for entry in std::fs::read_dir(p)? {
+            total += path_size(&entry?.path())?;
+        }
+        Ok(total)
+    } else {
+        Ok(md.len())
+    }
+}
+
+fn remove_path(p: &Path) -> Result<u64> {
+    let size = path_size(p)?;
+    if std::fs::symlink_metadata(p)?.is_dir() {
+        std::fs::remove_dir_all(p)
+    } else {
+        std::fs::remove_file(p)
+    }
+    .with_context(|| format!("could not remove {}", p.display()))?;
+    Ok(size)
+}
+
+// The entries of an index and of a quant output directory that are
+// only needed while the run is in progress.
+pub const INDEX_INTERMEDIATES: &[&str] = &["ref"];
+pub const QUANT_INTERMEDIATES: &[&str] = &["af_map", "downsampled_reads"];
+
+fn remove_intermediates(dir: &Path, subs: &[&str]) -> Result<u64> {
+    let mut reclaimed = 0;
+    for sub in subs {
+        let p = dir.join(sub);
+        if p.exists() {
+            reclaimed += remove_path(&p)?;
+        }
+    }
+    // the mapping output is gone, so its stage
+    // can no longer be considered complete.
+    let marker = stage_marker_path(dir, "map");
+    if subs.contains(&"af_map") && marker.exists() {
+        remove_path(&marker)?;
+    }
+    Ok(reclaimed)
+}
+
+// Called with the error `e` of a run in `dir` that failed: the
+// intermediates `subs` it left behind are kept and named in the
+// error, so users know where to look, or removed if `keep` is unset.
+pub fn with_failed_intermediates(
+    e: anyhow::Error,
+    dir: &Path,
+    subs: &[&str],
+    keep: bool,
+) -> anyhow::Error {
+    let present: Vec<PathBuf> = subs
+        .iter()
+        .map(|s| dir.join(s))
+        .filter(|p| p.exists())
+        .collect();
+    if present.is_empty() {
+        return e;
+    }
+    if !keep {
+        if let Err(re) = remove_intermediates(dir, subs) {
+            warn!(
+                "could not remove the intermediates of the failed run: {:#}",
+                re
+            );
+        }
+        return e;
+    }
+    let kept: Vec<String> = present.iter().map(|p| p.display().to_string()).collect();
+    e.context(format!(
+        "the run failed; its intermediate files were kept for debugging in {}",
+        kept.join(", ")
+    ))
+}
+
+// The file at the root of the output of a manifest quant run
+// listing the samples it is to quantify, one subdirectory each.
+pub const SAMPLE_LIST_FILE: &str = "simpleaf_samples.json";
+
+// Writes the names of the manifest samples about to be quantified
+// into `output`, so that `check` can tell which ones never ran.
+pub fn write_sample_list(output: &Path, names: &[&str]) -> Result<()> {
+    std::fs::create_dir_all(output)
+        .with_context(|| format!("could not create {}", output.display()))?;
+    let p = output.join(SAMPLE_LIST_FILE);
+    let list = serde_json::json!({ "samples" : names });
+    std::fs::write(&p, serde_json::to_string_pretty(&list)?)
+        .with_context(|| format!("could not write {}", p.display()))
+}
+
+// Why the simpleaf run in `dir` did not complete, if it did not. The
+// logs are only written once every stage has succeeded, and the
+// quant marker once the stages before it have, so together they
+// mark a complete run even after `clean` dropped the map marker. A
+// manifest run is complete if every sample in its sample list is.
+pub fn run_completion_problem(dir: &Path) -> Option<String> {
+    if !dir.is_dir() {
+        return Some(format!("{} is not a directory", dir.display()));
+    }
+    if dir.join(LOCK_FILE_NAME).exists() {
+        return Some(format!(
+            "a simpleaf run is in progress in {}",
+            dir.display()
+        ));
+    }
+    if dir.join("simpleaf_index_log.json").is_file() {
+        return None;
+    }
+    if dir.join("simpleaf_quant_log.json").is_file() {
+        if !stage_marker_path(dir, "quant").exists() {
+            return Some(format!(
+                "the quant stage of the run in {} did not complete",
+                dir.display()
+            ));
+        }
+        return None;
+    }
+    let list = dir.join(SAMPLE_LIST_FILE);
+    if !list.is_file() {
+        return Some(format!(
+            "{} holds no completed simpleaf run (no {}, {} or {})",
+            dir.display(),
+            "simpleaf_index_log.json",
+            "simpleaf_quant_log.json",
+            SAMPLE_LIST_FILE
+        ));
+    }
+    let samples = match std::fs::read_to_string(&list)
+        .ok()
+        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+        .and_then(|v| {
+            v["samples"].as_array().map(|a| {
+                a.iter()
+                    .filter_map(|n| n.as_str().map(String::from))
+                    .collect::<Vec<String>>()
+            })
+        }) {
+        Some(s) if !s.is_empty() => s,
+        _ => return Some(format!("could not read the sample list {}", list.display())),
+    };
+    // a sample that failed early or never started has an
+    // incomplete directory or none at all
+    let failed: Vec<&str> = samples
+        .iter()
+        .filter(|n| run_completion_problem(&dir.join(n.as_str())).is_some())
+        .map(String::as_str)
+        .collect();
+    if failed.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "{} of the {} samples in {} did not complete: {}",
+            failed.len(),
+            samples.len(),
+            dir.display(),
+            failed.join(", ")
+        ))
+    }
+}
+
+// Removes the reclaimable parts of a simpleaf output directory
+// and returns the number of bytes freed. With `level` equal to
+// "intermediates" the mapping RAD files, the splici reference and
+// any downsampled reads are removed; with "all" everything but the provenance files
+// is removed.
+pub fn clean_run_dir(dir: &Path, level: &str, force: bool) -> Result<u64> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    if !force && !has_provenance(dir) {
+        bail!(
+            "{} does not look like a simpleaf output directory (none of {:?} found); \
+            pass --force to clean it anyway",
+            dir.display(),
+            PROVENANCE_FILES
+        );
+    }
+    if dir.join(LOCK_FILE_NAME).exists() {
+        bail!(
+            "another simpleaf run is in progress in {}; refusing to clean it",
+            dir.display()
+        );
+    }
+
+    let mut reclaimed = 0;
+    match level {
+        "intermediates" => {
+            let subs: Vec<&str> = INDEX_INTERMEDIATES
+                .iter()
+                .chain(QUANT_INTERMEDIATES)
+                .copied()
+                .collect();
+            reclaimed += remove_intermediates(dir, &subs)?;
+            // it would list the files just removed
+            let manifest = dir.join(RUN_MANIFEST_FILE);
+            if manifest.exists() {
+                remove_path(&manifest)?;
+            }
+        }
+        "all" => {
+            for entry in std::fs::read_dir(dir)? {
+                let p = entry?.path();
+                let keep = p
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| PROVENANCE_FILES.contains(&n));
+                if !keep {
+                    reclaimed += remove_path(&p)?;
+                }
+            }
+        }
+        l => bail!("unknown clean level {}", l),
+    }
+    Ok(reclaimed)
+}
+
+// Creates `output` if needed and checks that a small file can be
+// written to (and flushed in) it, so that read-only mounts or an
+// exceeded quota are reported before any heavy work starts.
+pub fn ensure_output_writable(output: &Path) -> Result<()> {
+    ensure_dir_writable(output, "output directory")
+}
+
+// Like `ensure_output_writable`, for any directory simpleaf must
+// write to; `desc` names the directory in the error messages.
+pub fn ensure_dir_writable(dir: &Path, desc: &str) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| {
+        format!(
+            "could not create the {} {}; check that you have \
+            permission to write there",
+            desc,
+            dir.display()
+        )
+    })?;
+    let probe = dir.join(format!(".simpleaf_write_test.{}", std::process::id()));
+    let res = std::fs::File::create(&probe).and_then(|mut f| {
+        f.write_all(b"simpleaf write test\n")?;
+        f.sync_all()
+    });
+    // remove the probe whether or not the write succeeded
+    let _ = std::fs::remove_file(&probe);
+    res.with_context(|| {
+        format!(
+            "the {} {} is not writable (check its permissions, \
+            whether the filesystem is mounted read-only, and your disk quota)",
+            desc,
+            dir.display()
+        )
+    })
+}
+
+// Appends `row` to the tab-separated table at `path`, writing
+// `header` first if the table doesn't exist yet. Concurrent
+// appends are serialized through a `<path>.lock` file.
+pub fn append_tsv_row(path: &Path, header: &[&str], row: &[String]) -> Result<()> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    // wait (for a bounded time) for any other writer to finish
+    let start = std::time::Instant::now();
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+        {
+            Ok(_) => break,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                if start.elapsed() > std::time::Duration::from_secs(60) {
+                    bail!(
+                        "timed out waiting for the lock {} on {}; if no other run is \
+                        writing to it, remove the lock file and try again.",
+                        lock_path.display(),
+                        path.display()
+                    );
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("could not create lock file {}", lock_path.display()))
+            }
+        }
+    }
+
+    let res = (|| -> Result<()> {
+        let write_header = !path.exists();
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let mut out = String::new();
+        if write_header {
+            out.push_str(&header.join("\t"));
+            out.push('\n');
+        }
+        out.push_str(&row.join("\t"));
+        out.push('\n');
+        f.write_all(out.as_bytes())
+            .with_context(|| format!("could not append to {}", path.display()))
+    })();
+    let _ = std::fs::remove_file(&lock_path);
+    res
+}
+
+// Whether `output` contains `{token}` placeholders to expand.
+pub fn is_output_template(output: &Path) -> bool {
+    output.to_string_lossy().contains('{')
+}
+
+// Replaces each `{token}` in `template` by its value from
+// `tokens`, failing on tokens that aren't known or have no value
+// for this run.
+pub fn expand_output_template(
+    template: &Path,
+    tokens: &[(&str, Option<String>)],
+) -> Result<PathBuf> {
+    let t = template.to_string_lossy();
+    let mut out = String::new();
+    let mut rest = t.as_ref();
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => bail!("unterminated placeholder in output template {}", t),
+        };
+        let name = &rest[start + 1..end];
+        match tokens.iter().find(|(k, _)| *k == name) {
+            Some((_, Some(v))) => out.push_str(v),
+            Some((_, None)) => bail!(
+                "the output template {} uses {{{}}}, which has no value for this run",
+                t,
+                name
+            ),
+            None => bail!(
+                "unknown placeholder {{{}}} in output template {}; expected one of {}",
+                name,
+                t,
+                tokens
+                    .iter()
+                    .map(|(k, _)| format!("{{{}}}", k))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(PathBuf::from(out))
+}
+
+// The space in bytes available to unprivileged users on the
+// filesystem holding `dir`, as reported by `df`. A `dir` that
+// doesn't exist yet will be created on the filesystem of its
+// nearest existing ancestor.
+pub fn available_space(dir: &Path) -> Option<u64> {
+    let abs = std::path::absolute(dir).ok()?;
+    let existing = abs.ancestors().find(|a| a.exists())?;
+    let out = std::process::Command::new("df")
+        .arg("-Pk")
+        .arg(existing)
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&out.stdout);
+    // the second line holds the filesystem, with the
+    // available 1K blocks in the fourth column
+    let kb = text.lines().nth(1)?.split_whitespace().nth(3)?;
+    kb.parse::<u64>().ok().map(|k| k * 1024)
+}
@@ -0,0 +1,10 @@
+pub mod af_utils;
+pub mod dl_utils;
+pub mod estimate_utils;
+pub mod fs_utils;
+pub mod prog_utils;
+pub mod prov_utils;
+pub mod quant_utils;
+pub mod ref_utils;
+pub mod run_utils;
+pub mod sample_utils;
@@ -6,6 +6,8 @@ use std::env;
 use std::path::PathBuf;
 use which::which;
 
+use crate::utils::run_utils::RunWarnings;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProgInfo {
     pub exe_path: PathBuf,
@@ -29,6 +31,10 @@ pub struct ReqProgs {
     pub salmon: Option<ProgInfo>,
     pub alevin_fry: Option<ProgInfo>,
     pub pyroe: Option<ProgInfo>,
+    // whether the versions were accepted with --allow-newer,
+    // so later commands keep honouring it.
+    #[serde(default)]
+    pub allow_newer: bool,
 }
 
 pub fn check_version_constraints<S1: AsRef<str>>(
@@ -60,19 +66,227 @@ pub fn check_version_constraints<S1: AsRef<str>>(
     Err(anyhow!("invalid version string"))
 }
 
+// Checks the version reported by `prog_output` against the range
+// `[min, below)` that `tool` has been tested with. A version at or
+// past `below` is an error unless `allow_newer` is set, in which
+// case it is only warned about.
+pub fn check_tested_version(
+    tool: &str,
+    min: &str,
+    below: &str,
+    prog_output: std::result::Result<String, std::io::Error>,
+    allow_newer: bool,
+) -> Result<Version> {
+    let v = check_version_constraints(format!(">={}", min), prog_output)
+        .map_err(|e| anyhow!("{} must be at least version {} : {}", tool, min, e))?;
+    if !VersionReq::parse(&format!("<{}", below))?.matches(&v) {
+        if !allow_newer {
+            return Err(anyhow!(
+                "{} {} is newer than the versions simpleaf was tested with (below {}); \
+                pass --allow-newer to use it anyway",
+                tool,
+                v,
+                below
+            ));
+        }
+        warn!(
+            "{} {} is newer than tested (below {}); proceeding, results may vary",
+            tool, v, below
+        );
+    }
+    Ok(v)
+}
+
+// An exact version that a tool is required to have.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionPin {
+    pub tool: String,
+    pub version: String,
+}
+
+// Parses a comma-separated list of `tool=version` pins,
+// e.g. `salmon=1.9.0,alevin-fry=0.8.2`.
+pub fn parse_version_pins(s: &str) -> Result<Vec<VersionPin>> {
+    let mut pins = Vec::new();
+    for p in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (tool, version) = match p.split_once('=') {
+            Some((t, v)) => (t.trim(), v.trim()),
+            None => {
+                return Err(anyhow!(
+                    "version pin {:?} is not of the form tool=version",
+                    p
+                ))
+            }
+        };
+        if !["salmon", "alevin-fry", "pyroe"].contains(&tool) {
+            return Err(anyhow!(
+                "cannot pin the version of unknown tool {:?}; expected salmon, alevin-fry or pyroe",
+                tool
+            ));
+        }
+        Version::parse(version)
+            .map_err(|e| anyhow!("invalid version {:?} for {} : {}", version, tool, e))?;
+        pins.push(VersionPin {
+            tool: tool.to_string(),
+            version: version.to_string(),
+        });
+    }
+    Ok(pins)
+}
+
+// Checks that each pinned tool in `rp` has exactly the pinned version.
+pub fn check_version_pins(rp: &ReqProgs, pins: &[VersionPin]) -> Result<()> {
+    for pin in pins {
+        let prog = match pin.tool.as_str() {
+            "salmon" => &rp.salmon,
+            "alevin-fry" => &rp.alevin_fry,
+            _ => &rp.pyroe,
+        };
+        let prog = prog
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} is pinned but was not found", pin.tool))?;
+        check_version_constraints(format!("={}", pin.version), Ok(prog.version.clone())).map_err(
+            |_| {
+                anyhow!(
+                    "{} {} does not match its pinned version {}",
+                    pin.tool,
+                    prog.version,
+                    pin.version
+                )
+            },
+        )?;
+        info!("{} {} matches its pinned version", pin.tool, prog.version);
+    }
+    Ok(())
+}
+
+// The mapping output formats simpleaf can have salmon write, with
+// the alevin-fry versions whose generate-permit-list reads them.
+// No alevin-fry release reads BAM.
+const MAPPING_FORMAT_SUPPORT: &[(&str, Option<&str>)] = &[("rad", Some(">=0.1.0")), ("bam", None)];
+
+// Checks, before anything is mapped, that the alevin-fry in `rp`
+// can read mapping output of `format`.
+pub fn check_mapping_output_format(format: &str, rp: &ReqProgs) -> Result<()> {
+    let alevin_fry = rp
+        .alevin_fry
+        .as_ref()
+        .ok_or_else(|| anyhow!("cannot check the mapping output format without alevin-fry"))?;
+    let af_ver = Version::parse(&alevin_fry.version)?;
+    let support = MAPPING_FORMAT_SUPPORT
+        .iter()
+        .find(|(f, _)| *f == format)
+        .and_then(|(_, req)| *req);
+    match support {
+        Some(req) if VersionReq::parse(req)?.matches(&af_ver) => Ok(()),
+        Some(req) => Err(anyhow!(
+            "alevin-fry {} cannot read {} mapping output (it requires alevin-fry {})",
+            af_ver,
+            format,
+            req
+        )),
+        None => Err(anyhow!(
+            "alevin-fry {} cannot read {} mapping output; generate-permit-list only reads \
+            RAD, so use --mapping-output-format rad",
+            af_ver,
+            format
+        )),
+    }
+}
+
+// Pairs of (alevin-fry requirement, salmon requirement)
+// describing which salmon versions write RAD files that
+// the matching alevin-fry versions are known to read.
+const RAD_COMPATIBILITY: &[(&str, &str)] = &[
+    (">=0.4.1, <0.5.0", ">=1.5.1, <1.6.0"),
+    (">=0.5.0, <1.0.0", ">=1.5.1, <2.0.0"),
+];
+
+// Checks that the salmon and alevin-fry versions in `rp`
+// are a known-compatible pair with respect to the RAD
+// format. An alevin-fry version absent from the table
+// only produces a warning, while a listed alevin-fry
+// version paired with an incompatible salmon is an error,
+// unless salmon is only newer than the compatible range and
+// newer versions are allowed, in which case it is a warning.
+pub fn check_rad_compatibility(
+    rp: &ReqProgs,
+    allow_newer: bool,
+    warnings: &mut RunWarnings,
+) -> Result<()> {
+    let (salmon, alevin_fry) = match (&rp.salmon, &rp.alevin_fry) {
+        (Some(s), Some(a)) => (s, a),
+        _ => {
+            return Err(anyhow!(
+                "cannot check RAD compatibility without both salmon and alevin-fry"
+            ))
+        }
+    };
+    let salmon_ver = Version::parse(&salmon.version)?;
+    let af_ver = Version::parse(&alevin_fry.version)?;
+
+    for (af_req, salmon_req) in RAD_COMPATIBILITY {
+        if VersionReq::parse(af_req)?.matches(&af_ver) {
+            if VersionReq::parse(salmon_req)?.matches(&salmon_ver) {
+                return Ok(());
+            }
+            let min_salmon = VersionReq::parse(salmon_req.split(',').next().unwrap_or_default())?;
+            if (allow_newer || rp.allow_newer) && min_salmon.matches(&salmon_ver) {
+                warnings.push(format!(
+                    "salmon {} is newer than the versions alevin-fry {} is known to read \
+                    RAD files from ({}); proceeding because of --allow-newer",
+                    salmon_ver, af_ver, salmon_req
+                ));
+                return Ok(());
+            }
+            return Err(anyhow!(
+                "salmon {} writes RAD files that alevin-fry {} cannot read; \
+                alevin-fry {} requires salmon {}",
+                salmon_ver,
+                af_ver,
+                af_req,
+                salmon_req
+            ));
+        }
+    }
+    warnings.push(format!(
+        "the compatibility of salmon {} with alevin-fry {} is unknown; \
+        collate may fail if their RAD formats differ",
+        salmon_ver, af_ver
+    ));
+    Ok(())
+}
+
 pub fn get_which_executable(prog_name: &str) -> Result<PathBuf> {
     match which(prog_name) {
         Ok(p) => {
             println!("found `{}` in the PATH at {}", prog_name, p.display());
             Ok(p)
         }
-        Err(e) => {
-            return Err(anyhow!(
-                "could not find `{}` in your path: {}",
-                prog_name,
-                e
-            ));
-        }
+        Err(e) => Err(anyhow!(
+            "could not find `{}` in your path: {}{}",
+            prog_name,
+            e,
+            module_hint(prog_name)
+        )),
+    }
+}
+
+// On systems using environment modules (or Lmod), the tools
+// are often only on the PATH after a `module load`, so
+// suggest that when a tool can't be found.
+fn module_hint(prog_name: &str) -> String {
+    let has_modules = ["MODULESHOME", "LMOD_CMD", "MODULEPATH"]
+        .iter()
+        .any(|k| env::var_os(k).is_some());
+    if has_modules {
+        format!(
+            "; this system uses environment modules, so `{}` may need to be loaded \
+            first (e.g. `module load {}`, see `module avail {}`), or pass its path explicitly",
+            prog_name, prog_name, prog_name
+        )
+    } else {
+        String::new()
     }
 }
 
@@ -95,11 +309,13 @@ pub fn get_required_progs_from_paths(
     salmon_exe: Option<PathBuf>,
     alevin_fry_exe: Option<PathBuf>,
     pyroe_exe: Option<PathBuf>,
+    allow_newer: bool,
 ) -> Result<ReqProgs> {
     let mut rp = ReqProgs {
         salmon: None,
         alevin_fry: None,
         pyroe: None,
+        allow_newer,
     };
 
     // use the given path if we have it
@@ -134,7 +350,7 @@ pub fn get_required_progs_from_paths(
 
     let st = salmon.display().to_string();
     let sr = run_fun!($st --version);
-    let v = check_version_constraints(">=1.5.1, <2.0.0", sr)?;
+    let v = check_tested_version("salmon", "1.5.1", "2.0.0", sr, allow_newer)?;
     rp.salmon = Some(ProgInfo {
         exe_path: salmon,
         version: format!("{}", v),
@@ -142,7 +358,7 @@ pub fn get_required_progs_from_paths(
 
     let st = alevin_fry.display().to_string();
     let sr = run_fun!($st --version);
-    let v = check_version_constraints(">=0.4.1, <1.0.0", sr)?;
+    let v = check_tested_version("alevin-fry", "0.4.1", "1.0.0", sr, allow_newer)?;
     rp.alevin_fry = Some(ProgInfo {
         exe_path: alevin_fry,
         version: format!("{}", v),
@@ -150,7 +366,7 @@ pub fn get_required_progs_from_paths(
 
     let st = pyroe.display().to_string();
     let sr = run_fun!($st --version);
-    let v = check_version_constraints(">=0.6.2, <1.0.0", sr)?;
+    let v = check_tested_version("pyroe", "0.6.2", "1.0.0", sr, allow_newer)?;
     rp.pyroe = Some(ProgInfo {
         exe_path: pyroe,
         version: format!("{}", v),
@@ -167,5 +383,5 @@ pub fn get_required_progs() -> Result<ReqProgs> {
     let alevin_fry_exe = Some(search_for_executable("ALEVIN_FRY", "alevin-fry")?);
     let pyroe_exe = Some(search_for_executable("PYROE", "pyroe")?);
 
-    get_required_progs_from_paths(salmon_exe, alevin_fry_exe, pyroe_exe)
+    get_required_progs_from_paths(salmon_exe, alevin_fry_exe, pyroe_exe, false)
 }
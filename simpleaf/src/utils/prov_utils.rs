@@ -0,0 +1,206 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::utils::fs_utils::PROVENANCE_FILES;
+
+// The fields of a provenance JSON that differ from run to run
+// even with identical inputs and parameters.
+pub const VOLATILE_FIELDS: [&str; 3] = ["time_info", "resources", "timestamp"];
+
+// Resolves `p` to a provenance JSON: either the file itself, or
+// the first provenance file found in the directory `p`.
+pub fn resolve_provenance(p: &Path) -> Result<PathBuf> {
+    if p.is_file() {
+        return Ok(p.to_path_buf());
+    }
+    if p.is_dir() {
+        // prefer the log of a run over the index info next to it
+        for f in PROVENANCE_FILES.iter().rev() {
+            if p.join(f).is_file() {
+                return Ok(p.join(f));
+            }
+        }
+    }
+    bail!(
+        "{} is neither a provenance JSON nor a directory containing one of {:?}",
+        p.display(),
+        PROVENANCE_FILES
+    );
+}
+
+pub fn read_provenance(p: &Path) -> Result<serde_json::Value> {
+    let f = std::fs::File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    serde_json::from_reader(BufReader::new(f))
+        .with_context(|| format!("could not parse {}", p.display()))
+}
+
+// Flattens `v` into a map from dotted field paths (e.g.
+// `version_info.salmon`) to their scalar values, keeping arrays
+// of scalars (such as command arguments) as one JSON value.
+fn flatten(v: &serde_json::Value, prefix: &str, out: &mut BTreeMap<String, String>) {
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", prefix, k)
+        }
+    };
+    match v {
+        serde_json::Value::Object(m) => {
+            for (k, v) in m {
+                flatten(v, &key(k), out);
+            }
+        }
+        serde_json::Value::Array(a) if a.iter().all(|x| !x.is_object() && !x.is_array()) => {
+            out.insert(prefix.to_string(), v.to_string());
+        }
+        serde_json::Value::Array(a) => {
+            for (i, v) in a.iter().enumerate() {
+                flatten(v, &key(&i.to_string()), out);
+            }
+        }
+        v => {
+            out.insert(prefix.to_string(), scalar(v));
+        }
+    }
+}
+
+fn scalar(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+// A field whose value differs between two provenance records;
+// a value of `None` means the field is absent from that record.
+#[derive(Debug)]
+pub struct FieldDiff {
+    pub field: String,
+    pub a: Option<String>,
+    pub b: Option<String>,
+}
+
+// Compares two provenance records field by field, returning the
+// differing fields and the number of identical ones. Fields under
+// `VOLATILE_FIELDS` are skipped unless `include_volatile` is set.
+pub fn diff_provenance(
+    a: &serde_json::Value,
+    b: &serde_json::Value,
+    include_volatile: bool,
+) -> (Vec<FieldDiff>, usize) {
+    let (mut fa, mut fb) = (BTreeMap::new(), BTreeMap::new());
+    flatten(a, "", &mut fa);
+    flatten(b, "", &mut fb);
+    let volatile = |f: &str| f.split('.').any(|part| VOLATILE_FIELDS.contains(&part));
+
+    let mut fields = fa.keys().chain(fb.keys()).cloned().collect::<Vec<String>>();
+    fields.sort();
+    fields.dedup();
+    let mut diffs = Vec::new();
+    let mut same = 0;
+    for field in fields {
+        if !include_volatile && volatile(&field) {
+            continue;
+        }
+        let (va, vb) = (fa.get(&field).cloned(), fb.get(&field).cloned());
+        if va == vb {
+            same += 1;
+        } else {
+            diffs.push(FieldDiff {
+                field,
+                a: va,
+                b: vb,
+            });
+        }
+    }
+    (diffs, same)
+}
+
+// The package names of the tools simpleaf runs, keyed by
+// how they are named in its provenance.
+const TOOL_PACKAGES: [(&str, &str); 3] = [
+    ("salmon", "salmon"),
+    ("alevin_fry", "alevin-fry"),
+    ("pyroe", "pyroe"),
+];
+
+// The (package, version) pairs of the tools recorded in the
+// provenance JSON `v`, whose `version_info` either holds the version
+// strings themselves (a quant log) or the tools' `ProgInfo` (as
+// recorded by `set-paths` or `index`), preceded by simpleaf itself
+// if `v` records its version.
+pub fn provenance_tool_versions(v: &serde_json::Value) -> Vec<(String, String)> {
+    let mut versions = Vec::new();
+    if let Some(sv) = v["simpleaf_version"].as_str() {
+        versions.push((String::from("simpleaf"), sv.to_string()));
+    }
+    let info = if v["prog_info"].is_object() {
+        &v["prog_info"]
+    } else {
+        &v["version_info"]
+    };
+    for (key, package) in TOOL_PACKAGES {
+        let version = match &info[key] {
+            serde_json::Value::String(s) => Some(s.as_str()),
+            o => o["version"].as_str(),
+        };
+        if let Some(version) = version {
+            versions.push((package.to_string(), version.to_string()));
+        }
+    }
+    versions
+}
+
+// Renders `versions` as a conda `environment.yml`, or with `format`
+// "pins" as one `package=version` line each. The simpleaf version is
+// only noted in a comment, since the conda channels do not carry
+// every simpleaf release and pinning it could make the environment
+// unsolvable.
+pub fn environment_spec(versions: &[(String, String)], format: &str) -> String {
+    let (simpleaf, tools): (Vec<_>, Vec<_>) = versions.iter().partition(|(p, _)| p == "simpleaf");
+    let note = simpleaf
+        .iter()
+        .map(|(_, v)| format!("# recorded with simpleaf {}\n", v))
+        .collect::<String>();
+    let pins = tools
+        .iter()
+        .map(|(p, v)| format!("{}={}", p, v))
+        .collect::<Vec<String>>();
+    match format {
+        "pins" => note + &pins.join("\n") + "\n",
+        _ => {
+            let mut spec =
+                note + "name: simpleaf\nchannels:\n  - conda-forge\n  - bioconda\ndependencies:\n";
+            for p in pins {
+                spec.push_str(&format!("  - {}\n", p));
+            }
+            spec
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_spec_leaves_simpleaf_out_of_the_dependencies() {
+        let versions = [
+            (String::from("simpleaf"), String::from("0.1.0")),
+            (String::from("salmon"), String::from("1.9.0")),
+            (String::from("alevin-fry"), String::from("0.7.0")),
+        ];
+        assert_eq!(
+            environment_spec(&versions, "pins"),
+            "# recorded with simpleaf 0.1.0\nsalmon=1.9.0\nalevin-fry=0.7.0\n"
+        );
+        assert_eq!(
+            environment_spec(&versions, "yml"),
+            "# recorded with simpleaf 0.1.0\nname: simpleaf\nchannels:\n  - conda-forge\n  \
+            - bioconda\ndependencies:\n  - salmon=1.9.0\n  - alevin-fry=0.7.0\n"
+        );
+    }
+}
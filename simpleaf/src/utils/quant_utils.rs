@@ -0,0 +1,743 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// The files alevin-fry writes the count matrix to, relative
+// to the `alevin` directory of a quant output.
+const MTX_FILE: &str = "quants_mat.mtx";
+const ROWS_FILE: &str = "quants_mat_rows.txt";
+const COLS_FILE: &str = "quants_mat_cols.txt";
+
+// Resolves the `alevin` directory holding the count matrix of
+// `p`, which may be an `af_quant` directory or a simpleaf
+// quant output directory containing one.
+fn alevin_dir(p: &Path) -> Result<PathBuf> {
+    for d in [p.join("alevin"), p.join("af_quant").join("alevin")] {
+        if d.join(MTX_FILE).is_file() {
+            return Ok(d);
+        }
+    }
+    bail!(
+        "could not find an alevin-fry count matrix ({}) under {}",
+        MTX_FILE,
+        p.display()
+    );
+}
+
+fn read_lines(p: &Path) -> Result<Vec<String>> {
+    let f = std::fs::File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    BufReader::new(f)
+        .lines()
+        .map(|l| Ok(l?.trim_end().to_string()))
+        .collect()
+}
+
+// Parses the (1-based) coordinate entries of a MatrixMarket
+// file, calling `f` with the 0-based row, column and value.
+fn for_each_mtx_entry<F: FnMut(usize, usize, f64)>(p: &Path, mut f: F) -> Result<()> {
+    let file = std::fs::File::open(p).with_context(|| format!("could not open {}", p.display()))?;
+    let mut seen_size = false;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.starts_with('%') || line.trim().is_empty() {
+            continue;
+        }
+        // the first non-comment line holds the dimensions
+        if !seen_size {
+            seen_size = true;
+            continue;
+        }
+        let mut it = line.split_whitespace();
+        let (r, c, v) = match (it.next(), it.next(), it.next()) {
+            (Some(r), Some(c), Some(v)) => (r, c, v),
+            _ => bail!("malformed entry {:?} in {}", line, p.display()),
+        };
+        let r: usize = r
+            .parse()
+            .with_context(|| format!("bad row in {}", p.display()))?;
+        let c: usize = c
+            .parse()
+            .with_context(|| format!("bad column in {}", p.display()))?;
+        let v: f64 = v
+            .parse()
+            .with_context(|| format!("bad value in {}", p.display()))?;
+        if r == 0 || c == 0 {
+            bail!("zero coordinate in {}", p.display());
+        }
+        f(r - 1, c - 1, v);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct MergeSummary {
+    pub num_cells: usize,
+    pub num_genes: usize,
+    // barcodes that were present in more than one input
+    pub num_shared_cells: usize,
+}
+
+// Sums the count matrices of several quant outputs that share
+// a gene list, adding up the counts of barcodes that appear
+// in more than one of them, and writes the merged matrix to
+// `output/alevin`.
+pub fn merge_quants(inputs: &[PathBuf], output: &Path) -> Result<MergeSummary> {
+    let mut genes: Option<Vec<String>> = None;
+    let mut barcodes: Vec<String> = Vec::new();
+    let mut bc_index: HashMap<String, usize> = HashMap::new();
+    let mut bc_inputs: Vec<usize> = Vec::new();
+    let mut counts: Vec<HashMap<usize, f64>> = Vec::new();
+
+    for input in inputs {
+        let ad = alevin_dir(input)?;
+        let cols = read_lines(&ad.join(COLS_FILE))?;
+        match &genes {
+            None => genes = Some(cols),
+            Some(g) if *g == cols => {}
+            Some(_) => bail!(
+                "the genes of {} differ from those of {}; only quant outputs built \
+                with the same t2g map can be merged",
+                input.display(),
+                inputs[0].display()
+            ),
+        }
+
+        // map this input's rows onto the merged barcodes
+        let rows = read_lines(&ad.join(ROWS_FILE))?;
+        let mut row_map = Vec::with_capacity(rows.len());
+        for bc in rows {
+            let i = *bc_index.entry(bc.clone()).or_insert_with(|| {
+                barcodes.push(bc);
+                bc_inputs.push(0);
+                counts.push(HashMap::new());
+                barcodes.len() - 1
+            });
+            bc_inputs[i] += 1;
+            row_map.push(i);
+        }
+
+        let mtx = ad.join(MTX_FILE);
+        let mut bad_row = None;
+        for_each_mtx_entry(&mtx, |r, c, v| match row_map.get(r) {
+            Some(&i) => *counts[i].entry(c).or_insert(0.0) += v,
+            None => bad_row = Some(r + 1),
+        })?;
+        if let Some(r) = bad_row {
+            bail!(
+                "{} refers to row {} but {} lists only {} barcodes",
+                mtx.display(),
+                r,
+                ROWS_FILE,
+                row_map.len()
+            );
+        }
+    }
+
+    // `inputs` is never empty, so the genes are known
+    let genes = genes.unwrap();
+    let out_dir = output.join("alevin");
+    std::fs::create_dir_all(&out_dir)
+        .with_context(|| format!("could not create {}", out_dir.display()))?;
+
+    let nnz: usize = counts.iter().map(|c| c.len()).sum();
+    let mtx_path = out_dir.join(MTX_FILE);
+    let mut w = BufWriter::new(
+        std::fs::File::create(&mtx_path)
+            .with_context(|| format!("could not create {}", mtx_path.display()))?,
+    );
+    writeln!(w, "%%MatrixMarket matrix coordinate real general")?;
+    writeln!(w, "{}\t{}\t{}", barcodes.len(), genes.len(), nnz)?;
+    for (i, row) in counts.iter().enumerate() {
+        let mut entries = row.iter().collect::<Vec<(&usize, &f64)>>();
+        entries.sort_unstable_by_key(|(c, _)| **c);
+        for (c, v) in entries {
+            writeln!(w, "{}\t{}\t{}", i + 1, c + 1, v)?;
+        }
+    }
+    w.flush()?;
+
+    std::fs::write(out_dir.join(ROWS_FILE), barcodes.join("\n") + "\n")
+        .with_context(|| format!("could not write {}", out_dir.join(ROWS_FILE).display()))?;
+    std::fs::write(out_dir.join(COLS_FILE), genes.join("\n") + "\n")
+        .with_context(|| format!("could not write {}", out_dir.join(COLS_FILE).display()))?;
+
+    Ok(MergeSummary {
+        num_cells: barcodes.len(),
+        num_genes: genes.len(),
+        num_shared_cells: bc_inputs.iter().filter(|n| **n > 1).count(),
+    })
+}
+
+// The dimensions of the count matrix written by `alevin-fry quant`.
+#[derive(Debug, Serialize)]
+pub struct MatrixShape {
+    pub num_cells: usize,
+    // the number of matrix columns; in USA mode each gene has
+    // separate spliced, unspliced and ambiguous columns
+    pub num_columns: usize,
+    pub num_genes: usize,
+    pub usa_mode: bool,
+}
+
+impl MatrixShape {
+    pub fn describe(&self) -> String {
+        if self.usa_mode {
+            format!(
+                "{} cells × {} genes (USA mode: {} spliced/unspliced/ambiguous columns)",
+                self.num_cells, self.num_genes, self.num_columns
+            )
+        } else {
+            format!("{} cells × {} genes", self.num_cells, self.num_genes)
+        }
+    }
+}
+
+// Reads the shape of the count matrix in the quant output
+// directory `quant_dir` from its row and column name files, which
+// are written for both the EDS and MTX output formats.
+pub fn matrix_shape(quant_dir: &Path) -> Result<MatrixShape> {
+    let ad = quant_dir.join("alevin");
+    let num_cells = read_lines(&ad.join(ROWS_FILE))?.len();
+    let num_columns = read_lines(&ad.join(COLS_FILE))?.len();
+    // alevin-fry records whether it ran in USA mode in its quant.json
+    let usa_mode = std::fs::read_to_string(quant_dir.join("quant.json"))
+        .ok()
+        .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+        .and_then(|v| v.get("usa_mode").and_then(|u| u.as_bool()))
+        .unwrap_or(false);
+    Ok(MatrixShape {
+        num_cells,
+        num_columns,
+        num_genes: if usa_mode {
+            num_columns / 3
+        } else {
+            num_columns
+        },
+        usa_mode,
+    })
+}
+
+// The parts of a USA-mode count matrix, in the order alevin-fry
+// lays out their column blocks.
+pub const USA_LAYERS: [&str; 3] = ["spliced", "unspliced", "ambiguous"];
+
+// Splits the USA-mode count matrix of the quant output `quant_dir`
+// into one MatrixMarket file per layer (`spliced.mtx`,
+// `unspliced.mtx` and `ambiguous.mtx`) in `out_dir`, alongside the
+// shared `barcodes.txt` and `genes.txt`. Returns the written files.
+pub fn split_usa_matrix(quant_dir: &Path, out_dir: &Path) -> Result<Vec<PathBuf>> {
+    let ad = quant_dir.join("alevin");
+    let mtx = ad.join(MTX_FILE);
+    if !mtx.is_file() {
+        bail!(
+            "{} does not exist; splitting the matrix needs --output-format mtx",
+            mtx.display()
+        );
+    }
+    let cols = read_lines(&ad.join(COLS_FILE))?;
+    let num_genes = cols.len() / USA_LAYERS.len();
+    // every layer lists the same genes in the same order
+    if num_genes == 0
+        || cols.len() % USA_LAYERS.len() != 0
+        || cols[..num_genes] != cols[num_genes..2 * num_genes]
+        || cols[..num_genes] != cols[2 * num_genes..]
+    {
+        bail!(
+            "the columns of {} are not laid out as spliced, unspliced and ambiguous \
+            blocks of the same genes; was it quantified in USA mode?",
+            mtx.display()
+        );
+    }
+    let num_cells = read_lines(&ad.join(ROWS_FILE))?.len();
+
+    let mut layers: Vec<Vec<(usize, usize, f64)>> = vec![Vec::new(); USA_LAYERS.len()];
+    let mut out_of_range = None;
+    for_each_mtx_entry(&mtx, |r, c, v| {
+        if c >= cols.len() {
+            out_of_range.get_or_insert(c + 1);
+        } else {
+            layers[c / num_genes].push((r, c % num_genes, v));
+        }
+    })?;
+    if let Some(c) = out_of_range {
+        bail!(
+            "{} has an entry in column {}, but {} lists only {} columns",
+            mtx.display(),
+            c,
+            COLS_FILE,
+            cols.len()
+        );
+    }
+
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("could not create {}", out_dir.display()))?;
+    let mut written = Vec::new();
+    for (name, entries) in USA_LAYERS.iter().zip(layers) {
+        let p = out_dir.join(format!("{}.mtx", name));
+        let mut w = BufWriter::new(
+            std::fs::File::create(&p)
+                .with_context(|| format!("could not create {}", p.display()))?,
+        );
+        writeln!(w, "%%MatrixMarket matrix coordinate real general")?;
+        writeln!(w, "{}\t{}\t{}", num_cells, num_genes, entries.len())?;
+        for (r, c, v) in entries {
+            writeln!(w, "{}\t{}\t{}", r + 1, c + 1, v)?;
+        }
+        w.flush()?;
+        written.push(p);
+    }
+    let barcodes = out_dir.join("barcodes.txt");
+    std::fs::copy(ad.join(ROWS_FILE), &barcodes)
+        .with_context(|| format!("could not write {}", barcodes.display()))?;
+    let genes = out_dir.join("genes.txt");
+    std::fs::write(&genes, cols[..num_genes].join("\n") + "\n")
+        .with_context(|| format!("could not write {}", genes.display()))?;
+    written.push(barcodes);
+    written.push(genes);
+    Ok(written)
+}
+
+// Writes the barcodes of the cells in the count matrix of the quant
+// output `quant_dir` to `dest`, one per line, returning their number.
+pub fn write_barcodes(quant_dir: &Path, dest: &Path) -> Result<usize> {
+    let barcodes = read_lines(&quant_dir.join("alevin").join(ROWS_FILE))?;
+    let mut out = barcodes.join("\n");
+    if !barcodes.is_empty() {
+        out.push('\n');
+    }
+    std::fs::write(dest, out).with_context(|| format!("could not write {}", dest.display()))?;
+    Ok(barcodes.len())
+}
+
+// Reads a gene allowlist with one gene id per line; empty lines
+// and lines starting with `#` are ignored.
+pub fn read_gene_allowlist(p: &Path) -> Result<HashSet<String>> {
+    let genes = read_lines(p)?
+        .into_iter()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect::<HashSet<String>>();
+    if genes.is_empty() {
+        bail!("the gene allowlist {} lists no genes", p.display());
+    }
+    Ok(genes)
+}
+
+#[derive(Debug)]
+pub struct AllowlistSummary {
+    // allowlisted genes found in the matrix
+    pub num_genes: usize,
+    // allowlisted genes the matrix doesn't have
+    pub missing: Vec<String>,
+}
+
+// Writes the count matrix of the quant output `quant_dir`
+// restricted to the columns of the genes in `genes` (every layer
+// of a gene in USA mode) to `out_dir`, under the same file names.
+pub fn filter_matrix_genes(
+    quant_dir: &Path,
+    genes: &HashSet<String>,
+    out_dir: &Path,
+) -> Result<AllowlistSummary> {
+    let ad = quant_dir.join("alevin");
+    let mtx = ad.join(MTX_FILE);
+    if !mtx.is_file() {
+        bail!(
+            "{} does not exist; restricting the matrix to a gene allowlist needs \
+            --output-format mtx",
+            mtx.display()
+        );
+    }
+    let cols = read_lines(&ad.join(COLS_FILE))?;
+    // the new index of every kept column
+    let mut keep = vec![None; cols.len()];
+    let mut kept_cols = Vec::new();
+    for (i, g) in cols.iter().enumerate() {
+        if genes.contains(g) {
+            keep[i] = Some(kept_cols.len());
+            kept_cols.push(g.clone());
+        }
+    }
+    let found = kept_cols.iter().collect::<HashSet<&String>>();
+    let mut missing = genes
+        .iter()
+        .filter(|g| !found.contains(g))
+        .cloned()
+        .collect::<Vec<String>>();
+    missing.sort();
+
+    let mut entries = Vec::new();
+    for_each_mtx_entry(&mtx, |r, c, v| {
+        if let Some(Some(nc)) = keep.get(c) {
+            entries.push((r, *nc, v));
+        }
+    })?;
+    let num_cells = read_lines(&ad.join(ROWS_FILE))?.len();
+
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("could not create {}", out_dir.display()))?;
+    let mtx_path = out_dir.join(MTX_FILE);
+    let mut w = BufWriter::new(
+        std::fs::File::create(&mtx_path)
+            .with_context(|| format!("could not create {}", mtx_path.display()))?,
+    );
+    writeln!(w, "%%MatrixMarket matrix coordinate real general")?;
+    writeln!(w, "{}\t{}\t{}", num_cells, kept_cols.len(), entries.len())?;
+    for (r, c, v) in entries {
+        writeln!(w, "{}\t{}\t{}", r + 1, c + 1, v)?;
+    }
+    w.flush()?;
+    std::fs::copy(ad.join(ROWS_FILE), out_dir.join(ROWS_FILE))
+        .with_context(|| format!("could not write {}", out_dir.join(ROWS_FILE).display()))?;
+    let mut cols_out = kept_cols.join("\n");
+    if !kept_cols.is_empty() {
+        cols_out.push('\n');
+    }
+    std::fs::write(out_dir.join(COLS_FILE), cols_out)
+        .with_context(|| format!("could not write {}", out_dir.join(COLS_FILE).display()))?;
+
+    Ok(AllowlistSummary {
+        num_genes: found.len(),
+        missing,
+    })
+}
+
+// The number of reads of every barcode generate-permit-list saw
+// in `gpl_dir`, sorted from most to fewest. These come from its
+// `all_freq.bin` where the filtering method writes one, or else from
+// `permit_freq.bin`, which with an unfiltered permit list holds every
+// barcode that matched it.
+pub fn barcode_read_counts(gpl_dir: &Path) -> Result<Vec<u64>> {
+    let all_freq = gpl_dir.join("all_freq.bin");
+    let mut counts = if all_freq.is_file() {
+        read_frequencies(&all_freq, ALL_FREQ_ENTRY_BYTES)?
+    } else {
+        read_frequencies(&gpl_dir.join("permit_freq.bin"), PERMIT_FREQ_ENTRY_BYTES)?
+    };
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(counts)
+}
+
+// The per-cell statistics of a quant output's `featureDump.txt`,
+// summarized by the median of every numeric column.
+#[derive(Debug, Serialize)]
+pub struct FeatureDumpSummary {
+    pub num_cells: usize,
+    pub medians: BTreeMap<String, f64>,
+}
+
+pub fn summarize_feature_dump(p: &Path) -> Result<FeatureDumpSummary> {
+    let lines = read_lines(p)?;
+    let header = match lines.first() {
+        Some(h) => h.split('\t').collect::<Vec<&str>>(),
+        None => bail!("{} is empty", p.display()),
+    };
+    let mut columns = vec![Vec::with_capacity(lines.len()); header.len()];
+    for l in &lines[1..] {
+        for (col, v) in columns.iter_mut().zip(l.split('\t')) {
+            if let Ok(x) = v.parse::<f64>() {
+                col.push(x);
+            }
+        }
+    }
+    let mut medians = BTreeMap::new();
+    for (name, mut col) in header.into_iter().zip(columns) {
+        // the barcode column, or one that isn't numeric
+        if col.len() + 1 < lines.len() || col.is_empty() {
+            continue;
+        }
+        col.sort_unstable_by(f64::total_cmp);
+        let mid = col.len() / 2;
+        let median = if col.len() % 2 == 0 {
+            (col[mid - 1] + col[mid]) / 2.0
+        } else {
+            col[mid]
+        };
+        medians.insert(name.to_string(), median);
+    }
+    Ok(FeatureDumpSummary {
+        num_cells: lines.len() - 1,
+        medians,
+    })
+}
+
+// Writes a PNG barcode-rank ("knee") plot of `counts`, sorted from
+// most to fewest reads, with both axes on a log scale.
+#[cfg(feature = "knee-plot")]
+pub fn write_knee_plot(counts: &[u64], dest: &Path) -> Result<()> {
+    use plotters::prelude::*;
+
+    let draw = || -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let root = BitMapBackend::new(dest, (640, 480)).into_drawing_area();
+        root.fill(&WHITE)?;
+        let max_rank = counts.len().max(2) as u64;
+        let max_count = counts.first().copied().unwrap_or(1).max(10);
+        let mut chart = ChartBuilder::on(&root)
+            .caption(
+                format!("barcode rank plot ({} barcodes)", counts.len()),
+                ("sans-serif", 20),
+            )
+            .margin(20)
+            .x_label_area_size(40)
+            .y_label_area_size(60)
+            .build_cartesian_2d((1..max_rank).log_scale(), (1..max_count).log_scale())?;
+        chart
+            .configure_mesh()
+            .x_desc("barcode rank")
+            .y_desc("reads")
+            .draw()?;
+
+        // plotting every barcode adds nothing once they share a
+        // pixel, so keep a point only every 0.5% along the rank axis
+        let mut next_rank = 1.0;
+        let points = counts.iter().enumerate().filter_map(|(i, c)| {
+            let rank = (i + 1) as u64;
+            if (rank as f64) < next_rank && i + 1 != counts.len() {
+                return None;
+            }
+            next_rank = rank as f64 * 1.005;
+            Some((rank, (*c).max(1)))
+        });
+        chart.draw_series(LineSeries::new(
+            points,
+            RGBColor(70, 130, 180).stroke_width(2),
+        ))?;
+        root.present()?;
+        Ok(())
+    };
+    draw()
+        .map_err(|e| anyhow::anyhow!("could not draw the knee plot to {} : {}", dest.display(), e))
+}
+
+// Without the `knee-plot` feature simpleaf is built without a
+// plotting library, so it cannot draw the knee plot.
+#[cfg(not(feature = "knee-plot"))]
+pub fn write_knee_plot(_counts: &[u64], dest: &Path) -> Result<()> {
+    bail!(
+        "could not write {}: this simpleaf was built without the knee-plot feature; \
+        rebuild it with `cargo build --features knee-plot` to use --knee-plot",
+        dest.display()
+    )
+}
+
+// The header alevin-fry writes ahead of the barcode frequencies in
+// `permit_freq.bin` and `all_freq.bin`: the file format version and
+// the barcode length, each a u64. Versions before the format was
+// versioned wrote the frequencies alone.
+const PERMIT_FREQ_HEADERS: [u64; 2] = [16, 0];
+// The bytes of one (barcode, frequency) entry of the map in
+// `permit_freq.bin`, and of one frequency in `all_freq.bin`.
+const PERMIT_FREQ_ENTRY_BYTES: u64 = 16;
+const ALL_FREQ_ENTRY_BYTES: u64 = 8;
+
+// The number of barcodes generate-permit-list permitted into
+// `gpl_dir`, from the barcode frequency map in its `permit_freq.bin`.
+pub fn permitted_barcode_count(gpl_dir: &Path) -> Result<u64> {
+    let p = gpl_dir.join("permit_freq.bin");
+    Ok(read_frequencies(&p, PERMIT_FREQ_ENTRY_BYTES)?.len() as u64)
+}
+
+// Reads the frequencies of a bincode-encoded frequency file of
+// alevin-fry, i.e. the last u64 of each `entry_bytes`-sized entry
+// following the length prefix. The length is only trusted when it
+// accounts for the size of the whole file.
+fn read_frequencies(p: &Path, entry_bytes: u64) -> Result<Vec<u64>> {
+    let data = std::fs::read(p).with_context(|| format!("could not read {}", p.display()))?;
+    let size = data.len() as u64;
+    for header in PERMIT_FREQ_HEADERS {
+        let start = header as usize;
+        let Some(prefix) = data.get(start..start + 8) else {
+            continue;
+        };
+        let len = u64::from_le_bytes(prefix.try_into().unwrap());
+        let expected = len
+            .checked_mul(entry_bytes)
+            .and_then(|b| b.checked_add(header + 8));
+        if expected == Some(size) {
+            let entries = &data[start + 8..];
+            return Ok(entries
+                .chunks(entry_bytes as usize)
+                .map(|e| u64::from_le_bytes(e[e.len() - 8..].try_into().unwrap()))
+                .collect());
+        }
+    }
+    bail!(
+        "{} is not in a frequency file format simpleaf knows ({} bytes)",
+        p.display(),
+        size
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a permit_freq.bin laid out as alevin-fry's
+    // generate-permit-list serializes it with bincode: the format
+    // version and barcode length as u64s, then the map as its u64
+    // length followed by the (barcode, frequency) u64 pairs.
+    fn write_permit_freq(dir: &Path, header: Option<(u64, u64)>, freqs: &[(u64, u64)]) {
+        let mut data = Vec::new();
+        if let Some((version, bc_len)) = header {
+            data.extend(version.to_le_bytes());
+            data.extend(bc_len.to_le_bytes());
+        }
+        data.extend((freqs.len() as u64).to_le_bytes());
+        for (bc, f) in freqs {
+            data.extend(bc.to_le_bytes());
+            data.extend(f.to_le_bytes());
+        }
+        std::fs::write(dir.join("permit_freq.bin"), data).unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("simpleaf_test_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    // Writes a quant output under `dir` holding the matrix `entries`
+    // (1-based coordinates) over the given barcodes and columns.
+    fn write_quant(dir: &Path, barcodes: &[&str], cols: &[&str], entries: &[(usize, usize, f64)]) {
+        let ad = dir.join("alevin");
+        std::fs::create_dir_all(&ad).unwrap();
+        let mut mtx = format!(
+            "%%MatrixMarket matrix coordinate real general\n{}\t{}\t{}\n",
+            barcodes.len(),
+            cols.len(),
+            entries.len()
+        );
+        for (r, c, v) in entries {
+            mtx += &format!("{}\t{}\t{}\n", r, c, v);
+        }
+        std::fs::write(ad.join(MTX_FILE), mtx).unwrap();
+        std::fs::write(ad.join(ROWS_FILE), barcodes.join("\n") + "\n").unwrap();
+        std::fs::write(ad.join(COLS_FILE), cols.join("\n") + "\n").unwrap();
+    }
+
+    #[test]
+    fn split_usa_matrix_rejects_malformed_matrices() {
+        let d = test_dir("split_bad");
+        let q = d.join("quant");
+        write_quant(&q, &["AAAA"], &[], &[]);
+        assert!(split_usa_matrix(&q, &d.join("out")).is_err());
+
+        let usa = ["g1", "g2", "g1", "g2", "g1", "g2"];
+        write_quant(&q, &["AAAA"], &usa, &[(1, 7, 1.0)]);
+        let e = split_usa_matrix(&q, &d.join("out")).unwrap_err();
+        assert!(e.to_string().contains("column 7"), "{}", e);
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn split_usa_matrix_writes_one_matrix_per_layer() {
+        let d = test_dir("split");
+        let q = d.join("quant");
+        let usa = ["g1", "g2", "g1", "g2", "g1", "g2"];
+        write_quant(
+            &q,
+            &["AAAA", "CCCC"],
+            &usa,
+            &[(1, 1, 1.0), (1, 4, 2.0), (2, 5, 3.0), (2, 6, 4.0)],
+        );
+        let out = d.join("out");
+        assert_eq!(split_usa_matrix(&q, &out).unwrap().len(), 5);
+        let read = |f: &str| std::fs::read_to_string(out.join(f)).unwrap();
+        let header = "%%MatrixMarket matrix coordinate real general\n";
+        assert_eq!(read("spliced.mtx"), format!("{}2\t2\t1\n1\t1\t1\n", header));
+        assert_eq!(
+            read("unspliced.mtx"),
+            format!("{}2\t2\t1\n1\t2\t2\n", header)
+        );
+        assert_eq!(
+            read("ambiguous.mtx"),
+            format!("{}2\t2\t2\n2\t1\t3\n2\t2\t4\n", header)
+        );
+        assert_eq!(read("barcodes.txt"), "AAAA\nCCCC\n");
+        assert_eq!(read("genes.txt"), "g1\ng2\n");
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn merge_quants_sums_the_counts_of_shared_barcodes() {
+        let d = test_dir("merge");
+        let (a, b) = (d.join("a"), d.join("b"));
+        write_quant(
+            &a,
+            &["AAAA", "CCCC"],
+            &["g1", "g2"],
+            &[(1, 1, 1.0), (2, 2, 2.0)],
+        );
+        write_quant(
+            &b,
+            &["CCCC", "GGGG"],
+            &["g1", "g2"],
+            &[(1, 2, 3.0), (2, 1, 4.0)],
+        );
+        let out = d.join("merged");
+        let summary = merge_quants(&[a.clone(), b], &out).unwrap();
+        assert_eq!(
+            (
+                summary.num_cells,
+                summary.num_genes,
+                summary.num_shared_cells
+            ),
+            (3, 2, 1)
+        );
+        let ad = out.join("alevin");
+        assert_eq!(
+            std::fs::read_to_string(ad.join(MTX_FILE)).unwrap(),
+            "%%MatrixMarket matrix coordinate real general\n3\t2\t3\n1\t1\t1\n2\t2\t5\n3\t1\t4\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(ad.join(ROWS_FILE)).unwrap(),
+            "AAAA\nCCCC\nGGGG\n"
+        );
+
+        let c = d.join("c");
+        write_quant(&c, &["AAAA"], &["g1", "g3"], &[]);
+        assert!(merge_quants(&[a, c], &d.join("bad")).is_err());
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn permitted_barcode_count_skips_the_header() {
+        let d = test_dir("permit_header");
+        write_permit_freq(&d, Some((2, 16)), &[(1, 10), (2, 20), (3, 30)]);
+        assert_eq!(permitted_barcode_count(&d).unwrap(), 3);
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn permitted_barcode_count_reads_unversioned_files() {
+        let d = test_dir("permit_legacy");
+        write_permit_freq(&d, None, &[(1, 10), (2, 20)]);
+        assert_eq!(permitted_barcode_count(&d).unwrap(), 2);
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn barcode_read_counts_prefers_all_freq() {
+        let d = test_dir("all_freq");
+        write_permit_freq(&d, Some((2, 16)), &[(1, 10), (2, 30)]);
+        assert_eq!(barcode_read_counts(&d).unwrap(), vec![30, 10]);
+
+        let mut data = Vec::new();
+        for v in [2u64, 16, 3, 5, 30, 10] {
+            data.extend(v.to_le_bytes());
+        }
+        std::fs::write(d.join("all_freq.bin"), data).unwrap();
+        assert_eq!(barcode_read_counts(&d).unwrap(), vec![30, 10, 5]);
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn permitted_barcode_count_rejects_unknown_layouts() {
+        let d = test_dir("permit_bad");
+        std::fs::write(d.join("permit_freq.bin"), [0u8; 13]).unwrap();
+        assert!(permitted_barcode_count(&d).is_err());
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+}
@@ -0,0 +1,900 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::utils::prog_utils::ProgInfo;
+use crate::utils::sample_utils::open_maybe_gzipped;
+
+// Returns the names of the records in the FASTA file at `p`,
+// i.e. the first whitespace-delimited word of each header.
+pub fn fasta_seq_names(p: &Path) -> Result<Vec<String>> {
+    let r = open_maybe_gzipped(p)
+        .with_context(|| format!("could not open FASTA file {}", p.display()))?;
+    let mut names = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('>') {
+            if let Some(name) = header.split_whitespace().next() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+// Writes a 2-column t2g file mapping each record of the
+// FASTA file at `fasta` to itself.
+pub fn write_identity_t2g(fasta: &Path, t2g: &Path) -> Result<usize> {
+    let names = fasta_seq_names(fasta)?;
+    let f = std::fs::File::create(t2g)
+        .with_context(|| format!("could not create t2g file {}", t2g.display()))?;
+    let mut w = BufWriter::new(f);
+    for n in &names {
+        writeln!(w, "{}\t{}", n, n)?;
+    }
+    w.flush()?;
+    Ok(names.len())
+}
+
+// A feature-barcode reference written by `write_feature_reference`.
+#[derive(Debug)]
+pub struct FeatureReference {
+    pub num_features: usize,
+    // the salmon read geometry locating the feature barcode in
+    // read 2, if the CSV gives a pattern
+    pub read_geometry: Option<String>,
+}
+
+// Turns a 10x feature-reference pattern (e.g. `^NNNNNNNNNN(BC)`,
+// `5PNNNNNNNNNN(BC)` or just `(BC)`) with a barcode of length `len`
+// into a salmon read geometry such as `2[11-25]`.
+fn feature_pattern_geometry(pattern: &str, len: usize) -> Result<String> {
+    let p = pattern
+        .strip_prefix('^')
+        .or_else(|| pattern.strip_prefix("5P"))
+        .unwrap_or(pattern);
+    let Some(offset) = p.find("(BC)") else {
+        bail!("feature pattern {:?} has no (BC) placeholder", pattern);
+    };
+    if !p[..offset].chars().all(|c| c == 'N') {
+        bail!(
+            "feature pattern {:?} is not supported; only patterns with a fixed offset \
+            before (BC) (e.g. ^NNNNNNNNNN(BC)) can be used",
+            pattern
+        );
+    }
+    Ok(format!("2[{}-{}]", offset + 1, offset + len))
+}
+
+// Reads a 10x-style feature-reference CSV (with at least the `id` and
+// `sequence` columns, and optionally `read` and `pattern`) and writes
+// the `id<TAB>sequence` table salmon indexes with `--features` to
+// `features`, and an identity t2g for it to `t2g`.
+pub fn write_feature_reference(
+    csv: &Path,
+    features: &Path,
+    t2g: &Path,
+) -> Result<FeatureReference> {
+    let f = std::fs::File::open(csv)
+        .with_context(|| format!("could not open feature reference {}", csv.display()))?;
+    let mut lines = BufReader::new(f).lines();
+    let header = match lines.next() {
+        Some(l) => l?,
+        None => bail!("feature reference {} is empty", csv.display()),
+    };
+    let cols: Vec<String> = header.split(',').map(|c| c.trim().to_lowercase()).collect();
+    let col = |name: &str| cols.iter().position(|c| c == name);
+    let (Some(id_col), Some(seq_col)) = (col("id"), col("sequence")) else {
+        bail!(
+            "feature reference {} must have the columns id and sequence, found: {}",
+            csv.display(),
+            header
+        );
+    };
+    let (read_col, pattern_col) = (col("read"), col("pattern"));
+
+    let mut w_features = BufWriter::new(
+        std::fs::File::create(features)
+            .with_context(|| format!("could not create {}", features.display()))?,
+    );
+    let mut w_t2g = BufWriter::new(
+        std::fs::File::create(t2g)
+            .with_context(|| format!("could not create {}", t2g.display()))?,
+    );
+    let mut seen = HashSet::new();
+    let mut read_geometry: Option<String> = None;
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // the header is line 1
+        let line_no = i + 2;
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        let field = |c: usize| fields.get(c).copied().unwrap_or("");
+        let (id, seq) = (field(id_col), field(seq_col));
+        if id.is_empty() || seq.is_empty() {
+            bail!(
+                "line {} of feature reference {} has no id or sequence",
+                line_no,
+                csv.display()
+            );
+        }
+        if !seq.chars().all(|c| matches!(c, 'A' | 'C' | 'G' | 'T')) {
+            bail!(
+                "line {} of feature reference {}: the sequence {:?} of {} is not a plain ACGT barcode",
+                line_no,
+                csv.display(),
+                seq,
+                id
+            );
+        }
+        if !seen.insert(id.to_string()) {
+            bail!(
+                "line {} of feature reference {}: the feature id {} appears more than once",
+                line_no,
+                csv.display(),
+                id
+            );
+        }
+        if let Some(r) = read_col.map(field).filter(|r| !r.is_empty()) {
+            if r != "R2" {
+                bail!(
+                    "line {} of feature reference {}: feature {} is in read {}, \
+                    but simpleaf only supports feature barcodes in R2",
+                    line_no,
+                    csv.display(),
+                    id,
+                    r
+                );
+            }
+        }
+        if let Some(p) = pattern_col.map(field).filter(|p| !p.is_empty()) {
+            let geo = feature_pattern_geometry(p, seq.len()).with_context(|| {
+                format!("line {} of feature reference {}", line_no, csv.display())
+            })?;
+            match &read_geometry {
+                Some(g) if *g != geo => bail!(
+                    "the features in {} are at different positions in the read ({} and {}); \
+                    split them into one reference per pattern and barcode length",
+                    csv.display(),
+                    g,
+                    geo
+                ),
+                _ => read_geometry = Some(geo),
+            }
+        }
+        writeln!(w_features, "{}\t{}", id, seq)?;
+        writeln!(w_t2g, "{}\t{}", id, id)?;
+    }
+    w_features.flush()?;
+    w_t2g.flush()?;
+    if seen.is_empty() {
+        bail!("feature reference {} lists no features", csv.display());
+    }
+    Ok(FeatureReference {
+        num_features: seen.len(),
+        read_geometry,
+    })
+}
+
+// Returns the distinct sequence names (the `seqname` column)
+// referenced by the features in the GTF file at `p`.
+pub fn gtf_seq_names(p: &Path) -> Result<HashSet<String>> {
+    let f = std::fs::File::open(p)
+        .with_context(|| format!("could not open GTF file {}", p.display()))?;
+    let mut names = HashSet::new();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.split('\t').next().filter(|n| !n.is_empty()) {
+            if !names.contains(name) {
+                names.insert(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+// How the sequence names used by a GTF file line up
+// with the records of the genome FASTA file.
+#[derive(Debug)]
+pub struct SeqNameConcordance {
+    pub matching: usize,
+    // GTF sequence names with no FASTA record, sorted
+    pub gtf_only: Vec<String>,
+    pub fasta_only: usize,
+}
+
+impl SeqNameConcordance {
+    // A poor overlap is one where fewer than half of the
+    // sequences the GTF refers to are present in the FASTA,
+    // which usually means the two use different naming schemes.
+    pub fn is_poor(&self) -> bool {
+        self.matching < self.gtf_only.len() || self.matching == 0
+    }
+}
+
+pub fn seq_name_concordance(fasta: &Path, gtf: &Path) -> Result<SeqNameConcordance> {
+    let fasta_names = fasta_seq_names(fasta)?
+        .into_iter()
+        .collect::<HashSet<String>>();
+    let gtf_names = gtf_seq_names(gtf)?;
+    let mut gtf_only = gtf_names
+        .difference(&fasta_names)
+        .cloned()
+        .collect::<Vec<String>>();
+    gtf_only.sort();
+    let matching = gtf_names.len() - gtf_only.len();
+    Ok(SeqNameConcordance {
+        matching,
+        gtf_only,
+        fasta_only: fasta_names.len() - matching,
+    })
+}
+
+// The `index_info.json` written by `simpleaf index` alongside the
+// salmon index at `index`, i.e. in the directory above it.
+pub fn read_index_info(index: &Path) -> Option<serde_json::Value> {
+    let info_file = index.parent()?.join("index_info.json");
+    let f = std::fs::File::open(info_file).ok()?;
+    serde_json::from_reader(BufReader::new(f)).ok()
+}
+
+// The `--reference-name` recorded when the salmon index at `index`
+// was built by `simpleaf index`.
+pub fn index_reference_name(index: &Path) -> Option<String> {
+    read_index_info(index)?["reference_name"]
+        .as_str()
+        .map(String::from)
+}
+
+// The read geometry of the feature barcodes in the feature-barcode
+// index at `index`, or None for other indices.
+pub fn index_feature_read_geometry(index: &Path) -> Option<String> {
+    read_index_info(index)?["feature_barcodes"]["read_geometry"]
+        .as_str()
+        .map(String::from)
+}
+
+// An extra sequence file to add to the splici reference.
+#[derive(Debug)]
+pub struct ExtraSequences {
+    pub fasta: PathBuf,
+    pub spliced: bool,
+    // the gene the records are assigned to in the t2g, if
+    // not their own names
+    pub gene_id: Option<String>,
+}
+
+// Reads a TSV listing one extra sequence FASTA per line as
+// `fasta<TAB>spliced|unspliced[<TAB>gene_id]`. Empty lines and
+// lines starting with `#` are ignored.
+pub fn read_extra_sequences(p: &Path) -> Result<Vec<ExtraSequences>> {
+    let f = std::fs::File::open(p)
+        .with_context(|| format!("could not open extra sequence list {}", p.display()))?;
+    let mut extras = Vec::new();
+    for (i, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cols = line.split('\t').map(str::trim).collect::<Vec<&str>>();
+        let spliced = match cols.get(1) {
+            Some(&"spliced") => true,
+            Some(&"unspliced") => false,
+            _ => bail!(
+                "line {} of {} must give a FASTA file and `spliced` or `unspliced`",
+                i + 1,
+                p.display()
+            ),
+        };
+        let fasta = PathBuf::from(cols[0]);
+        if !fasta.is_file() {
+            bail!(
+                "extra sequence file {} (line {} of {}) does not exist",
+                fasta.display(),
+                i + 1,
+                p.display()
+            );
+        }
+        extras.push(ExtraSequences {
+            fasta,
+            spliced,
+            gene_id: cols.get(2).filter(|g| !g.is_empty()).map(|g| g.to_string()),
+        });
+    }
+    Ok(extras)
+}
+
+// Concatenates the (possibly gzip-compressed) FASTA files `inputs`
+// into the uncompressed `dest`, streaming each one through and
+// separating them by a newline where one doesn't end in one.
+pub fn concat_fastas(inputs: &[&Path], dest: &Path) -> Result<()> {
+    let mut w = BufWriter::new(
+        std::fs::File::create(dest)
+            .with_context(|| format!("could not create {}", dest.display()))?,
+    );
+    // the last byte written, so that an input lacking a final
+    // newline doesn't run into the header of the next one
+    let mut last = None;
+    let mut buf = vec![0u8; 64 * 1024];
+    for p in inputs {
+        if last.is_some_and(|b| b != b'\n') {
+            w.write_all(b"\n")?;
+        }
+        let mut f = open_maybe_gzipped(p)?;
+        let copy_err = || format!("could not copy {} to {}", p.display(), dest.display());
+        loop {
+            let n = f.read(&mut buf).with_context(copy_err)?;
+            if n == 0 {
+                break;
+            }
+            w.write_all(&buf[..n]).with_context(copy_err)?;
+            last = Some(buf[n - 1]);
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+// The decoy sequences to give salmon index: every record of
+// `decoy_fasta` for a full (genome) decoy, or, for a partial decoy,
+// those named one per line in `names_file`, each of which must be
+// a record of `decoy_fasta`.
+pub fn read_decoy_names(decoy_fasta: &Path, names_file: Option<&Path>) -> Result<Vec<String>> {
+    let records = fasta_seq_names(decoy_fasta)?;
+    if records.is_empty() {
+        bail!("the decoy FASTA {} has no records", decoy_fasta.display());
+    }
+    let Some(names_file) = names_file else {
+        return Ok(records);
+    };
+    let text = std::fs::read_to_string(names_file)
+        .with_context(|| format!("could not read {}", names_file.display()))?;
+    let names = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    if names.is_empty() {
+        bail!("{} names no decoy sequences", names_file.display());
+    }
+    let records = records.into_iter().collect::<HashSet<String>>();
+    let missing = names
+        .iter()
+        .filter(|n| !records.contains(*n))
+        .cloned()
+        .collect::<Vec<String>>();
+    if !missing.is_empty() {
+        bail!(
+            "{} of the {} decoys named in {} are not records of {} (e.g. {})",
+            missing.len(),
+            names.len(),
+            names_file.display(),
+            decoy_fasta.display(),
+            missing[..missing.len().min(5)].join(", ")
+        );
+    }
+    Ok(names)
+}
+
+// Copies the records of the FASTA file `src` named in `keep` to
+// `dest`, returning the number copied.
+pub fn extract_fasta_records(src: &Path, keep: &HashSet<String>, dest: &Path) -> Result<usize> {
+    let r = open_maybe_gzipped(src)?;
+    let mut w = BufWriter::new(
+        std::fs::File::create(dest)
+            .with_context(|| format!("could not create {}", dest.display()))?,
+    );
+    let mut copying = false;
+    let mut copied = 0;
+    for line in r.lines() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('>') {
+            copying = header
+                .split_whitespace()
+                .next()
+                .is_some_and(|n| keep.contains(n));
+            if copying {
+                copied += 1;
+            }
+        }
+        if copying {
+            writeln!(w, "{}", line)?;
+        }
+    }
+    w.flush()?;
+    Ok(copied)
+}
+
+// Rewrites the gene (second) column of the t2g file `t2g` for the
+// transcripts listed in `genes`, returning the number of rows changed.
+pub fn retag_t2g_genes(t2g: &Path, genes: &HashMap<String, String>) -> Result<usize> {
+    let text = std::fs::read_to_string(t2g)
+        .with_context(|| format!("could not read t2g file {}", t2g.display()))?;
+    let mut out = String::with_capacity(text.len());
+    let mut changed = 0;
+    for line in text.lines() {
+        let mut cols = line.split('\t').collect::<Vec<&str>>();
+        if let Some(g) = cols.first().and_then(|t| genes.get(*t)) {
+            if cols.len() >= 2 {
+                cols[1] = g;
+                changed += 1;
+            }
+        }
+        out.push_str(&cols.join("\t"));
+        out.push('\n');
+    }
+    std::fs::write(t2g, out).with_context(|| format!("could not write {}", t2g.display()))?;
+    Ok(changed)
+}
+
+// How many of the transcripts in an index have an entry in a t2g map.
+#[derive(Debug)]
+pub struct T2gCoverage {
+    pub num_transcripts: usize,
+    pub missing: Vec<String>,
+}
+
+// Checks that every transcript of the salmon index at `index`, built
+// from the FASTA `ref_seq`, is listed in the t2g map `t2g`. Sequences
+// salmon dropped as duplicates (recorded in `duplicate_clusters.tsv`)
+// aren't in the index and so need no entry.
+pub fn t2g_coverage(ref_seq: &Path, index: &Path, t2g: &Path) -> Result<T2gCoverage> {
+    let f = std::fs::File::open(t2g)
+        .with_context(|| format!("could not open t2g file {}", t2g.display()))?;
+    let mut mapped = HashSet::new();
+    for line in BufReader::new(f).lines() {
+        if let Some(t) = line?.split('\t').next() {
+            mapped.insert(t.to_string());
+        }
+    }
+
+    let mut dropped = HashSet::new();
+    if let Ok(f) = std::fs::File::open(index.join("duplicate_clusters.tsv")) {
+        // a header line followed by `retained<TAB>duplicate` pairs
+        for line in BufReader::new(f).lines().skip(1) {
+            if let Some(d) = line?.split('\t').nth(1) {
+                dropped.insert(d.to_string());
+            }
+        }
+    }
+
+    let names = fasta_seq_names(ref_seq)?
+        .into_iter()
+        .filter(|n| !dropped.contains(n))
+        .collect::<Vec<String>>();
+    Ok(T2gCoverage {
+        num_transcripts: names.len(),
+        missing: names.into_iter().filter(|n| !mapped.contains(n)).collect(),
+    })
+}
+
+// The result of checking the format of a t2g file.
+#[derive(Debug)]
+pub struct T2gValidation {
+    pub rows: usize,
+    // 2 or 3, as set by the first row, if it had either
+    pub columns: Option<usize>,
+    // one description, with its line number, per problem found
+    pub problems: Vec<String>,
+}
+
+// Checks that the t2g file `t2g` has 2 (transcript, gene) or 3
+// (transcript, gene, S/U/A status) tab-separated columns on every
+// line, with no empty fields and no transcript listed twice.
+pub fn validate_t2g(t2g: &Path) -> Result<T2gValidation> {
+    let f = std::fs::File::open(t2g)
+        .with_context(|| format!("could not open t2g file {}", t2g.display()))?;
+    let mut v = T2gValidation {
+        rows: 0,
+        columns: None,
+        problems: Vec::new(),
+    };
+    // the line each transcript was first seen on
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (i, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        let line_no = i + 1;
+        v.rows += 1;
+        let cols = line.split('\t').collect::<Vec<&str>>();
+        if line.is_empty() {
+            v.problems.push(format!("line {}: empty line", line_no));
+            continue;
+        }
+        match v.columns {
+            None if cols.len() == 2 || cols.len() == 3 => v.columns = Some(cols.len()),
+            None => {
+                v.problems.push(format!(
+                    "line {}: {} columns, but a t2g file has 2 (transcript, gene) \
+                    or 3 (transcript, gene, status)",
+                    line_no,
+                    cols.len()
+                ));
+                continue;
+            }
+            Some(n) if n != cols.len() => {
+                v.problems.push(format!(
+                    "line {}: {} columns, but the file started with {}",
+                    line_no,
+                    cols.len(),
+                    n
+                ));
+                continue;
+            }
+            Some(_) => {}
+        }
+        if cols.iter().any(|c| c.trim().is_empty()) {
+            v.problems.push(format!("line {}: empty field", line_no));
+        }
+        if let Some(status) = cols.get(2) {
+            if !matches!(*status, "S" | "U" | "A") {
+                v.problems.push(format!(
+                    "line {}: status {:?} of {} is not S, U or A",
+                    line_no, status, cols[0]
+                ));
+            }
+        }
+        if let Some(first) = seen.get(cols[0]) {
+            v.problems.push(format!(
+                "line {}: transcript {} is already listed on line {}",
+                line_no, cols[0], first
+            ));
+        } else {
+            seen.insert(cols[0].to_string(), line_no);
+        }
+    }
+    Ok(v)
+}
+
+// Non-standard GTF attribute keys and feature type to translate
+// to the `gene_id`, `transcript_id` and `exon` that pyroe expects.
+#[derive(Debug, Default, Serialize)]
+pub struct GtfOverrides {
+    pub gene_id_attr: Option<String>,
+    pub transcript_id_attr: Option<String>,
+    pub exon_feature: Option<String>,
+}
+
+impl GtfOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.gene_id_attr.is_none()
+            && self.transcript_id_attr.is_none()
+            && self.exon_feature.is_none()
+    }
+}
+
+// Writes a copy of the GTF `src` to `dest` with the attribute keys
+// and feature type of `o` renamed to the standard ones, replacing
+// any standard attribute they would duplicate in the same record.
+// Returns the number of records written.
+pub fn normalize_gtf(src: &Path, dest: &Path, o: &GtfOverrides) -> Result<usize> {
+    let f = std::fs::File::open(src)
+        .with_context(|| format!("could not open GTF file {}", src.display()))?;
+    let mut w = BufWriter::new(
+        std::fs::File::create(dest)
+            .with_context(|| format!("could not create {}", dest.display()))?,
+    );
+    let renames = [
+        (o.gene_id_attr.as_deref(), "gene_id"),
+        (o.transcript_id_attr.as_deref(), "transcript_id"),
+    ];
+    let mut records = 0;
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        let mut cols = line
+            .splitn(9, '\t')
+            .map(String::from)
+            .collect::<Vec<String>>();
+        if line.starts_with('#') || cols.len() < 9 {
+            writeln!(w, "{}", line)?;
+            continue;
+        }
+        if o.exon_feature.as_deref() == Some(cols[2].as_str()) {
+            cols[2] = String::from("exon");
+        }
+        let pairs = cols[8]
+            .split(';')
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(|a| a.split_once(' ').unwrap_or((a, "")))
+            .collect::<Vec<(&str, &str)>>();
+        let mut attrs = Vec::new();
+        for (key, value) in &pairs {
+            let mut key = *key;
+            for (from, to) in renames {
+                if let Some(from) = from {
+                    if key == from {
+                        key = to;
+                    } else if key == to && pairs.iter().any(|(k, _)| *k == from) {
+                        // superseded by the renamed attribute, which
+                        // only records that have it can replace
+                        key = "";
+                    }
+                }
+            }
+            if !key.is_empty() {
+                attrs.push(format!("{} {};", key, value.trim()));
+            }
+        }
+        cols[8] = attrs.join(" ");
+        writeln!(w, "{}", cols.join("\t"))?;
+        records += 1;
+    }
+    w.flush()?;
+    Ok(records)
+}
+
+// Describes how the simpleaf that built the index at `index`
+// differs from this one, when that may matter: a different
+// release series (major version, or minor while still at 0.x), or
+// a simpleaf too old to record its version at all.
+pub fn index_version_mismatch(index: &Path) -> Option<String> {
+    let info = read_index_info(index)?;
+    let ours = env!("CARGO_PKG_VERSION");
+    let built_by = match info["simpleaf_version"].as_str() {
+        Some(v) => v.to_string(),
+        None => {
+            let mut msg = format!(
+                "the index {} was built by an earlier simpleaf that didn't record its version",
+                index.display()
+            );
+            if info["ref_seq"].is_null() {
+                msg.push_str(
+                    ", nor the reference sequences that --check-t2g reads, \
+                    so that check can't be used with it",
+                );
+            }
+            msg.push_str("; rebuild it if you run into problems.");
+            return Some(msg);
+        }
+    };
+    let series = |v: &str| {
+        semver::Version::parse(v).ok().map(|v| {
+            if v.major == 0 {
+                (0, v.minor)
+            } else {
+                (v.major, 0)
+            }
+        })
+    };
+    match (series(&built_by), series(ours)) {
+        (Some(a), Some(b)) if a != b => Some(format!(
+            "the index {} was built by simpleaf {}, but this is simpleaf {}; the index \
+            layout or its recorded information may have changed between them, so rebuild \
+            the index if you run into problems.",
+            index.display(),
+            built_by,
+            ours
+        )),
+        _ => None,
+    }
+}
+
+// The salmon versions that can validate an index themselves, with the
+// arguments (followed by the index path) that do so. No released salmon
+// has such a subcommand yet, so until one does `validate_index` always
+// falls back to checking the files of the index.
+const SALMON_INDEX_VALIDATION: &[(&str, &[&str])] = &[];
+
+// The files of a salmon index that mapping reads, besides the
+// positions, which are in `pos.bin` for a dense index and in
+// `sample_pos.bin` for a sparse one.
+const SALMON_INDEX_FILES: &[&str] = &[
+    "info.json",
+    "versionInfo.json",
+    "complete_ref_lens.bin",
+    "ctable.bin",
+    "ctg_offsets.bin",
+    "mphf.bin",
+    "rank.bin",
+    "refAccumLengths.bin",
+    "reflengths.bin",
+    "refseq.bin",
+    "seq.bin",
+];
+
+// Checks that the salmon index at `index` is usable before mapping to
+// it, with the validation of `salmon` itself when it has one, and
+// otherwise by checking that the index files are present and
+// non-empty and that its JSON files parse. Returns how it was checked.
+pub fn validate_index(index: &Path, salmon: &ProgInfo) -> Result<&'static str> {
+    let salmon_ver = semver::Version::parse(&salmon.version)?;
+    for (req, args) in SALMON_INDEX_VALIDATION {
+        if semver::VersionReq::parse(req)?.matches(&salmon_ver) {
+            let out = std::process::Command::new(&salmon.exe_path)
+                .args(*args)
+                .arg(index)
+                .output()
+                .context("could not run salmon to validate the index")?;
+            if !out.status.success() {
+                bail!(
+                    "salmon {} found the index {} unusable:\n{}",
+                    salmon_ver,
+                    index.display(),
+                    String::from_utf8_lossy(&out.stderr).trim_end()
+                );
+            }
+            return Ok("validation of salmon itself");
+        }
+    }
+
+    let mut problems = Vec::new();
+    let positions = if index.join("sample_pos.bin").exists() {
+        "sample_pos.bin"
+    } else {
+        "pos.bin"
+    };
+    for f in SALMON_INDEX_FILES.iter().chain([&positions]) {
+        let p = index.join(f);
+        match std::fs::metadata(&p) {
+            Err(_) => problems.push(format!("{} is missing", f)),
+            Ok(md) if md.len() == 0 => problems.push(format!("{} is empty", f)),
+            Ok(_) if f.ends_with(".json") => {
+                let parsed = std::fs::read(&p)
+                    .ok()
+                    .and_then(|b| serde_json::from_slice::<serde_json::Value>(&b).ok());
+                if parsed.is_none() {
+                    problems.push(format!("{} is not valid JSON", f));
+                }
+            }
+            Ok(_) => {}
+        }
+    }
+    if !problems.is_empty() {
+        bail!(
+            "the index {} looks incomplete or corrupt ({}); rebuild it",
+            index.display(),
+            problems.join(", ")
+        );
+    }
+    Ok("checks of its files")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let d = std::env::temp_dir().join(format!("simpleaf_test_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&d).unwrap();
+        d
+    }
+
+    #[test]
+    fn validate_t2g_reports_each_problem_with_its_line() {
+        let d = test_dir("validate_t2g");
+        let t2g = d.join("t2g.tsv");
+        std::fs::write(&t2g, "t1\tg1\tS\nt2\tg1\n\nt3\t\tU\nt4\tg2\tX\nt1\tg3\tA\n").unwrap();
+        let v = validate_t2g(&t2g).unwrap();
+        assert_eq!((v.rows, v.columns), (6, Some(3)));
+        assert_eq!(
+            v.problems,
+            vec![
+                "line 2: 2 columns, but the file started with 3",
+                "line 3: empty line",
+                "line 4: empty field",
+                "line 5: status \"X\" of t4 is not S, U or A",
+                "line 6: transcript t1 is already listed on line 1",
+            ]
+        );
+
+        std::fs::write(&t2g, "t1\n").unwrap();
+        let v = validate_t2g(&t2g).unwrap();
+        assert_eq!(v.columns, None);
+        assert!(
+            v.problems[0].starts_with("line 1: 1 columns"),
+            "{:?}",
+            v.problems
+        );
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn feature_patterns_map_to_read_geometries() {
+        assert_eq!(
+            feature_pattern_geometry("^NNNNNNNNNN(BC)", 15).unwrap(),
+            "2[11-25]"
+        );
+        assert_eq!(feature_pattern_geometry("5PNN(BC)", 8).unwrap(), "2[3-10]");
+        assert_eq!(feature_pattern_geometry("(BC)", 12).unwrap(), "2[1-12]");
+        assert!(feature_pattern_geometry("^NNNN", 12).is_err());
+        assert!(feature_pattern_geometry("^NNACGT(BC)", 12).is_err());
+    }
+
+    #[test]
+    fn write_feature_reference_takes_the_geometry_from_the_patterns() {
+        let d = test_dir("feature_ref");
+        let (csv, features, t2g) = (d.join("f.csv"), d.join("f.tsv"), d.join("t2g.tsv"));
+        std::fs::write(
+            &csv,
+            "id,name,read,pattern,sequence\n\
+            ab1,CD3,R2,^NNNNNNNNNN(BC),ACGTACGTACGTACG\n\
+            ab2,CD4,R2,^NNNNNNNNNN(BC),TTTTACGTACGTACG\n",
+        )
+        .unwrap();
+        let r = write_feature_reference(&csv, &features, &t2g).unwrap();
+        assert_eq!(r.num_features, 2);
+        assert_eq!(r.read_geometry.as_deref(), Some("2[11-25]"));
+        assert_eq!(
+            std::fs::read_to_string(&features).unwrap(),
+            "ab1\tACGTACGTACGTACG\nab2\tTTTTACGTACGTACG\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&t2g).unwrap(),
+            "ab1\tab1\nab2\tab2\n"
+        );
+
+        // barcodes of different lengths end up at different positions
+        std::fs::write(
+            &csv,
+            "id,pattern,sequence\nab1,^NN(BC),ACGT\nab2,^NN(BC),ACGTA\n",
+        )
+        .unwrap();
+        assert!(write_feature_reference(&csv, &features, &t2g).is_err());
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn seq_name_concordance_counts_shared_and_missing_names() {
+        let d = test_dir("concordance");
+        let (fasta, gtf) = (d.join("g.fa"), d.join("g.gtf"));
+        std::fs::write(&fasta, ">chr1 primary\nACGT\n>chr2\nACGT\n>chrM\nACGT\n").unwrap();
+        std::fs::write(
+            &gtf,
+            "#!genome-build test\n\
+            chr1\tsrc\texon\t1\t2\t.\t+\t.\tgene_id \"G1\";\n\
+            chr1\tsrc\texon\t3\t4\t.\t+\t.\tgene_id \"G1\";\n\
+            3\tsrc\texon\t1\t2\t.\t+\t.\tgene_id \"G2\";\n",
+        )
+        .unwrap();
+        let c = seq_name_concordance(&fasta, &gtf).unwrap();
+        assert_eq!(
+            (c.matching, c.gtf_only.clone(), c.fasta_only),
+            (1, vec![String::from("3")], 2)
+        );
+        assert!(!c.is_poor());
+
+        std::fs::write(&gtf, "1\tsrc\texon\t1\t2\t.\t+\t.\tgene_id \"G1\";\n").unwrap();
+        assert!(seq_name_concordance(&fasta, &gtf).unwrap().is_poor());
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+
+    #[test]
+    fn normalize_gtf_keeps_gene_id_of_records_without_the_override() {
+        let d = test_dir("normalize_gtf");
+        let (src, dest) = (d.join("in.gtf"), d.join("out.gtf"));
+        std::fs::write(
+            &src,
+            "chr1\tsrc\texon\t1\t10\t.\t+\t.\tgene_id \"G1\"; gene_name \"A\"; transcript_id \"T1\";\n\
+            chr1\tsrc\texon\t20\t30\t.\t+\t.\tgene_id \"G2\"; transcript_id \"T2\";\n",
+        )
+        .unwrap();
+        let o = GtfOverrides {
+            gene_id_attr: Some(String::from("gene_name")),
+            transcript_id_attr: None,
+            exon_feature: None,
+        };
+        assert_eq!(normalize_gtf(&src, &dest, &o).unwrap(), 2);
+        let out = std::fs::read_to_string(&dest).unwrap();
+        let attrs = out
+            .lines()
+            .map(|l| l.rsplit('\t').next().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            attrs,
+            vec![
+                "gene_id \"A\"; transcript_id \"T1\";",
+                "gene_id \"G2\"; transcript_id \"T2\";"
+            ]
+        );
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Accumulates the non-fatal warnings raised during a run
+// so that they can be recorded in the provenance JSON
+// in addition to being logged.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(transparent)]
+pub struct RunWarnings(Vec<String>);
+
+impl RunWarnings {
+    pub fn push<S: Into<String>>(&mut self, msg: S) {
+        let msg = msg.into();
+        warn!("{}", msg);
+        self.0.push(msg);
+    }
+}
+
+// The environment variables through which common batch
+// schedulers report the number of cores allocated to a job.
+const SCHEDULER_CPU_VARS: [&str; 3] = ["SLURM_CPUS_PER_TASK", "NSLOTS", "PBS_NP"];
+
+// The CPU quota of this process's cgroup in (possibly fractional)
+// cores, read from `cpu.max` under cgroup v2 or from
+// `cpu.cfs_quota_us` and `cpu.cfs_period_us` under cgroup v1.
+fn cgroup_cpu_quota() -> Option<f64> {
+    let read = |p: &str| std::fs::read_to_string(p).ok();
+    // cgroup v2: "<quota> <period>", with a quota of "max" if unlimited
+    let own = read("/proc/self/cgroup").and_then(|c| {
+        c.lines().find_map(|l| {
+            l.strip_prefix("0::")
+                .map(|p| p.trim().trim_end_matches('/').to_string())
+        })
+    });
+    let v2_paths = own
+        .map(|p| format!("/sys/fs/cgroup{}/cpu.max", p))
+        .into_iter()
+        .chain(std::iter::once(String::from("/sys/fs/cgroup/cpu.max")));
+    for p in v2_paths {
+        if let Some(s) = read(&p) {
+            let mut it = s.split_whitespace();
+            return match (it.next()?.parse::<f64>(), it.next()?.parse::<f64>()) {
+                (Ok(q), Ok(per)) if q > 0.0 && per > 0.0 => Some(q / per),
+                // "max", i.e. no quota
+                _ => None,
+            };
+        }
+    }
+    // cgroup v1: a quota of -1 means unlimited
+    for dir in ["/sys/fs/cgroup/cpu", "/sys/fs/cgroup/cpu,cpuacct"] {
+        let q =
+            read(&format!("{}/cpu.cfs_quota_us", dir)).and_then(|s| s.trim().parse::<f64>().ok());
+        let per =
+            read(&format!("{}/cpu.cfs_period_us", dir)).and_then(|s| s.trim().parse::<f64>().ok());
+        if let (Some(q), Some(per)) = (q, per) {
+            return (q > 0.0 && per > 0.0).then(|| q / per);
+        }
+    }
+    None
+}
+
+// Limits `threads` to what the machine, its cgroup CPU quota (as
+// in a container) and the scheduler allocation if running inside
+// a batch job can provide, warning when it has to lower it. A
+// `threads` of 0 (`--threads auto`) asks for all of them.
+pub fn clamp_threads(threads: u32, warnings: &mut RunWarnings) -> u32 {
+    // lowering an automatic request is not worth a warning
+    let auto = threads == 0;
+    let mut threads = if auto { u32::MAX } else { threads };
+    // if the user requested more threads than can be used
+    if let Ok(max_threads_usize) = std::thread::available_parallelism() {
+        let max_threads = max_threads_usize.get() as u32;
+        if threads > max_threads {
+            if !auto {
+                warnings.push(format!(
+                    "The maximum available parallelism is {}, but {} threads were requested; \
+                    setting number of threads to {}",
+                    max_threads, threads, max_threads
+                ));
+            }
+            threads = max_threads;
+        }
+    }
+    // a fractional quota only buys whole threads' worth of cores
+    if let Some(quota) = cgroup_cpu_quota() {
+        let max_threads = (quota.floor() as u32).max(1);
+        if threads > max_threads {
+            if !auto {
+                warnings.push(format!(
+                    "The CPU quota of this cgroup is {} cores, but {} threads were requested; \
+                    setting number of threads to {}",
+                    quota, threads, max_threads
+                ));
+            }
+            threads = max_threads;
+        }
+    }
+    // the node may have more cores than the job was given
+    for var in SCHEDULER_CPU_VARS {
+        let alloc = std::env::var(var)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .filter(|n| *n > 0);
+        if let Some(alloc) = alloc {
+            if threads > alloc {
+                if !auto {
+                    warnings.push(format!(
+                        "The scheduler allocated {} cores to this job (${}), but {} threads were \
+                        requested; setting number of threads to {}",
+                        alloc, var, threads, alloc
+                    ));
+                }
+                threads = alloc;
+            }
+            break;
+        }
+    }
+    if auto {
+        // with nothing to go by, play it safe
+        if threads == u32::MAX {
+            threads = 1;
+        }
+        info!("using {} threads (--threads auto)", threads);
+    }
+    threads
+}
+
+// Controls how long a single subprocess stage may run
+// and how many times it is retried after timing out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StageOpts {
+    pub timeout: Option<Duration>,
+    pub max_retries: u32,
+}
+
+impl StageOpts {
+    pub fn new(timeout_secs: Option<u64>, max_retries: u32) -> Self {
+        Self {
+            timeout: timeout_secs.map(Duration::from_secs),
+            max_retries,
+        }
+    }
+}
+
+// The clock ticks per second /proc reports CPU times in; USER_HZ
+// is 100 on all the platforms Linux is commonly run on.
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+// The shortest window the peak CPU utilization is measured over,
+// as shorter ones are dominated by sampling jitter.
+const CPU_PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+// How many cores a stage kept busy, on average over its run
+// and at its busiest.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CpuUsage {
+    pub avg_cores: f64,
+    pub peak_cores: f64,
+}
+
+// The result of running a subprocess stage: its exit status and
+// stderr, plus its peak resident set size in bytes and its CPU
+// utilization where those could be sampled.
+#[derive(Debug)]
+pub struct StageOutput {
+    pub status: ExitStatus,
+    pub stderr: Vec<u8>,
+    pub peak_rss: Option<u64>,
+    pub cpu: Option<CpuUsage>,
+}
+
+impl StageOutput {
+    // Describes how the stage `what` failed. A process killed by
+    // SIGKILL has most likely been stopped by the kernel's (or the
+    // scheduler's) out-of-memory killer, so say so.
+    pub fn failure_message(&self, what: &str) -> String {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            match self.status.signal() {
+                Some(9) => {
+                    let peak = self
+                        .peak_rss
+                        .map(|r| format!(" after reaching a peak RSS of {}", format_bytes(r)))
+                        .unwrap_or_default();
+                    return format!(
+                        "{} was killed by SIGKILL{}; this usually means it ran out of memory. \
+                        Try again with fewer threads or more memory",
+                        what, peak
+                    );
+                }
+                Some(sig) => return format!("{} was terminated by signal {}", what, sig),
+                None => {}
+            }
+        }
+        match self.status.code() {
+            Some(code) => format!("{} failed with exit code {}", what, code),
+            None => format!("{} failed with exit status {:?}", what, self.status),
+        }
+    }
+}
+
+// Runs `cmd` to completion, sampling its memory and CPU use as it
+// goes. When a timeout is set, a process that
+// exceeds it is killed and restarted up to `max_retries`
+// times before giving up.
+pub fn run_stage(cmd: &mut Command, opts: &StageOpts) -> Result<StageOutput> {
+    let mut attempt = 0;
+    loop {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+
+        // drain the pipes on separate threads so that a chatty
+        // child can't block on a full pipe while we wait.
+        let stdout_reader = drain_pipe(child.stdout.take());
+        let stderr_reader = drain_pipe(child.stderr.take());
+
+        let start = Instant::now();
+        let mut peak_rss = None;
+        let mut cpu_secs = None;
+        // the start of the current peak CPU window and the
+        // CPU time used by then
+        let mut window = (start, 0.0);
+        let mut peak_cores: Option<f64> = None;
+        let status = loop {
+            // sample before reaping, as the high-water mark
+            // is gone once the child has exited.
+            peak_rss = peak_rss.max(read_peak_rss(child.id()));
+            if let Some(c) = read_cpu_secs(child.id()) {
+                cpu_secs = Some(c);
+                let since = window.0.elapsed();
+                if since >= CPU_PEAK_WINDOW {
+                    let cores = (c - window.1) / since.as_secs_f64();
+                    peak_cores = Some(peak_cores.map_or(cores, |p| p.max(cores)));
+                    window = (Instant::now(), c);
+                }
+            }
+            if let Some(st) = child.try_wait()? {
+                break Some(st);
+            }
+            if opts.timeout.is_some_and(|t| start.elapsed() >= t) {
+                child.kill()?;
+                child.wait()?;
+                break None;
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let cpu = cpu_secs.filter(|_| elapsed > 0.0).map(|c| {
+            let avg_cores = c / elapsed;
+            CpuUsage {
+                avg_cores,
+                // a stage shorter than one window only has its average
+                peak_cores: peak_cores.map_or(avg_cores, |p| p.max(avg_cores)),
+            }
+        });
+
+        // stdout is only drained, not kept
+        let _ = stdout_reader.join();
+        let stderr = stderr_reader.join().unwrap_or_default();
+        let timeout = opts.timeout.unwrap_or_default();
+
+        match status {
+            Some(status) => {
+                return Ok(StageOutput {
+                    status,
+                    stderr,
+                    peak_rss,
+                    cpu,
+                })
+            }
+            None if attempt < opts.max_retries => {
+                attempt += 1;
+                warn!(
+                    "{:?} exceeded the stage timeout of {}s; retrying ({} of {})",
+                    cmd.get_program(),
+                    timeout.as_secs(),
+                    attempt,
+                    opts.max_retries
+                );
+            }
+            None => {
+                bail!(
+                    "{:?} exceeded the stage timeout of {}s and was killed after {} attempt(s)",
+                    cmd.get_program(),
+                    timeout.as_secs(),
+                    attempt + 1
+                );
+            }
+        }
+    }
+}
+
+// The peak resident set size in bytes of the running process
+// `pid`, as reported by /proc (so only available on Linux).
+fn read_peak_rss(pid: u32) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmHWM:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+// The CPU time in seconds (user plus system, including that of any
+// children it has waited for) used so far by the running process
+// `pid`, as reported by /proc (so only available on Linux).
+fn read_cpu_secs(pid: u32) -> Option<f64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name in parentheses may itself contain spaces, so
+    // count the fields from after it; utime, stime, cutime and cstime
+    // are the 14th to 17th fields
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+    let ticks = fields
+        .get(11..15)?
+        .iter()
+        .map(|f| f.parse::<u64>().ok())
+        .sum::<Option<u64>>()?;
+    Some(ticks as f64 / CLOCK_TICKS_PER_SEC)
+}
+
+// Formats `d` like `1h 23m 4s`, or as fractional
+// seconds when it is shorter than a minute.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 {
+        return format!("{:.1}s", d.as_secs_f64());
+    }
+    let (h, m, s) = (secs / 3600, (secs / 60) % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {}m {}s", h, m, s)
+    } else {
+        format!("{}m {}s", m, s)
+    }
+}
+
+// Formats a size in bytes using binary units.
+pub fn format_bytes(b: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut v = b as f64;
+    let mut u = 0;
+    while v >= 1024.0 && u < units.len() - 1 {
+        v /= 1024.0;
+        u += 1;
+    }
+    if u == 0 {
+        format!("{} B", b)
+    } else {
+        format!("{:.1} {}", v, units[u])
+    }
+}
+
+// Records the elapsed time, peak memory and CPU utilization of each
+// stage for the `resources` section of the provenance JSON, logging
+// a readable summary as it goes.
+#[derive(Debug, Default, Serialize)]
+#[serde(transparent)]
+pub struct ResourceLog(serde_json::Map<String, serde_json::Value>);
+
+impl ResourceLog {
+    pub fn record(&mut self, stage: &str, duration: Duration, out: &StageOutput) {
+        match out.peak_rss {
+            Some(rss) => info!(
+                "{} took {} (peak RSS {})",
+                stage,
+                format_duration(duration),
+                format_bytes(rss)
+            ),
+            None => info!("{} took {}", stage, format_duration(duration)),
+        }
+        self.0.insert(
+            stage.to_string(),
+            serde_json::json!({
+                "elapsed" : format_duration(duration),
+                "elapsed_secs" : duration.as_secs_f64(),
+                "peak_rss_bytes" : out.peak_rss,
+                "cpu" : out.cpu,
+            }),
+        );
+    }
+
+    // Logs how many of the `threads` requested cores each recorded
+    // stage kept busy, to show whether the allocation was used.
+    pub fn log_cpu_summary(&self, threads: u32) {
+        let stages = self
+            .0
+            .iter()
+            .filter_map(|(stage, v)| {
+                let cpu = &v["cpu"];
+                Some(format!(
+                    "{} {:.1} avg / {:.1} peak",
+                    stage,
+                    cpu["avg_cores"].as_f64()?,
+                    cpu["peak_cores"].as_f64()?
+                ))
+            })
+            .collect::<Vec<String>>();
+        if !stages.is_empty() {
+            info!(
+                "cores busy with {} thread(s) requested: {}",
+                threads,
+                stages.join(", ")
+            );
+        }
+    }
+}
+
+fn drain_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut p) = pipe {
+            let _ = p.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+// Quotes `s` for safe use as a single word in a POSIX shell.
+pub fn shell_quote(s: &OsStr) -> String {
+    let s = s.to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.into_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+// Renders `cmd` as a shell command line.
+pub fn command_to_shell(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(shell_quote)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// A copy of `cmd` with the value following the argument `flag`
+// replaced by `value`.
+pub fn with_arg_value(cmd: &Command, flag: &str, value: &str) -> Command {
+    let mut out = Command::new(cmd.get_program());
+    let mut replace_next = false;
+    for a in cmd.get_args() {
+        if replace_next {
+            out.arg(value);
+        } else {
+            out.arg(a);
+        }
+        replace_next = a == flag;
+    }
+    out
+}
+
+// Writes `lines` as an executable bash script at `path`
+// that stops at the first failing command.
+pub fn write_command_script(path: &Path, lines: &[String]) -> Result<()> {
+    let mut script = String::from("#!/usr/bin/env bash\nset -euo pipefail\n\n");
+    for l in lines {
+        script.push_str(l);
+        script.push('\n');
+    }
+    std::fs::write(path, script)
+        .with_context(|| format!("could not write script {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+// Writes newline-delimited JSON events describing the progress
+// of a run to stdout when enabled, for live monitoring.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    enabled: bool,
+    sample: Option<String>,
+}
+
+impl EventLog {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            sample: None,
+        }
+    }
+
+    // An event log whose events are tagged with `sample`.
+    pub fn for_sample(&self, sample: &str) -> Self {
+        Self {
+            enabled: self.enabled,
+            sample: Some(sample.to_string()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn sample(&self) -> Option<&str> {
+        self.sample.as_deref()
+    }
+
+    pub fn emit(&self, event: &str, stage: &str, mut fields: serde_json::Value) {
+        if !self.enabled {
+            return;
+        }
+        let timestamp = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        if let Some(m) = fields.as_object_mut() {
+            m.insert("event".into(), event.into());
+            m.insert("stage".into(), stage.into());
+            m.insert("timestamp".into(), timestamp.into());
+            if let Some(s) = &self.sample {
+                m.insert("sample".into(), s.as_str().into());
+            }
+        }
+        let mut stdout = std::io::stdout().lock();
+        // a closed stdout shouldn't abort the run itself
+        let _ = writeln!(stdout, "{}", fields);
+        let _ = stdout.flush();
+    }
+
+    pub fn stage_started(&self, stage: &str, cmd: &Command) {
+        self.emit(
+            "stage_started",
+            stage,
+            serde_json::json!({ "cmd": command_to_shell(cmd) }),
+        );
+    }
+
+    pub fn stage_finished(&self, stage: &str, status: &ExitStatus, duration: Duration) {
+        self.emit(
+            "stage_finished",
+            stage,
+            serde_json::json!({
+                "success": status.success(),
+                "exit_code": status.code(),
+                "duration_secs": duration.as_secs_f64(),
+            }),
+        );
+    }
+}
@@ -0,0 +1,701 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+// The read files belonging to one sample.
+#[derive(Debug, Clone)]
+pub struct SampleReads {
+    pub name: String,
+    pub reads1: Vec<PathBuf>,
+    pub reads2: Vec<PathBuf>,
+    // the chemistry the manifest says the reads were made with, if any
+    pub chemistry: Option<String>,
+}
+
+fn split_reads(s: &str) -> Vec<PathBuf> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+// Checks that the manifest sample name `name`, given on line `line`
+// of the manifest `p`, can be used as the name of its output
+// subdirectory without pointing outside the output directory.
+fn check_sample_name(name: &str, line: usize, p: &Path) -> Result<()> {
+    if name.is_empty()
+        || Path::new(name).is_absolute()
+        || name.contains(['/', '\\'])
+        || name.contains("..")
+    {
+        bail!(
+            "line {} of manifest {}: the sample name {:?} is not usable as a directory \
+            name; sample names must be non-empty and contain no `/`, `\\` or `..`",
+            line,
+            p.display(),
+            name
+        );
+    }
+    Ok(())
+}
+
+// Reads a sample manifest: a `.csv` samplesheet (see
+// `read_csv_manifest`) or otherwise a TSV with one tab-separated
+// `name reads1 reads2 [chemistry]` line per sample, where the read
+// columns hold comma-separated lists of files. Empty lines and lines
+// starting with `#` are ignored.
+pub fn read_manifest(p: &Path) -> Result<Vec<SampleReads>> {
+    if p.extension().is_some_and(|e| e.eq_ignore_ascii_case("csv")) {
+        return read_csv_manifest(p);
+    }
+    let f = std::fs::File::open(p)
+        .with_context(|| format!("could not open manifest {}", p.display()))?;
+    let mut samples = Vec::new();
+    let mut seen = HashSet::new();
+    for (i, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cols = line.split('\t').collect::<Vec<&str>>();
+        if cols.len() != 3 && cols.len() != 4 {
+            bail!(
+                "line {} of manifest {} has {} columns; expected name, reads1, reads2 \
+                and optionally chemistry",
+                i + 1,
+                p.display(),
+                cols.len()
+            );
+        }
+        let sample = SampleReads {
+            name: cols[0].trim().to_string(),
+            reads1: split_reads(cols[1]),
+            reads2: split_reads(cols[2]),
+            chemistry: cols
+                .get(3)
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty()),
+        };
+        if sample.name.is_empty() || sample.reads1.is_empty() || sample.reads2.is_empty() {
+            bail!(
+                "line {} of manifest {} is missing a sample name or read files",
+                i + 1,
+                p.display()
+            );
+        }
+        check_sample_name(&sample.name, i + 1, p)?;
+        if sample.reads1.len() != sample.reads2.len() {
+            bail!(
+                "sample {} in manifest {} has {} read 1 files but {} read 2 files",
+                sample.name,
+                p.display(),
+                sample.reads1.len(),
+                sample.reads2.len()
+            );
+        }
+        if !seen.insert(sample.name.clone()) {
+            bail!(
+                "sample {} appears more than once in manifest {}",
+                sample.name,
+                p.display()
+            );
+        }
+        samples.push(sample);
+    }
+    if samples.is_empty() {
+        bail!("manifest {} lists no samples", p.display());
+    }
+    Ok(samples)
+}
+
+// The header names accepted for each samplesheet column, following
+// the nf-core `sample,fastq_1,fastq_2` convention.
+const CSV_SAMPLE_COLUMNS: [&str; 2] = ["sample", "name"];
+const CSV_READS1_COLUMNS: [&str; 3] = ["fastq_1", "reads1", "r1"];
+const CSV_READS2_COLUMNS: [&str; 3] = ["fastq_2", "reads2", "r2"];
+const CSV_CHEMISTRY_COLUMN: &str = "chemistry";
+
+// Splits CSV text into records of trimmed fields, each with the
+// line it starts on. Fields may be double-quoted (with `""` standing
+// for a literal quote), in which case they may also contain commas
+// and line breaks. A leading byte order mark and blank lines are
+// skipped.
+fn parse_csv(text: &str) -> Vec<(usize, Vec<String>)> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let (mut line, mut start) = (1, 1);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            '\n' | '\r' if !quoted => {
+                if c == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                fields.push(std::mem::take(&mut field).trim().to_string());
+                let record = std::mem::take(&mut fields);
+                if record.iter().any(|f| !f.is_empty()) {
+                    records.push((start, record));
+                }
+                line += 1;
+                start = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    fields.push(field.trim().to_string());
+    if fields.iter().any(|f| !f.is_empty()) {
+        records.push((start, fields));
+    }
+    records
+}
+
+// Reads a CSV samplesheet whose header row names its columns, e.g.
+// `sample,fastq_1,fastq_2`, optionally with a `chemistry` column.
+// Other columns are ignored, and the rows of a sample listed more
+// than once (such as one row per lane) are concatenated in order,
+// so they must agree on the chemistry.
+pub fn read_csv_manifest(p: &Path) -> Result<Vec<SampleReads>> {
+    let text = std::fs::read_to_string(p)
+        .with_context(|| format!("could not read manifest {}", p.display()))?;
+    let mut records = parse_csv(&text).into_iter();
+    let header = match records.next() {
+        Some((_, h)) => h,
+        None => bail!("manifest {} is empty", p.display()),
+    };
+    let column = |names: &[&str]| -> Result<usize> {
+        header
+            .iter()
+            .position(|h| names.contains(&h.to_lowercase().as_str()))
+            .with_context(|| {
+                format!(
+                    "the header of manifest {} has no {} column",
+                    p.display(),
+                    names.join("/")
+                )
+            })
+    };
+    let (name_col, r1_col, r2_col) = (
+        column(&CSV_SAMPLE_COLUMNS)?,
+        column(&CSV_READS1_COLUMNS)?,
+        column(&CSV_READS2_COLUMNS)?,
+    );
+    let chem_col = header
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(CSV_CHEMISTRY_COLUMN));
+
+    let mut samples: Vec<SampleReads> = Vec::new();
+    for (line_no, fields) in records {
+        let field = |c: usize| fields.get(c).map_or("", String::as_str);
+        let (name, r1, r2) = (field(name_col), field(r1_col), field(r2_col));
+        if name.is_empty() || r1.is_empty() || r2.is_empty() {
+            bail!(
+                "line {} of manifest {} is missing a sample name or read files",
+                line_no,
+                p.display()
+            );
+        }
+        check_sample_name(name, line_no, p)?;
+        let chemistry = chem_col
+            .map(field)
+            .filter(|c| !c.is_empty())
+            .map(String::from);
+        let i = match samples.iter().position(|s| s.name == name) {
+            Some(k) => {
+                // the rows of a sample are mapped in one salmon run,
+                // which can only read them with one chemistry
+                if samples[k].chemistry != chemistry {
+                    bail!(
+                        "line {} of manifest {} gives sample {} the chemistry {}, but an \
+                        earlier row of it has {}; reads of different chemistries can't be \
+                        mapped together, so give them different sample names, quantify them \
+                        in separate runs, and combine the results with `simpleaf merge`",
+                        line_no,
+                        p.display(),
+                        name,
+                        chemistry.as_deref().unwrap_or("none"),
+                        samples[k].chemistry.as_deref().unwrap_or("none")
+                    );
+                }
+                k
+            }
+            None => {
+                samples.push(SampleReads {
+                    name: name.to_string(),
+                    reads1: Vec::new(),
+                    reads2: Vec::new(),
+                    chemistry,
+                });
+                samples.len() - 1
+            }
+        };
+        samples[i].reads1.extend(split_reads(r1));
+        samples[i].reads2.extend(split_reads(r2));
+    }
+    for s in &samples {
+        if s.reads1.len() != s.reads2.len() {
+            bail!(
+                "sample {} in manifest {} has {} read 1 files but {} read 2 files",
+                s.name,
+                p.display(),
+                s.reads1.len(),
+                s.reads2.len()
+            );
+        }
+    }
+    if samples.is_empty() {
+        bail!("manifest {} lists no samples", p.display());
+    }
+    Ok(samples)
+}
+
+// salmon takes each read list as one comma-separated argument, so
+// a comma in a file name would silently split it into two paths.
+pub fn check_read_paths(sample: &SampleReads) -> Result<()> {
+    for p in sample.reads1.iter().chain(sample.reads2.iter()) {
+        if p.as_os_str().to_string_lossy().contains(',') {
+            bail!(
+                "the read file {} of sample {} has a comma in its path, which salmon would \
+                take as a separator between files; please rename or symlink it",
+                p.display(),
+                sample.name
+            );
+        }
+    }
+    Ok(())
+}
+
+// Joins read file paths into the comma-separated list salmon expects.
+pub fn join_read_paths(reads: &[PathBuf]) -> OsString {
+    let mut joined = OsString::new();
+    for (i, p) in reads.iter().enumerate() {
+        if i > 0 {
+            joined.push(",");
+        }
+        joined.push(p);
+    }
+    joined
+}
+
+// Describes what is wrong with the read file at `p`, if anything:
+// it must exist (through any symbolic links), be a non-empty file
+// and start like a FASTQ record (an `@` header) or a gzip stream.
+fn read_file_problem(p: &Path) -> Option<String> {
+    // tell a dangling link to staged data apart from a missing file
+    match std::fs::symlink_metadata(p) {
+        Ok(md) if md.file_type().is_symlink() => {
+            if std::fs::canonicalize(p).is_err() {
+                let target =
+                    std::fs::read_link(p).map_or(String::from("?"), |t| t.display().to_string());
+                return Some(format!(
+                    "is a broken symbolic link (to {}, which does not exist)",
+                    target
+                ));
+            }
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Some(String::from("does not exist"));
+        }
+        Err(e) => return Some(format!("cannot be read ({})", e)),
+    }
+    let p = match std::fs::canonicalize(p) {
+        Ok(p) => p,
+        Err(e) => return Some(format!("cannot be resolved ({})", e)),
+    };
+    let md = match std::fs::metadata(&p) {
+        Ok(md) => md,
+        Err(e) => return Some(format!("cannot be read ({})", e)),
+    };
+    if md.is_dir() {
+        return Some(format!("is a directory ({}), not a read file", p.display()));
+    }
+    if md.len() == 0 {
+        return Some(String::from("is empty"));
+    }
+    let mut head = [0u8; 2];
+    let n = match std::fs::File::open(&p).and_then(|mut f| f.read(&mut head)) {
+        Ok(n) => n,
+        Err(e) => return Some(format!("cannot be read ({})", e)),
+    };
+    let is_gzip = n == 2 && head == [0x1f, 0x8b];
+    if !is_gzip && head[0] != b'@' {
+        return Some(String::from(
+            "does not start with a FASTQ record or gzip header",
+        ));
+    }
+    None
+}
+
+// Checks every read file of `samples`, reporting all of
+// the bad ones at once.
+pub fn check_read_files<'a, I: IntoIterator<Item = &'a SampleReads>>(samples: I) -> Result<()> {
+    let problems = samples
+        .into_iter()
+        .flat_map(|s| s.reads1.iter().chain(s.reads2.iter()))
+        .filter_map(|p| read_file_problem(p).map(|e| format!("  {} {}", p.display(), e)))
+        .collect::<Vec<String>>();
+    if !problems.is_empty() {
+        bail!(
+            "{} read file(s) failed the preflight check:\n{}",
+            problems.len(),
+            problems.join("\n")
+        );
+    }
+    Ok(())
+}
+
+// Whether `p` is gzip-compressed, judged by its magic bytes or, if
+// it can't be read, by its name.
+pub fn is_gzipped(p: &Path) -> bool {
+    let mut magic = [0u8; 2];
+    match std::fs::File::open(p).and_then(|mut f| f.read(&mut magic)) {
+        Ok(n) => n == 2 && magic == [0x1f, 0x8b],
+        Err(_) => p.extension().is_some_and(|e| e == "gz"),
+    }
+}
+
+// Describes the read pairs of `sample` whose R1 and R2 files differ
+// in compression, which usually means the wrong files were picked.
+pub fn compression_mismatches(sample: &SampleReads) -> Vec<String> {
+    sample
+        .reads1
+        .iter()
+        .zip(sample.reads2.iter())
+        .filter_map(|(r1, r2)| {
+            let (gz1, gz2) = (is_gzipped(r1), is_gzipped(r2));
+            let state = |gz| if gz { "gzipped" } else { "not gzipped" };
+            (gz1 != gz2).then(|| {
+                format!(
+                    "{} is {} but its mate {} is {}; please check that these are the right files",
+                    r1.display(),
+                    state(gz1),
+                    r2.display(),
+                    state(gz2)
+                )
+            })
+        })
+        .collect()
+}
+
+// The output of a `gzip -dc` child. At the end of the stream gzip is
+// waited on, and a failed exit (a corrupt or truncated file) is
+// returned as a read error rather than passing for the end of the
+// data. A reader dropped before then stops and reaps gzip.
+struct GzipReader {
+    child: Child,
+    stdout: ChildStdout,
+    path: PathBuf,
+    exited: bool,
+}
+
+impl Read for GzipReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() && !self.exited {
+            self.exited = true;
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!(
+                    "gzip could not decompress {} ({}); the file may be corrupt or truncated",
+                    self.path.display(),
+                    status
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for GzipReader {
+    fn drop(&mut self) {
+        if !self.exited {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+// Opens a (possibly gzip-compressed) FASTQ or FASTA file for
+// reading, decompressing through `gzip -dc` when needed.
+pub fn open_maybe_gzipped(p: &Path) -> Result<Box<dyn BufRead>> {
+    let mut magic = [0u8; 2];
+    let n = std::fs::File::open(p)
+        .and_then(|mut f| f.read(&mut magic))
+        .with_context(|| format!("could not read {}", p.display()))?;
+    if n == 2 && magic == [0x1f, 0x8b] {
+        let mut child = Command::new("gzip")
+            .arg("-dc")
+            .arg(p)
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("could not run gzip to decompress {}", p.display()))?;
+        let Some(stdout) = child.stdout.take() else {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("could not read the output of gzip for {}", p.display());
+        };
+        Ok(Box::new(BufReader::new(GzipReader {
+            child,
+            stdout,
+            path: p.to_path_buf(),
+            exited: false,
+        })))
+    } else {
+        Ok(Box::new(BufReader::new(std::fs::File::open(p)?)))
+    }
+}
+
+// Reads the next 4-line FASTQ record of `r` into `rec`,
+// returning false at the end of the file.
+fn read_fastq_record(r: &mut dyn BufRead, rec: &mut String) -> Result<bool> {
+    rec.clear();
+    for i in 0..4 {
+        if r.read_line(rec)? == 0 {
+            if i == 0 {
+                return Ok(false);
+            }
+            bail!("truncated FASTQ record");
+        }
+    }
+    Ok(true)
+}
+
+// A small deterministic generator (splitmix64), so that a given
+// seed always keeps the same reads.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_f64(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Writes a random subset of about `fraction` of the read pairs of
+// `sample` to uncompressed FASTQ files in `out_dir`, keeping mates
+// together, and returns the downsampled sample.
+pub fn downsample_reads(
+    sample: &SampleReads,
+    fraction: f64,
+    seed: u64,
+    out_dir: &Path,
+) -> Result<SampleReads> {
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("could not create {}", out_dir.display()))?;
+    let mut rng = SplitMix64(seed);
+    let mut ds = SampleReads {
+        name: sample.name.clone(),
+        reads1: Vec::new(),
+        reads2: Vec::new(),
+        chemistry: sample.chemistry.clone(),
+    };
+    let (mut kept, mut total) = (0u64, 0u64);
+    for (i, (r1, r2)) in sample.reads1.iter().zip(sample.reads2.iter()).enumerate() {
+        let o1 = out_dir.join(format!("{}_{}_R1.fastq", sample.name, i + 1));
+        let o2 = out_dir.join(format!("{}_{}_R2.fastq", sample.name, i + 1));
+        let mut in1 = open_maybe_gzipped(r1)?;
+        let mut in2 = open_maybe_gzipped(r2)?;
+        let mut w1 = BufWriter::new(std::fs::File::create(&o1)?);
+        let mut w2 = BufWriter::new(std::fs::File::create(&o2)?);
+        let (mut rec1, mut rec2) = (String::new(), String::new());
+        loop {
+            let has1 = read_fastq_record(&mut *in1, &mut rec1)
+                .with_context(|| format!("could not read {}", r1.display()))?;
+            let has2 = read_fastq_record(&mut *in2, &mut rec2)
+                .with_context(|| format!("could not read {}", r2.display()))?;
+            match (has1, has2) {
+                (false, false) => break,
+                (true, true) => {}
+                _ => bail!(
+                    "{} and {} have different numbers of reads",
+                    r1.display(),
+                    r2.display()
+                ),
+            }
+            total += 1;
+            if rng.next_f64() < fraction {
+                kept += 1;
+                w1.write_all(rec1.as_bytes())?;
+                w2.write_all(rec2.as_bytes())?;
+            }
+        }
+        w1.flush()?;
+        w2.flush()?;
+        ds.reads1.push(o1);
+        ds.reads2.push(o2);
+    }
+    info!(
+        "kept {} of {} read pairs of sample {} after downsampling",
+        kept, total, sample.name
+    );
+    Ok(ds)
+}
+
+// The length of the first read in the FASTQ file at `p`,
+// if it has any reads.
+pub fn first_read_length(p: &Path) -> Result<Option<usize>> {
+    let mut r = open_maybe_gzipped(p)?;
+    let mut rec = String::new();
+    if !read_fastq_record(&mut *r, &mut rec)
+        .with_context(|| format!("could not read {}", p.display()))?
+    {
+        return Ok(None);
+    }
+    Ok(rec.lines().nth(1).map(|l| l.trim_end().len()))
+}
+
+// The lengths of the first `n` reads (or all, if fewer) in the
+// FASTQ file at `p`.
+pub fn read_lengths(p: &Path, n: usize) -> Result<Vec<usize>> {
+    let mut r = open_maybe_gzipped(p)?;
+    let mut rec = String::new();
+    let mut lens = Vec::new();
+    while lens.len() < n
+        && read_fastq_record(&mut *r, &mut rec)
+            .with_context(|| format!("could not read {}", p.display()))?
+    {
+        lens.extend(rec.lines().nth(1).map(|l| l.trim_end().len()));
+    }
+    Ok(lens)
+}
+
+// Describes why the read pair `r1`, `r2` look swapped, if they do:
+// the barcode read (R1) of droplet chemistries is the shorter one,
+// holding just the `needed` bases of the barcode and UMI.
+pub fn swapped_reads_problem(r1: &Path, r2: &Path, needed: u32) -> Result<Option<String>> {
+    let median = |p: &Path| -> Result<Option<usize>> {
+        let mut lens = read_lengths(p, 100)?;
+        lens.sort_unstable();
+        Ok(lens.get(lens.len() / 2).copied())
+    };
+    let (Some(len1), Some(len2)) = (median(r1)?, median(r2)?) else {
+        return Ok(None);
+    };
+    if len1 <= len2 {
+        return Ok(None);
+    }
+    let mut msg = format!(
+        "the reads of {} (R1, median {} bases) are longer than those of {} (R2, median {} \
+        bases)",
+        r1.display(),
+        len1,
+        r2.display(),
+        len2
+    );
+    let needed = needed as usize;
+    if len2 >= needed && len2 < needed + 5 {
+        msg.push_str(&format!(
+            ", and R2 is about the {} bases of the barcode and UMI",
+            needed
+        ));
+    }
+    msg.push_str("; the files look swapped, so check the order of --reads1 and --reads2");
+    Ok(Some(msg))
+}
+
+// The mean length in (uncompressed) bytes of the first `n` FASTQ
+// records of `p`, or `None` if it has no records.
+pub fn mean_record_bytes(p: &Path, n: usize) -> Result<Option<f64>> {
+    let mut r = open_maybe_gzipped(p)?;
+    let mut rec = String::new();
+    let (mut records, mut bytes) = (0, 0);
+    while records < n
+        && read_fastq_record(&mut *r, &mut rec)
+            .with_context(|| format!("could not read {}", p.display()))?
+    {
+        records += 1;
+        bytes += rec.len();
+    }
+    Ok((records > 0).then(|| bytes as f64 / records as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(records: &[(usize, Vec<String>)]) -> Vec<Vec<&str>> {
+        records
+            .iter()
+            .map(|(_, r)| r.iter().map(String::as_str).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parse_csv_keeps_quoted_commas_and_quotes() {
+        let records = parse_csv("a,\"b,c\",\"say \"\"hi\"\"\"\n");
+        assert_eq!(fields(&records), vec![vec!["a", "b,c", "say \"hi\""]]);
+    }
+
+    #[test]
+    fn parse_csv_skips_a_bom_and_reads_crlf_lines() {
+        let records = parse_csv("\u{feff}sample,fastq_1\r\n\r\ns1,r1.fq\r\n");
+        assert_eq!(
+            fields(&records),
+            vec![vec!["sample", "fastq_1"], vec!["s1", "r1.fq"]]
+        );
+        // the blank line is skipped, but still counted
+        assert_eq!(records[1].0, 3);
+    }
+
+    #[test]
+    fn parse_csv_reports_where_a_multi_line_record_starts() {
+        let records = parse_csv("h\n\"x\ny\"\nz\n");
+        assert_eq!(fields(&records), vec![vec!["h"], vec!["x\ny"], vec!["z"]]);
+        assert_eq!(
+            records.iter().map(|(l, _)| *l).collect::<Vec<usize>>(),
+            vec![1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn csv_manifest_concatenates_the_lanes_of_a_sample() {
+        let d = std::env::temp_dir().join(format!("simpleaf_test_csv_{}", std::process::id()));
+        std::fs::create_dir_all(&d).unwrap();
+        let p = d.join("samples.csv");
+        std::fs::write(
+            &p,
+            "sample,fastq_1,fastq_2\ns1,L1_R1.fq,L1_R2.fq\ns2,a_R1.fq,a_R2.fq\ns1,L2_R1.fq,L2_R2.fq\n",
+        )
+        .unwrap();
+        let samples = read_csv_manifest(&p).unwrap();
+        assert_eq!(
+            samples
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["s1", "s2"]
+        );
+        assert_eq!(
+            samples[0].reads1,
+            vec![PathBuf::from("L1_R1.fq"), PathBuf::from("L2_R1.fq")]
+        );
+        assert_eq!(
+            samples[0].reads2,
+            vec![PathBuf::from("L1_R2.fq"), PathBuf::from("L2_R2.fq")]
+        );
+        std::fs::remove_dir_all(&d).unwrap();
+    }
+}